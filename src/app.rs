@@ -3,39 +3,107 @@
 #[path = "utils/centralpanel_modules.rs"] mod centralpanel_modules;
 #[path = "utils/modal.rs"] mod modal;
 #[path = "utils/settings_loader.rs"] mod settings_loader;
+#[path = "utils/tag_store.rs"] mod tag_store;
+#[path = "utils/migrations.rs"] mod migrations;
+#[path = "utils/tag_query.rs"] mod tag_query;
+#[path = "utils/file_identity.rs"] mod file_identity;
+#[path = "utils/fs_watcher.rs"] mod fs_watcher;
+#[path = "utils/library_io.rs"] mod library_io;
+#[path = "utils/xmp_sidecar.rs"] mod xmp_sidecar;
+#[path = "utils/embedded_metadata.rs"] mod embedded_metadata;
+#[path = "utils/journal.rs"] mod journal;
+#[path = "utils/collections.rs"] mod collections;
+#[path = "utils/tag_autocomplete.rs"] mod tag_autocomplete;
+#[path = "utils/auto_tag.rs"] mod auto_tag;
+#[path = "utils/duplicates.rs"] mod duplicates;
+#[path = "utils/perceptual_hash.rs"] mod perceptual_hash;
+#[path = "utils/file_attributes.rs"] mod file_attributes;
+#[path = "utils/file_notes.rs"] mod file_notes;
+#[path = "utils/directory_scan.rs"] mod directory_scan;
+#[path = "utils/thumbnails.rs"] mod thumbnails;
+#[path = "utils/freedesktop_thumbnails.rs"] mod freedesktop_thumbnails;
 
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use egui_file_dialog::FileDialog;
+use tag_store::{Cached, GalleryTags, TagStore};
+use sidebar_modules::TagManagerState;
+use centralpanel_modules::{GallerySelection, SearchState};
+use fs_watcher::LibraryWatcher;
+use library_io::{ImportMode, LibraryDialogAction, LibraryIoStatus};
+use journal::JournalHistory;
+use collections::CollectionsState;
+use tag_autocomplete::TagCompletion;
+use auto_tag::{AutoTagRule, AutoTagState};
+use duplicates::DuplicatesState;
+use perceptual_hash::SimilarState;
+use directory_scan::ScanSettings;
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct ImageData {
+    /// What egui caches the texture under; see [`thumbnails::Thumbnail::uri`].
+    pub uri: String,
     pub bytes: Vec<u8>,
+    /// The thumbnail size asked for, so tiles of another size load a fitting one.
+    pub size: u32,
     pub loading: bool,
+    /// The file could not be read or decoded; it is tried again once it changes.
+    pub failed: bool,
+    /// The egui frame the tile was last drawn in, so the cache drops the longest unseen first.
+    pub last_drawn: u64,
 }
 
 #[derive(Clone)]
 pub enum DirectoryScanState {
-    Scanning,
-    Complete(Vec<String>),
+    /// The files found so far, shown while the scan goes on. Setting `cancel` stops it.
+    Scanning { files: Arc<Vec<String>>, cancel: Arc<AtomicBool> },
+    /// Shared with the gallery, which takes a cheap clone every frame.
+    Complete(Arc<Vec<String>>),
+}
+
+impl DirectoryScanState {
+    pub fn files(&self) -> &Arc<Vec<String>> {
+        match self {
+            DirectoryScanState::Scanning { files, .. } | DirectoryScanState::Complete(files) => files,
+        }
+    }
+
+    /// The file list to change, copied first if the gallery still holds the old one.
+    pub fn files_mut(&mut self) -> &mut Vec<String> {
+        match self {
+            DirectoryScanState::Scanning { files, .. } | DirectoryScanState::Complete(files) => Arc::make_mut(files),
+        }
+    }
+
+    /// Stops the scan if it is still running.
+    pub fn cancel(&self) {
+        if let DirectoryScanState::Scanning { cancel, .. } = self {
+            cancel.store(true, Ordering::Relaxed);
+        }
+    }
 }
 
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)]
-
 pub struct TaggerrsTemplate {
     paths: Vec<String>,
+    /// How each of `paths` is scanned; paths without an entry use the defaults.
+    scan_settings: HashMap<String, ScanSettings>,
     currently_active_menu: String,
     gallery_media_box_size: f32,
     gallery_media_boxes_per_row: u32,
+    write_xmp_sidecars: bool,
+    embedded_metadata_namespace: String,
+    auto_tag_rules: Vec<AutoTagRule>,
 
     #[serde(skip)]
     currently_active_path: Option<String>,
     #[serde(skip)]
     input_path_usestate: String,
     #[serde(skip)]
-    current_path_filepaths: Option<Vec<String>>,
+    current_path_filepaths: Option<Arc<Vec<String>>>,
     #[serde(skip)]
     settings_modal_open: bool,
     #[serde(skip)]
@@ -46,12 +114,52 @@ pub struct TaggerrsTemplate {
     directory_scan_state: Arc<Mutex<HashMap<String, DirectoryScanState>>>,
     #[serde(skip)]
     file_dialog: FileDialog,
+    #[serde(skip)]
+    tag_store: Option<TagStore>,
+    #[serde(skip)]
+    tag_store_error: Option<String>,
+    #[serde(skip)]
+    tag_store_error_dismissed: bool,
+    #[serde(skip)]
+    gallery_tags: Arc<Mutex<GalleryTags>>,
+    #[serde(skip)]
+    tag_manager_state: TagManagerState,
+    #[serde(skip)]
+    gallery_selection: GallerySelection,
+    #[serde(skip)]
+    search_state: SearchState,
+    #[serde(skip)]
+    library_watcher: Option<LibraryWatcher>,
+    #[serde(skip)]
+    library_file_dialog: FileDialog,
+    #[serde(skip)]
+    library_dialog_action: Option<LibraryDialogAction>,
+    #[serde(skip)]
+    library_io_status: Arc<Mutex<Option<LibraryIoStatus>>>,
+    #[serde(skip)]
+    journal_history: Arc<Mutex<Cached<JournalHistory>>>,
+    #[serde(skip)]
+    journal_status: Arc<Mutex<Option<String>>>,
+    /// Files the last scan or watcher event could not import keywords or tags for.
+    #[serde(skip)]
+    import_status: Arc<Mutex<Option<String>>>,
+    #[serde(skip)]
+    collections_state: CollectionsState,
+    #[serde(skip)]
+    auto_tag_state: AutoTagState,
+    #[serde(skip)]
+    duplicates_state: DuplicatesState,
+    #[serde(skip)]
+    similar_state: SimilarState,
+    #[serde(skip)]
+    tag_completions: Arc<Mutex<Cached<Arc<Vec<TagCompletion>>>>>,
 }
 
 impl Default for TaggerrsTemplate {
     fn default() -> Self {
         Self {
             paths: vec![],
+            scan_settings: HashMap::new(),
             currently_active_menu: "Paths".to_string(),
             currently_active_path: None,
             input_path_usestate: "".to_string(),
@@ -59,6 +167,9 @@ impl Default for TaggerrsTemplate {
             settings_modal_open: false,
             gallery_media_box_size: 200.0,
             gallery_media_boxes_per_row: 2,
+            write_xmp_sidecars: false,
+            embedded_metadata_namespace: embedded_metadata::DEFAULT_NAMESPACE.to_string(),
+            auto_tag_rules: Vec::new(),
             image_cache: Arc::new(Mutex::new(HashMap::new())),
             runtime: Arc::new(tokio::runtime::Runtime::new().unwrap()),
             directory_scan_state: Arc::new(Mutex::new(HashMap::new())),
@@ -66,6 +177,32 @@ impl Default for TaggerrsTemplate {
                 .default_size([600.0, 400.0])
                 .show_new_folder_button(true)
                 .show_search(true),
+            tag_store: None,
+            tag_store_error: None,
+            tag_store_error_dismissed: false,
+            gallery_tags: Arc::new(Mutex::new(GalleryTags::default())),
+            tag_manager_state: TagManagerState::default(),
+            gallery_selection: GallerySelection::default(),
+            search_state: SearchState::default(),
+            library_watcher: None,
+            library_file_dialog: FileDialog::new()
+                .id("library_file_dialog")
+                .default_size([600.0, 400.0])
+                .add_file_filter_extensions("JSON", vec!["json"])
+                .default_file_filter("JSON")
+                .add_save_extension("JSON", "json")
+                .default_save_extension("JSON")
+                .default_file_name("taggerrs-library.json"),
+            library_dialog_action: None,
+            library_io_status: Arc::new(Mutex::new(None)),
+            journal_history: Arc::new(Mutex::new(Cached::default())),
+            journal_status: Arc::new(Mutex::new(None)),
+            import_status: Arc::new(Mutex::new(None)),
+            collections_state: CollectionsState::default(),
+            tag_completions: Arc::new(Mutex::new(Cached::default())),
+            auto_tag_state: AutoTagState::default(),
+            duplicates_state: DuplicatesState::default(),
+            similar_state: SimilarState::default(),
         }
     }
 }
@@ -77,11 +214,27 @@ impl TaggerrsTemplate {
 
         // Load previous app state (if any).
         // Note that you must enable the `persistence` feature for this to work.
-        if let Some(storage) = cc.storage {
-            return eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default();
+        let mut app: Self = cc.storage
+            .and_then(|storage| eframe::get_value(storage, eframe::APP_KEY))
+            .unwrap_or_default();
+
+        // The tag database is opened once here and shared with every module afterwards.
+        match TagStore::open_default() {
+            Ok(store) => app.tag_store = Some(store),
+            Err(e) => app.tag_store_error = Some(e.to_string()),
         }
 
-        Default::default()
+        app.library_watcher = LibraryWatcher::new(
+            &cc.egui_ctx,
+            &app.runtime,
+            &app.directory_scan_state,
+            &app.image_cache,
+            app.tag_store.clone(),
+            &app.import_status,
+            &app.auto_tag_state.status,
+        ).ok();
+
+        app
     }
 }
 
@@ -101,15 +254,77 @@ impl eframe::App for TaggerrsTemplate {
             }
             self.currently_active_path = Some(path_str.clone());
             self.current_path_filepaths = None;
+            self.collections_state.active = None;
+            self.duplicates_state.open = false;
+            self.similar_state.open = false;
             
             // Reset directory scan state for new path
-            if let Ok(mut state_map) = self.directory_scan_state.try_lock() {
-                state_map.remove(&path_str);
+            if let Ok(mut state_map) = self.directory_scan_state.try_lock()
+                && let Some(state) = state_map.remove(&path_str)
+            {
+                state.cancel();
             }
         }
         
-        // Only request repaint when needed to prevent excessive CPU usage
-        
+        self.library_file_dialog.update(ctx);
+        if let Some(picked_path) = self.library_file_dialog.take_picked()
+            && let (Some(action), Some(store)) = (self.library_dialog_action.take(), self.tag_store.clone())
+        {
+            let status_clone = self.library_io_status.clone();
+            let ctx_clone = ctx.clone();
+            self.runtime.spawn(async move {
+                let running = match action {
+                    LibraryDialogAction::Export => "Exporting library…",
+                    LibraryDialogAction::Import(_) => "Importing library…",
+                };
+                *status_clone.lock().await = Some(LibraryIoStatus::Running(running.to_string()));
+                ctx_clone.request_repaint();
+
+                let status = match action {
+                    LibraryDialogAction::Export => match library_io::export_to_file(&store, &picked_path).await {
+                        Ok(files) => LibraryIoStatus::Finished {
+                            message: format!("Exported {} files to {}", files, picked_path.display()),
+                            unmatched: Vec::new(),
+                        },
+                        Err(e) => LibraryIoStatus::Failed(format!("Export failed: {}", e)),
+                    },
+                    LibraryDialogAction::Import(mode) => match library_io::import_from_file(&store, &picked_path, mode).await {
+                        Ok(report) => LibraryIoStatus::Finished {
+                            message: format!("Imported {} files and {} tags", report.files, report.tags),
+                            unmatched: report.unmatched,
+                        },
+                        Err(e) => LibraryIoStatus::Failed(format!("Import failed: {}", e)),
+                    },
+                };
+                *status_clone.lock().await = Some(status);
+                ctx_clone.request_repaint();
+            });
+        }
+
+        // Keep a watch on every library path; changes repaint as they arrive
+        if let Some(watcher) = &mut self.library_watcher {
+            watcher.sync(&self.paths, &self.scan_settings, &self.embedded_metadata_namespace, &self.auto_tag_rules);
+        }
+
+        if let Some(store) = &self.tag_store {
+            tag_store::refresh_cached(ctx, &self.journal_history, store, &self.runtime, journal::history);
+            tag_store::refresh_cached(ctx, &self.tag_completions, store, &self.runtime, tag_autocomplete::load_completions);
+        }
+        // Text fields keep Ctrl+Z for their own undo
+        if let Some(store) = &self.tag_store
+            && !ctx.wants_keyboard_input()
+        {
+            // Redo first: Ctrl+Z also matches while shift is held
+            if ctx.input_mut(|i| i.consume_shortcut(&journal::REDO_SHORTCUT)) {
+                journal::spawn_step(ctx, store, &self.runtime, &self.journal_status, false, self.write_xmp_sidecars);
+            } else if ctx.input_mut(|i| i.consume_shortcut(&journal::UNDO_SHORTCUT)) {
+                journal::spawn_step(ctx, store, &self.runtime, &self.journal_status, true, self.write_xmp_sidecars);
+            }
+        }
+        let history = self.journal_history.try_lock().map(|cached| cached.value.clone()).unwrap_or_default();
+        let journal_status = self.journal_status.try_lock().ok().and_then(|status| status.clone());
+        let import_status = self.import_status.try_lock().ok().and_then(|status| status.clone());
+
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             egui::MenuBar::new().ui(ui, |ui| {
                 ui.menu_button("File", |ui| {
@@ -119,10 +334,108 @@ impl eframe::App for TaggerrsTemplate {
                     if ui.button("Settings").clicked() {
                         self.settings_modal_open = true;
                     }
+                    ui.separator();
+                    ui.add_enabled_ui(self.tag_store.is_some(), |ui| {
+                        if ui.button("Export library…").clicked() {
+                            self.library_dialog_action = Some(LibraryDialogAction::Export);
+                            self.library_file_dialog.save_file();
+                        }
+                        if ui.button("Import library (merge)…").clicked() {
+                            self.library_dialog_action = Some(LibraryDialogAction::Import(ImportMode::Merge));
+                            self.library_file_dialog.pick_file();
+                        }
+                        if ui.button("Import library (replace)…").on_hover_text("Drops all existing tags and rules first").clicked() {
+                            self.library_dialog_action = Some(LibraryDialogAction::Import(ImportMode::Replace));
+                            self.library_file_dialog.pick_file();
+                        }
+                    });
+                });
+                ui.menu_button("Edit", |ui| {
+                    let Some(store) = &self.tag_store else {
+                        ui.label("Tag database unavailable");
+                        return;
+                    };
+                    let undo = egui::Button::new(match &history.undo {
+                        Some(description) => format!("Undo {}", description),
+                        None => "Undo".to_string(),
+                    })
+                    .shortcut_text(ctx.format_shortcut(&journal::UNDO_SHORTCUT));
+                    if ui.add_enabled(history.undo.is_some(), undo).clicked() {
+                        journal::spawn_step(ctx, store, &self.runtime, &self.journal_status, true, self.write_xmp_sidecars);
+                    }
+                    let redo = egui::Button::new(match &history.redo {
+                        Some(description) => format!("Redo {}", description),
+                        None => "Redo".to_string(),
+                    })
+                    .shortcut_text(ctx.format_shortcut(&journal::REDO_SHORTCUT));
+                    if ui.add_enabled(history.redo.is_some(), redo).clicked() {
+                        journal::spawn_step(ctx, store, &self.runtime, &self.journal_status, false, self.write_xmp_sidecars);
+                    }
+                });
+                ui.menu_button("View", |ui| {
+                    ui.add_enabled_ui(self.tag_store.is_some(), |ui| {
+                        if ui.checkbox(&mut self.duplicates_state.open, "Duplicates")
+                            .on_hover_text("Identical files across all library paths")
+                            .changed()
+                        {
+                            self.similar_state.open = false;
+                        }
+                        if ui.checkbox(&mut self.similar_state.open, "Similar images")
+                            .on_hover_text("Resized, recompressed or slightly cropped versions of the same image")
+                            .changed()
+                        {
+                            self.similar_state.reference = None;
+                            self.duplicates_state.open = false;
+                        }
+                    });
+                });
+                ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                    if let Some(status) = &import_status {
+                        if ui.small_button("✖").on_hover_text("Dismiss").clicked()
+                            && let Ok(mut current) = self.import_status.try_lock()
+                        {
+                            *current = None;
+                        }
+                        ui.colored_label(ui.visuals().warn_fg_color, status);
+                    }
+                    if let Some(status) = &journal_status {
+                        ui.weak(status);
+                    }
                 });
             });
         });
 
+        if let Some(error) = &self.tag_store_error
+            && !self.tag_store_error_dismissed
+        {
+            egui::Window::new("Tag database unavailable")
+                .collapsible(false)
+                .resizable(false)
+                .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+                .show(ctx, |ui| {
+                    ui.label(error);
+                    ui.add_space(8.0);
+                    ui.label("Tagging and search are disabled for this session.");
+                    if ui.button("OK").clicked() {
+                        self.tag_store_error_dismissed = true;
+                    }
+                });
+        }
+
+        let library_io_status = self.library_io_status.try_lock().ok().and_then(|status| status.clone());
+        if let Some(status) = library_io_status {
+            egui::Window::new("Library export / import")
+                .collapsible(false)
+                .anchor(egui::Align2::CENTER_CENTER, [0.0, 0.0])
+                .show(ctx, |ui| {
+                    if modal::library_io_report(ui, &status)
+                        && let Ok(mut current) = self.library_io_status.try_lock()
+                    {
+                        *current = None;
+                    }
+                });
+        }
+
         if self.settings_modal_open {
             egui::Window::new("Settings")
                 .open(&mut self.settings_modal_open)
@@ -132,11 +445,23 @@ impl eframe::App for TaggerrsTemplate {
                         ui,
                         &mut self.gallery_media_box_size,
                         &mut self.gallery_media_boxes_per_row,
+                        &mut self.write_xmp_sidecars,
+                        &mut self.embedded_metadata_namespace,
+                    );
+                    ui.separator();
+                    modal::auto_tag_settings(
+                        ui,
+                        ctx,
+                        &mut self.auto_tag_rules,
+                        self.tag_store.as_ref(),
+                        &self.runtime,
+                        &self.auto_tag_state,
                     );
                 }
             );
         }
 
+        let completions = self.tag_completions.try_lock().map(|cached| cached.value.clone()).unwrap_or_default();
         egui::SidePanel::left("sidebar").show(ctx, |ui| {
             ui.horizontal(|ui| {
                 if ui.label("Paths").clicked() {
@@ -146,9 +471,14 @@ impl eframe::App for TaggerrsTemplate {
                 if ui.label("Tag Manager").clicked() {
                     self.currently_active_menu = "Tag Manager".to_string();
                 }
+                ui.separator();
+                if ui.label("Collections").clicked() {
+                    self.currently_active_menu = "Collections".to_string();
+                }
             });
             ui.separator();
             if self.currently_active_menu == "Paths" {
+                let previous_path = self.currently_active_path.clone();
                 sidebar_modules::sidebar_paths(
                     ui,
                     ctx,
@@ -157,36 +487,123 @@ impl eframe::App for TaggerrsTemplate {
                     &mut self.currently_active_path,
                     &mut self.current_path_filepaths,
                     &self.directory_scan_state,
+                    &mut self.scan_settings,
                     &mut self.file_dialog,
                 );
+                // Opening a path closes the open collection and the Duplicates and Similar images views
+                if self.currently_active_path != previous_path {
+                    self.collections_state.active = None;
+                    self.duplicates_state.open = false;
+                    self.similar_state.open = false;
+                }
             } else if self.currently_active_menu == "Tag Manager" {
-                sidebar_modules::sidebar_tag_manager(ui);
+                if let Some(error) = &self.tag_store_error {
+                    ui.colored_label(ui.visuals().error_fg_color, format!("Tag database unavailable: {}", error));
+                }
+                if let Some(store) = &self.tag_store {
+                    sidebar_modules::sidebar_tag_manager(
+                        ui,
+                        ctx,
+                        store,
+                        &self.runtime,
+                        &mut self.tag_manager_state,
+                        &completions,
+                        self.write_xmp_sidecars,
+                    );
+                }
+            } else if self.currently_active_menu == "Collections" {
+                if let Some(error) = &self.tag_store_error {
+                    ui.colored_label(ui.visuals().error_fg_color, format!("Tag database unavailable: {}", error));
+                }
+                if let Some(store) = &self.tag_store {
+                    let previous_collection = self.collections_state.active;
+                    sidebar_modules::sidebar_collections(
+                        ui,
+                        ctx,
+                        store,
+                        &self.runtime,
+                        &mut self.collections_state,
+                        &mut self.search_state,
+                        &mut self.currently_active_path,
+                    );
+                    if self.collections_state.active != previous_collection {
+                        self.duplicates_state.open = false;
+                        self.similar_state.open = false;
+                    }
+                }
             }
 
         });
 
         egui::CentralPanel::default().show(ctx, |ui| {
-            if !self.currently_active_path.is_none() {
+            if self.tag_store.is_some() {
+                centralpanel_modules::search_bar(ui, &mut self.search_state, &completions);
+                ui.separator();
+            }
+
+            if self.duplicates_state.open
+                && let Some(store) = &self.tag_store
+            {
+                centralpanel_modules::duplicates_view(
+                    ui,
+                    ctx,
+                    &mut self.duplicates_state,
+                    &self.paths,
+                    store,
+                    &self.runtime,
+                    self.gallery_media_box_size,
+                    &self.image_cache,
+                );
+            } else if self.similar_state.open
+                && let Some(store) = &self.tag_store
+            {
+                centralpanel_modules::similar_images_view(
+                    ui,
+                    ctx,
+                    &mut self.similar_state,
+                    &self.paths,
+                    store,
+                    &self.runtime,
+                    self.gallery_media_box_size,
+                    &self.image_cache,
+                );
+            } else if self.currently_active_path.is_some()
+                || self.search_state.is_active()
+                || self.collections_state.active.is_some()
+            {
                 centralpanel_modules::file_gallery(
                     ui,
                     ctx,
                     &self.currently_active_path,
+                    &self.paths,
                     &mut self.current_path_filepaths,
                     &self.gallery_media_box_size,
                     &self.gallery_media_boxes_per_row,
                     &self.image_cache,
                     &self.runtime,
                     &self.directory_scan_state,
+                    &self.scan_settings,
+                    self.tag_store.as_ref(),
+                    &self.gallery_tags,
+                    &mut self.gallery_selection,
+                    &mut self.search_state,
+                    self.write_xmp_sidecars,
+                    &self.embedded_metadata_namespace,
+                    &self.auto_tag_rules,
+                    &self.auto_tag_state.status,
+                    &self.collections_state,
+                    &completions,
+                    &self.import_status,
                 );
+                if let Some(path) = self.gallery_selection.find_similar.take() {
+                    self.similar_state.reference = Some(path);
+                    self.similar_state.open = true;
+                }
             } else {
                 static_page::default_window(ui);
             }
 
             static_page::footer(ui);
         });
-        
-        // Keep the app responsive even when window loses focus (Linux fix)
-        // Use a timer to avoid excessive CPU usage while still keeping background tasks running
-        ctx.request_repaint_after(std::time::Duration::from_secs(1));
     }
 }
\ No newline at end of file
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use regex::Regex;
+use rusqlite::Connection;
+use tokio::sync::Mutex;
+use crate::app::tag_store::{self, TagStore};
+
+/// What a rule looks at in a file's path.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub enum RuleKind {
+    /// A regular expression matched against the full path, with `/` as the separator.
+    Regex,
+    /// A comma-separated list of file extensions such as `mp4, mkv`.
+    Extension,
+}
+
+/// Tags files whose path matches `pattern`. `tags` is a comma-separated list like the bulk
+/// tagging box; for regex rules `$name`, `${name}` and `$1` are replaced by capture groups,
+/// so `/clients/(?P<client>[^/]+)/` with `client:$client` tags by client folder.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct AutoTagRule {
+    pub enabled: bool,
+    pub kind: RuleKind,
+    pub pattern: String,
+    pub tags: String,
+}
+
+impl Default for AutoTagRule {
+    fn default() -> Self {
+        Self { enabled: true, kind: RuleKind::Extension, pattern: String::new(), tags: String::new() }
+    }
+}
+
+impl AutoTagRule {
+    /// Why the rule cannot run, if it cannot.
+    pub fn error(&self) -> Option<String> {
+        if self.pattern.trim().is_empty() {
+            return Some("needs a pattern".to_string());
+        }
+        if self.tags.split(',').all(|tag| tag_store::parse_tag_path(tag).is_none()) {
+            return Some("needs at least one tag".to_string());
+        }
+        match self.kind {
+            RuleKind::Regex => Regex::new(&self.pattern).err().map(|e| e.to_string()),
+            RuleKind::Extension => None,
+        }
+    }
+}
+
+enum Matcher {
+    Regex(Regex),
+    Extensions(Vec<String>),
+}
+
+/// The enabled, valid rules, compiled once per run. Each keeps its index in the settings list.
+pub struct RuleSet {
+    rules: Vec<(usize, Matcher, String)>,
+}
+
+impl RuleSet {
+    pub fn compile(rules: &[AutoTagRule]) -> Self {
+        let rules = rules
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| rule.enabled && rule.error().is_none())
+            .filter_map(|(index, rule)| {
+                let matcher = match rule.kind {
+                    RuleKind::Regex => Matcher::Regex(Regex::new(&rule.pattern).ok()?),
+                    RuleKind::Extension => Matcher::Extensions(
+                        rule.pattern
+                            .split(',')
+                            .map(|ext| ext.trim().trim_start_matches('.').to_lowercase())
+                            .filter(|ext| !ext.is_empty())
+                            .collect(),
+                    ),
+                };
+                Some((index, matcher, rule.tags.clone()))
+            })
+            .collect();
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The tags each matching rule gives `path`, as `(rule index, tag paths)`.
+    pub fn matches(&self, path: &str) -> Vec<(usize, Vec<Vec<String>>)> {
+        let path = path.replace('\\', "/");
+        let extension = std::path::Path::new(&path)
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+
+        let mut matches = Vec::new();
+        for (index, matcher, template) in &self.rules {
+            let tags = match matcher {
+                Matcher::Regex(regex) => {
+                    let Some(captures) = regex.captures(&path) else {
+                        continue;
+                    };
+                    let mut expanded = String::new();
+                    captures.expand(template, &mut expanded);
+                    expanded
+                }
+                Matcher::Extensions(extensions) => {
+                    if !extension.as_ref().is_some_and(|ext| extensions.contains(ext)) {
+                        continue;
+                    }
+                    template.clone()
+                }
+            };
+            let tags: Vec<Vec<String>> = tags
+                .split(',')
+                .filter_map(tag_store::parse_tag_path)
+                // A capture group that matched nothing leaves a bare `client:` behind
+                .filter(|names| names.iter().all(|name| !name.ends_with(':')))
+                .collect();
+            if !tags.is_empty() {
+                matches.push((*index, tags));
+            }
+        }
+        matches
+    }
+}
+
+/// Attaches the tags of every matching rule, grouped so each tag is written once for all its files.
+/// Returns the number of files that matched a rule.
+pub fn apply_rules(conn: &Connection, rules: &RuleSet, paths: &[String]) -> rusqlite::Result<usize> {
+    let mut files_by_tag: HashMap<Vec<String>, Vec<String>> = HashMap::new();
+    let mut matched = 0;
+    for path in paths {
+        let matches = rules.matches(path);
+        if !matches.is_empty() {
+            matched += 1;
+        }
+        for tag in matches.into_iter().flat_map(|(_, tags)| tags) {
+            files_by_tag.entry(tag).or_default().push(path.clone());
+        }
+    }
+    for (tag, files) in files_by_tag {
+        tag_store::add_tags_to_files(conn, &files, &[tag])?;
+    }
+    Ok(matched)
+}
+
+/// Runs the rules on scanned files, reporting a failure in `status`. Like the scan itself this
+/// is not an undo step; rules are applied on every scan, so a tag a rule gives comes back until
+/// the rule changes.
+pub async fn apply_to_scanned(
+    tag_store: TagStore,
+    paths: Vec<String>,
+    rules: Vec<AutoTagRule>,
+    status: &Arc<Mutex<Option<String>>>,
+) {
+    let rules = RuleSet::compile(&rules);
+    if rules.is_empty() || paths.is_empty() {
+        return;
+    }
+    if let Err(e) = tag_store.write(move |tx| apply_rules(tx, &rules, &paths)).await {
+        *status.lock().await = Some(format!("Applying rules to scanned files failed: {}", e));
+    }
+}
+
+fn library_paths(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare_cached("SELECT path FROM files ORDER BY path")?;
+    stmt.query_map([], |row| row.get(0))?.collect()
+}
+
+/// The files one rule would tag, each with the tags it would get.
+pub type RulePreview = Vec<(String, Vec<String>)>;
+
+/// The files of the library each rule would tag, and with what, without changing anything.
+/// Indexed like the rules; disabled and invalid rules get an empty list.
+pub fn preview(conn: &Connection, rules: &[AutoTagRule]) -> rusqlite::Result<Vec<RulePreview>> {
+    let compiled = RuleSet::compile(rules);
+    let mut preview = vec![Vec::new(); rules.len()];
+    for path in library_paths(conn)? {
+        for (index, tags) in compiled.matches(&path) {
+            let names = tags.iter().map(|names| names.join(" > ")).collect();
+            preview[index].push((path.clone(), names));
+        }
+    }
+    Ok(preview)
+}
+
+/// A finished preview, or why it could not be made.
+pub type PreviewResult = Result<Vec<RulePreview>, String>;
+
+/// The outcome of the last preview and of the last run over the library.
+#[derive(Default)]
+pub struct AutoTagState {
+    pub preview: Arc<Mutex<Option<PreviewResult>>>,
+    pub status: Arc<Mutex<Option<String>>>,
+}
+
+/// Computes a dry-run preview of `rules` in the background.
+pub fn spawn_preview(
+    ctx: &egui::Context,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    state: &AutoTagState,
+    rules: Vec<AutoTagRule>,
+) {
+    let store_clone = tag_store.clone();
+    let preview_clone = state.preview.clone();
+    let ctx_clone = ctx.clone();
+    runtime.spawn(async move {
+        let result = store_clone.read(move |conn| preview(conn, &rules)).await;
+        *preview_clone.lock().await = Some(result.map_err(|e| e.to_string()));
+        ctx_clone.request_repaint();
+    });
+}
+
+/// Runs `rules` over every file in the library as one undo step.
+pub fn spawn_apply_to_library(
+    ctx: &egui::Context,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    state: &AutoTagState,
+    rules: Vec<AutoTagRule>,
+) {
+    let store_clone = tag_store.clone();
+    let status_clone = state.status.clone();
+    let ctx_clone = ctx.clone();
+    runtime.spawn(async move {
+        let rules = RuleSet::compile(&rules);
+        let result = store_clone
+            .write_journaled("Apply auto-tag rules to the library", move |tx| {
+                let paths = library_paths(tx)?;
+                apply_rules(tx, &rules, &paths)
+            })
+            .await;
+        *status_clone.lock().await = Some(match result {
+            Ok(files) => format!("Auto-tag rules matched {} files", files),
+            Err(e) => format!("Applying auto-tag rules failed: {}", e),
+        });
+        ctx_clone.request_repaint();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(kind: RuleKind, pattern: &str, tags: &str) -> AutoTagRule {
+        AutoTagRule { enabled: true, kind, pattern: pattern.to_string(), tags: tags.to_string() }
+    }
+
+    fn paths(tags: &[&[&str]]) -> Vec<Vec<String>> {
+        tags.iter().map(|names| names.iter().map(|name| name.to_string()).collect()).collect()
+    }
+
+    fn matches(rules: &[AutoTagRule], path: &str) -> Vec<(usize, Vec<Vec<String>>)> {
+        RuleSet::compile(rules).matches(path)
+    }
+
+    #[test]
+    fn named_and_braced_captures_expand() {
+        let rules = [rule(RuleKind::Regex, r"/clients/(?P<client>[^/]+)/(?P<year>\d{4})/", "client:$client, ${year}_shoot")];
+        assert_eq!(
+            matches(&rules, "/work/clients/acme/2024/a.jpg"),
+            [(0, paths(&[&["client:acme"], &["2024_shoot"]]))]
+        );
+    }
+
+    #[test]
+    fn numbered_captures_expand_into_hierarchies() {
+        let rules = [rule(RuleKind::Regex, "/projects/([^/]+)/", "project > $1")];
+        assert_eq!(matches(&rules, "/projects/apollo/x.png"), [(0, paths(&[&["project", "apollo"]]))]);
+    }
+
+    #[test]
+    fn captures_that_matched_nothing_drop_their_tag() {
+        let rules = [
+            rule(RuleKind::Regex, "^/photos/(?:clients/(?P<client>[^/]+)/)?", "client:$client, photo"),
+            rule(RuleKind::Regex, "^/photos/(?:clients/(?P<client>[^/]+)/)?", "client:$client"),
+        ];
+        assert_eq!(matches(&rules, "/photos/misc/a.jpg"), [(0, paths(&[&["photo"]]))]);
+        assert_eq!(
+            matches(&rules, "/photos/clients/acme/a.jpg"),
+            [(0, paths(&[&["client:acme"], &["photo"]])), (1, paths(&[&["client:acme"]]))]
+        );
+    }
+
+    #[test]
+    fn regex_rules_see_forward_slashes() {
+        let rules = [rule(RuleKind::Regex, "/clients/([^/]+)/", "$1")];
+        assert_eq!(matches(&rules, r"C:\clients\acme\a.jpg"), [(0, paths(&[&["acme"]]))]);
+    }
+
+    #[test]
+    fn extensions_ignore_case_dots_and_spaces() {
+        let rules = [rule(RuleKind::Extension, " .MP4 , mkv,", "video")];
+        assert_eq!(matches(&rules, "/clips/a.mp4"), [(0, paths(&[&["video"]]))]);
+        assert_eq!(matches(&rules, "/clips/B.Mp4"), [(0, paths(&[&["video"]]))]);
+        assert_eq!(matches(&rules, "/clips/c.MKV"), [(0, paths(&[&["video"]]))]);
+        assert!(matches(&rules, "/clips/a.mp4.txt").is_empty());
+        assert!(matches(&rules, "/clips/mp4").is_empty());
+    }
+
+    #[test]
+    fn disabled_and_invalid_rules_keep_the_indices_of_the_rest() {
+        let disabled = AutoTagRule { enabled: false, ..rule(RuleKind::Extension, "jpg", "photo") };
+        let rules = [disabled, rule(RuleKind::Regex, "(", "broken"), rule(RuleKind::Extension, "jpg", "photo")];
+        assert_eq!(matches(&rules, "/a.jpg"), [(2, paths(&[&["photo"]]))]);
+    }
+
+    #[test]
+    fn rule_errors() {
+        assert_eq!(rule(RuleKind::Extension, "  ", "video").error().as_deref(), Some("needs a pattern"));
+        assert_eq!(rule(RuleKind::Extension, "mp4", " , ").error().as_deref(), Some("needs at least one tag"));
+        assert!(rule(RuleKind::Regex, "(unclosed", "tag").error().is_some());
+        assert_eq!(rule(RuleKind::Regex, "/clients/(?P<client>[^/]+)/", "client:$client").error(), None);
+        assert_eq!(rule(RuleKind::Extension, ".mp4", "video").error(), None);
+    }
+}
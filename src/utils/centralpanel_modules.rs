@@ -1,127 +1,1330 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use crate::app::{ImageData, DirectoryScanState};
+use crate::app::tag_store::{self, GalleryTags, TagStore};
+use crate::app::tag_query::{self, Query, QueryError};
+use crate::app::file_identity;
+use crate::app::journal;
+use crate::app::xmp_sidecar;
+use crate::app::embedded_metadata;
+use crate::app::auto_tag::{self, AutoTagRule};
+use crate::app::collections::{self, AlbumMove, Collection, CollectionsState};
+use crate::app::tag_autocomplete::{self, TagCompletion, TagField};
+use crate::app::duplicates::{self, DuplicatesState};
+use crate::app::perceptual_hash::{self, SimilarState};
+use crate::app::file_attributes::{self, ColorLabel, FileAttributes};
+use crate::app::file_notes::{self, NotesEditor};
+use crate::app::directory_scan::{self, ScanSettings};
+use crate::app::thumbnails;
 
+/// The search field contents and the result set it produced.
+#[derive(Default)]
+pub struct SearchState {
+    pub input: String,
+    pub query: Option<Query>,
+    pub error: Option<QueryError>,
+    pub results: Arc<Mutex<SearchResults>>,
+}
+
+#[derive(Default)]
+pub struct SearchResults {
+    pub query: Option<Query>,
+    pub revision: Option<u64>,
+    /// The matching files, or why the search failed; `None` until the first result arrives.
+    pub files: Option<Result<Vec<String>, String>>,
+}
+
+impl SearchState {
+    pub fn is_active(&self) -> bool {
+        !self.input.trim().is_empty()
+    }
+}
+
+/// Which gallery tiles are selected, plus the bulk tagging input that acts on them.
+#[derive(Default)]
+pub struct GallerySelection {
+    pub path: Option<String>,
+    pub selected: HashSet<String>,
+    pub anchor: Option<usize>,
+    pub rubber_band_origin: Option<egui::Pos2>,
+    pub rubber_band_base: HashSet<String>,
+    pub bulk_tag_input: String,
+    pub error: Arc<Mutex<Option<String>>>,
+    /// Set when "Find similar" is picked on a tile, for the app to open the Similar images view.
+    pub find_similar: Option<String>,
+    pub notes: NotesEditor,
+}
+
+impl GallerySelection {
+    /// The selected files among `files`, in gallery order.
+    fn selected_in(&self, files: &[String]) -> Vec<String> {
+        files.iter().filter(|f| self.selected.contains(*f)).cloned().collect()
+    }
+
+    /// Applies a click on the tile at `index` the way file managers do:
+    /// plain click selects one, ctrl toggles, shift extends from the anchor.
+    fn click(&mut self, index: usize, files: &[String], modifiers: egui::Modifiers) {
+        let path = &files[index];
+        if modifiers.shift && let Some(anchor) = self.anchor {
+            if !modifiers.command {
+                self.selected.clear();
+            }
+            let (start, end) = if anchor <= index { (anchor, index) } else { (index, anchor) };
+            self.selected.extend(files[start..=end].iter().cloned());
+            return;
+        }
+        if modifiers.command {
+            if !self.selected.remove(path) {
+                self.selected.insert(path.clone());
+            }
+        } else {
+            self.selected.clear();
+            self.selected.insert(path.clone());
+        }
+        self.anchor = Some(index);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn file_gallery(
     ui: &mut egui::Ui, 
     ctx: &egui::Context,
     currently_active_path: &Option<String>, 
-    current_path_filepaths: &mut Option<Vec<String>>,
+    library_paths: &[String],
+    current_path_filepaths: &mut Option<Arc<Vec<String>>>,
     gallery_media_box_size: &f32,
     gallery_media_boxes_per_row: &u32,
     image_cache: &Arc<Mutex<HashMap<String, ImageData>>>,
     runtime: &Arc<tokio::runtime::Runtime>,
     directory_scan_state: &Arc<Mutex<HashMap<String, DirectoryScanState>>>,
+    scan_settings: &HashMap<String, ScanSettings>,
+    tag_store: Option<&TagStore>,
+    gallery_tags: &Arc<Mutex<GalleryTags>>,
+    selection: &mut GallerySelection,
+    search: &mut SearchState,
+    write_xmp_sidecars: bool,
+    metadata_namespace: &str,
+    auto_tag_rules: &[AutoTagRule],
+    auto_tag_status: &Arc<Mutex<Option<String>>>,
+    collections: &CollectionsState,
+    completions: &[TagCompletion],
+    import_status: &Arc<Mutex<Option<String>>>,
 ) {
+    if let Some(store) = tag_store {
+        tag_store::refresh_cached(ctx, &collections.collections, store, runtime, collections::list_collections);
+    }
+
+    // An active search replaces the directory listing with its result set
+    if search.is_active() {
+        if search.error.is_none() && let (Some(query), Some(store)) = (search.query.clone(), tag_store) {
+            refresh_search_results(ctx, query, store, search, runtime);
+            let files = search.results.try_lock().ok().and_then(|results| results.files.clone());
+            match files {
+                Some(Ok(files)) => {
+                    ui.label(format!("{} matching files", files.len()));
+                    gallery_grid(
+                        ui,
+                        ctx,
+                        &format!("search: {}", search.input.trim()),
+                        &files,
+                        gallery_media_box_size,
+                        gallery_media_boxes_per_row,
+                        image_cache,
+                        runtime,
+                        tag_store,
+                        gallery_tags,
+                        selection,
+                        write_xmp_sidecars,
+                        collections,
+                        None,
+                        completions,
+                    );
+                }
+                Some(Err(error)) => {
+                    ui.colored_label(ui.visuals().error_fg_color, error);
+                }
+                None => {
+                    ui.vertical_centered(|ui| {
+                        ui.spinner();
+                        ui.label("Searching...");
+                    });
+                }
+            }
+        }
+        return;
+    }
+
+    // An open collection replaces the directory listing the same way
+    if let (Some(collection_id), Some(store)) = (collections.active, tag_store) {
+        refresh_collection_files(ctx, collection_id, store, collections, runtime);
+        let collection = collections
+            .collections
+            .try_lock()
+            .ok()
+            .and_then(|cached| cached.value.iter().find(|c| c.id == collection_id).cloned());
+        let listing = collections.files.try_lock().ok().and_then(|files| files.files.clone());
+        match listing {
+            Some(Ok(files)) => {
+                if let Some(collection) = &collection {
+                    ui.label(format!("{} ({} files)", collection.name, files.len()));
+                }
+                let album = collection.filter(|c| c.is_album()).map(|c| c.id);
+                gallery_grid(
+                    ui,
+                    ctx,
+                    &format!("collection: {}", collection_id),
+                    &files,
+                    gallery_media_box_size,
+                    gallery_media_boxes_per_row,
+                    image_cache,
+                    runtime,
+                    tag_store,
+                    gallery_tags,
+                    selection,
+                    write_xmp_sidecars,
+                    collections,
+                    album,
+                    completions,
+                );
+            }
+            Some(Err(error)) => {
+                ui.colored_label(ui.visuals().error_fg_color, error);
+            }
+            None => {
+                ui.vertical_centered(|ui| {
+                    ui.spinner();
+                    ui.label("Loading collection...");
+                });
+            }
+        }
+        return;
+    }
+
     if let Some(active_path) = currently_active_path {
-        ui.label(format!("{}", active_path));
+        ui.label(active_path);
     }
 
     if let Some(path) = currently_active_path {
-        // Check directory scan state without blocking
-        let scan_state = {
+        // Check directory scan state without blocking; `Some(true)` while the scan is running
+        let scanning = {
             if let Ok(mut state_map) = directory_scan_state.try_lock() {
-                match state_map.get(path) {
-                    Some(DirectoryScanState::Complete(files)) => {
-                        *current_path_filepaths = Some(files.clone());
-                        Some(DirectoryScanState::Complete(files.clone()))
+                // Switching paths stops the scans of the others; they start over when shown again
+                state_map.retain(|scanned, state| {
+                    let stale = scanned != path && matches!(state, DirectoryScanState::Scanning { .. });
+                    if stale {
+                        state.cancel();
                     }
-                    Some(DirectoryScanState::Scanning) => {
-                        Some(DirectoryScanState::Scanning)
+                    !stale
+                });
+
+                match state_map.get(path) {
+                    Some(state) => {
+                        *current_path_filepaths = Some(state.files().clone());
+                        Some(matches!(state, DirectoryScanState::Scanning { .. }))
                     }
-                    _ => {
-                        // Start scanning if not already started
-                        state_map.insert(path.clone(), DirectoryScanState::Scanning);
-                        
-                        // Spawn async task without blocking
-                        let path_clone = path.clone();
-                        let state_clone = directory_scan_state.clone();
-                        let ctx_clone = ctx.clone();
-                        
-                        runtime.spawn(async move {
-                            let files = scan_directory_async(&path_clone).await;
-                            
-                            // Update state without blocking the UI
-                            {
-                                let mut state_map = state_clone.lock().await;
-                                state_map.insert(path_clone, DirectoryScanState::Complete(files));
-                            }
-                            
-                            ctx_clone.request_repaint();
-                        });
-                        
-                        Some(DirectoryScanState::Scanning)
+                    None => {
+                        let settings = scan_settings.get(path).cloned().unwrap_or_default();
+                        let cancel = start_directory_scan(
+                            ctx,
+                            path,
+                            library_paths,
+                            settings,
+                            directory_scan_state,
+                            tag_store,
+                            metadata_namespace,
+                            auto_tag_rules,
+                            auto_tag_status,
+                            import_status,
+                            runtime,
+                        );
+                        state_map.insert(path.clone(), DirectoryScanState::Scanning { files: Arc::default(), cancel });
+                        *current_path_filepaths = Some(Arc::default());
+                        Some(true)
                     }
                 }
             } else {
+                // Busy with a background update, look again shortly
+                ctx.request_repaint_after(tag_store::BUSY_RETRY);
                 None
             }
         };
 
-        match scan_state {
-            Some(DirectoryScanState::Complete(_)) => {
-                if let Some(files) = current_path_filepaths.as_ref() {
-                    egui::ScrollArea::vertical().show(ui, |ui| {
-                        for chunk in files.chunks((*gallery_media_boxes_per_row).try_into().unwrap()) {
-                            ui.horizontal(|ui| {
-                                for image_path in chunk {
-                                    display_image_async(
-                                        ui, 
-                                        ctx,
-                                        image_path, 
-                                        *gallery_media_box_size, 
-                                        image_cache, 
-                                        runtime
-                                    );
+        if scanning == Some(true) {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                let found = current_path_filepaths.as_ref().map_or(0, |files| files.len());
+                ui.label(format!("{} files found…", format_count(found)));
+            });
+        }
+        match current_path_filepaths.as_ref() {
+            Some(files) => {
+                gallery_grid(
+                    ui,
+                    ctx,
+                    path,
+                    files,
+                    gallery_media_box_size,
+                    gallery_media_boxes_per_row,
+                    image_cache,
+                    runtime,
+                    tag_store,
+                    gallery_tags,
+                    selection,
+                    write_xmp_sidecars,
+                    collections,
+                    None,
+                    completions,
+                );
+            }
+            None => {
+                ui.label("Ready to scan...");
+            }
+        }
+    }
+}
+
+/// Scans `path` in the background, adding the files to its `Scanning` state batch by batch
+/// so the gallery fills in as they are found. Returns the flag that cancels the scan.
+#[allow(clippy::too_many_arguments)]
+fn start_directory_scan(
+    ctx: &egui::Context,
+    path: &str,
+    library_paths: &[String],
+    settings: ScanSettings,
+    directory_scan_state: &Arc<Mutex<HashMap<String, DirectoryScanState>>>,
+    tag_store: Option<&TagStore>,
+    metadata_namespace: &str,
+    auto_tag_rules: &[AutoTagRule],
+    auto_tag_status: &Arc<Mutex<Option<String>>>,
+    import_status: &Arc<Mutex<Option<String>>>,
+    runtime: &Arc<tokio::runtime::Runtime>,
+) -> Arc<AtomicBool> {
+    let cancel = Arc::new(AtomicBool::new(false));
+    let cancel_clone = cancel.clone();
+    let path_clone = path.to_string();
+    let state_clone = directory_scan_state.clone();
+    let ctx_clone = ctx.clone();
+    let store_clone = tag_store.cloned();
+    let namespace = metadata_namespace.to_string();
+    let rules = auto_tag_rules.to_vec();
+    let roots = library_paths.to_vec();
+    let status_clone = import_status.clone();
+    let auto_tag_status_clone = auto_tag_status.clone();
+
+    runtime.spawn(async move {
+        let cancel = cancel_clone;
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let walk_path = path_clone.clone();
+        let walk_cancel = cancel.clone();
+        let walk = tokio::task::spawn_blocking(move || {
+            directory_scan::scan_directory_in_batches(&walk_path, &settings, &walk_cancel, |batch| {
+                let _ = sender.send(batch);
+            });
+        });
+
+        // The scan only writes to the state it started; a rescan or path switch replaces it
+        let is_current = |state: Option<&DirectoryScanState>| {
+            matches!(state, Some(DirectoryScanState::Scanning { cancel: current, .. }) if Arc::ptr_eq(current, &cancel))
+        };
+        while let Some(batch) = receiver.recv().await {
+            // Give every scanned file a record (and uuid) in the tag database
+            if let Some(store) = &store_clone {
+                let batch_clone = batch.clone();
+                let _ = store.write(move |tx| tag_store::register_files(tx, &batch_clone)).await;
+            }
+
+            // Update state without blocking the UI
+            let mut state_map = state_clone.lock().await;
+            if !is_current(state_map.get(&path_clone)) {
+                cancel.store(true, Ordering::Relaxed);
+                return;
+            }
+            if let Some(state) = state_map.get_mut(&path_clone) {
+                state.files_mut().extend(batch);
+            }
+            ctx_clone.request_repaint();
+        }
+        let _ = walk.await;
+
+        let files = {
+            let mut state_map = state_clone.lock().await;
+            if cancel.load(Ordering::Relaxed) || !is_current(state_map.get(&path_clone)) {
+                return;
+            }
+            let files = state_map.get(&path_clone).map(|state| state.files().clone()).unwrap_or_default();
+            state_map.insert(path_clone, DirectoryScanState::Complete(files.clone()));
+            files.to_vec()
+        };
+        ctx_clone.request_repaint();
+
+        // Sidecar and embedded keywords, rule tags, content and perceptual hashes are filled in after the gallery is already showing
+        if let Some(store) = store_clone {
+            let problems = xmp_sidecar::import_sidecars(store.clone(), files.clone()).await;
+            directory_scan::report_problems(&status_clone, "Importing XMP sidecars", &problems).await;
+            let problems = embedded_metadata::import_embedded(store.clone(), files.clone(), namespace).await;
+            directory_scan::report_problems(&status_clone, "Importing embedded metadata", &problems).await;
+            auto_tag::apply_to_scanned(store.clone(), files.clone(), rules, &auto_tag_status_clone).await;
+            ctx_clone.request_repaint();
+            let problems = file_identity::hash_files(store.clone(), files.clone(), roots).await;
+            directory_scan::report_problems(&status_clone, "Storing content hashes", &problems).await;
+            ctx_clone.request_repaint();
+            let problems = perceptual_hash::hash_images(store, files).await;
+            directory_scan::report_problems(&status_clone, "Storing perceptual hashes", &problems).await;
+            ctx_clone.request_repaint();
+        }
+    });
+    cancel
+}
+
+/// A count with thousands separators, such as `12,345`.
+fn format_count(count: usize) -> String {
+    let digits = count.to_string();
+    let mut formatted = String::with_capacity(digits.len() + digits.len() / 3);
+    for (index, digit) in digits.chars().enumerate() {
+        if index > 0 && (digits.len() - index).is_multiple_of(3) {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
+    formatted
+}
+
+/// Lays out `files` as selectable tiles with the bulk tag bar above them.
+/// `key` identifies the listing (a directory or a search) so selection resets when it changes.
+#[allow(clippy::too_many_arguments)]
+fn gallery_grid(
+    ui: &mut egui::Ui,
+    ctx: &egui::Context,
+    key: &str,
+    files: &[String],
+    gallery_media_box_size: &f32,
+    gallery_media_boxes_per_row: &u32,
+    image_cache: &Arc<Mutex<HashMap<String, ImageData>>>,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    tag_store: Option<&TagStore>,
+    gallery_tags: &Arc<Mutex<GalleryTags>>,
+    selection: &mut GallerySelection,
+    write_xmp_sidecars: bool,
+    collections: &CollectionsState,
+    album: Option<i64>,
+    completions: &[TagCompletion],
+) {
+    if let Some(store) = tag_store {
+        refresh_gallery_tags(ctx, key, files, store, gallery_tags, runtime);
+    }
+    // Borrowed for the frame rather than copied, which would cost a copy per file every frame
+    let gallery_cache = gallery_tags.try_lock().ok();
+    let no_attributes = HashMap::new();
+    let attributes_by_path = gallery_cache.as_ref().map_or(&no_attributes, |cache| &cache.attributes);
+
+    if selection.path.as_deref() != Some(key) {
+        *selection = GallerySelection {
+            path: Some(key.to_string()),
+            error: selection.error.clone(),
+            bulk_tag_input: std::mem::take(&mut selection.bulk_tag_input),
+            notes: std::mem::take(&mut selection.notes),
+            ..Default::default()
+        };
+    }
+
+    if let Some(store) = tag_store {
+        bulk_tag_bar(ui, ctx, files, selection, store, runtime, write_xmp_sidecars, completions);
+        attribute_shortcuts(ctx, files, selection, store, runtime, attributes_by_path);
+        notes_editor(ui, ctx, selection, store, runtime);
+        let albums: Vec<Collection> = collections
+            .collections
+            .try_lock()
+            .map(|cached| cached.value.iter().filter(|c| c.is_album()).cloned().collect())
+            .unwrap_or_default();
+        if !albums.is_empty() {
+            album_bar(ui, ctx, files, selection, store, runtime, &albums, album);
+        }
+    }
+
+    // At least one, since the grid divides by it
+    let per_row = (*gallery_media_boxes_per_row).max(1) as usize;
+    let mut clicked_index = None;
+    let mut find_similar = None;
+    let mut label_edit = None;
+    let tile_size = tile_size(ui, *gallery_media_box_size);
+    let pitch = tile_size + ui.spacing().item_spacing;
+    // Mouse drags draw the selection rectangle instead of scrolling
+    let scroll_source = egui::scroll_area::ScrollSource { drag: false, ..Default::default() };
+    // Only the rows in view are laid out, so only their tiles load thumbnails
+    let rows = files.len().div_ceil(per_row.max(1));
+    egui::ScrollArea::vertical().scroll_source(scroll_source).show_rows(ui, tile_size.y, rows, |ui, row_range| {
+        // Where the first row would be, so tiles scrolled out of view still have a known place
+        let origin = ui.max_rect().left_top() - egui::vec2(0.0, row_range.start as f32 * pitch.y);
+        let grid = GridLayout { origin, tile_size, pitch, per_row: per_row.max(1) };
+        for row in row_range {
+            let start = row * grid.per_row;
+            let chunk = &files[start..(start + grid.per_row).min(files.len())];
+            ui.horizontal(|ui| {
+                for (column, image_path) in chunk.iter().enumerate() {
+                    let response = display_image_async(
+                        ui, 
+                        ctx,
+                        image_path, 
+                        *gallery_media_box_size, 
+                        image_cache, 
+                        runtime,
+                        gallery_cache.as_ref().and_then(|cache| cache.tags.get(image_path)).map_or(&[], Vec::as_slice),
+                        attributes_by_path.get(image_path),
+                        selection.selected.contains(image_path),
+                    );
+                    if response.clicked() {
+                        clicked_index = Some(start + column);
+                    }
+                    response.context_menu(|ui| {
+                        if perceptual_hash::has_perceptual_hash(image_path) && ui.button("Find similar").clicked() {
+                            find_similar = Some(image_path.clone());
+                            ui.close();
+                        }
+                        if tag_store.is_some() {
+                            ui.menu_button("Color label", |ui| {
+                                for label in ColorLabel::ALL.map(Some).into_iter().chain([None]) {
+                                    let text = label.map_or("None", ColorLabel::name);
+                                    if ui.button(text).clicked() {
+                                        label_edit = Some((image_path.clone(), label));
+                                        ui.close();
+                                    }
                                 }
                             });
                         }
                     });
                 }
+            });
+        }
+        rubber_band_select(ui, files, &grid, selection);
+    });
+
+    if let Some(index) = clicked_index {
+        selection.click(index, files, ui.input(|i| i.modifiers));
+    }
+    if find_similar.is_some() {
+        selection.find_similar = find_similar;
+    }
+    if let (Some((clicked_path, label)), Some(store)) = (label_edit, tag_store) {
+        // The whole selection is labelled when the clicked tile is part of it
+        let paths: Vec<String> = if selection.selected.contains(&clicked_path) {
+            selection.selected_in(files)
+        } else {
+            vec![clicked_path]
+        };
+        let description = match label {
+            Some(label) => format!("Label {} file(s) {}", paths.len(), label.name()),
+            None => format!("Clear the color label of {} file(s)", paths.len()),
+        };
+        journal::spawn_journaled_edit(ctx, store, runtime, &selection.error, description, move |tx| {
+            file_attributes::set_color_label(tx, &paths, label)
+        });
+    }
+}
+
+/// Groups of identical files across the library roots, with a way to keep one copy of each.
+#[allow(clippy::too_many_arguments)]
+pub fn duplicates_view(
+    ui: &mut egui::Ui,
+    ctx: &egui::Context,
+    state: &mut DuplicatesState,
+    roots: &[String],
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    gallery_media_box_size: f32,
+    image_cache: &Arc<Mutex<HashMap<String, ImageData>>>,
+) {
+    if state.roots != roots {
+        state.roots = roots.to_vec();
+        if let Ok(mut groups) = state.groups.try_lock() {
+            groups.invalidate();
+        }
+    }
+    let roots_clone = roots.to_vec();
+    tag_store::refresh_cached_then(
+        ctx,
+        &state.groups,
+        tag_store,
+        runtime,
+        move |conn| duplicates::find_duplicates(conn, &roots_clone),
+        duplicates::drop_missing,
+    );
+    let groups = state.groups.try_lock().map(|cached| cached.value.clone()).unwrap_or_default();
+
+    ui.horizontal(|ui| {
+        ui.heading("Duplicates");
+        let wasted: i64 = groups.iter().map(|group| group.wasted()).sum();
+        ui.label(format!("{} groups, {} wasted", groups.len(), duplicates::format_size(wasted)));
+        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+            if ui.button("Close").clicked() {
+                state.open = false;
+                state.pending_keep = None;
             }
-            Some(DirectoryScanState::Scanning) => {
-                ui.vertical_centered(|ui| {
-                    ui.spinner();
-                    ui.label("Scanning directory...");
+        });
+    });
+    ui.weak("Files are compared by content hash, which is computed in the background after a folder is scanned.");
+    if let Some(status) = state.status.try_lock().ok().and_then(|s| s.clone()) {
+        ui.label(status);
+    }
+    ui.separator();
+
+    egui::ScrollArea::vertical().show(ui, |ui| {
+        for group in &groups {
+            ui.group(|ui| {
+                ui.label(format!(
+                    "{} copies of {} · {} wasted",
+                    group.paths.len(),
+                    duplicates::format_size(group.size),
+                    duplicates::format_size(group.wasted()),
+                ));
+                let pending = state
+                    .pending_keep
+                    .as_ref()
+                    .filter(|(content_hash, _)| *content_hash == group.content_hash)
+                    .map(|(_, keep)| keep.clone());
+
+                ui.horizontal_wrapped(|ui| {
+                    for path in &group.paths {
+                        ui.vertical(|ui| {
+                            ui.set_width(gallery_media_box_size);
+                            let keeping = pending.as_deref() == Some(path.as_str());
+                            display_image_async(ui, ctx, path, gallery_media_box_size, image_cache, runtime, &[], None, keeping);
+                            ui.add(egui::Label::new(egui::RichText::new(path.as_str()).small()).truncate())
+                                .on_hover_text(path.as_str());
+                            if ui.add_enabled(pending.is_none(), egui::Button::new("Keep this copy")).clicked() {
+                                state.pending_keep = Some((group.content_hash.clone(), path.clone()));
+                            }
+                        });
+                    }
                 });
+
+                if let Some(keep) = pending {
+                    ui.horizontal(|ui| {
+                        ui.label(format!(
+                            "Move the other {} copies to the trash and merge their tags onto this one?",
+                            group.paths.len() - 1
+                        ));
+                        if ui.button("Trash others").clicked() {
+                            let others = group.paths.iter().filter(|path| **path != keep).cloned().collect();
+                            duplicates::spawn_keep_one(ctx, tag_store, runtime, &state.status, keep.clone(), others);
+                            state.pending_keep = None;
+                        }
+                        if ui.button("Cancel").clicked() {
+                            state.pending_keep = None;
+                        }
+                    });
+                }
+            });
+        }
+    });
+}
+
+/// Groups of visually similar images across the library roots, or the images that look
+/// like the one "Find similar" was used on.
+#[allow(clippy::too_many_arguments)]
+pub fn similar_images_view(
+    ui: &mut egui::Ui,
+    ctx: &egui::Context,
+    state: &mut SimilarState,
+    roots: &[String],
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    gallery_media_box_size: f32,
+    image_cache: &Arc<Mutex<HashMap<String, ImageData>>>,
+) {
+    ui.horizontal(|ui| {
+        match &state.reference {
+            Some(reference) => {
+                let name = std::path::Path::new(reference).file_name().unwrap_or_default().to_string_lossy();
+                ui.heading(format!("Similar to {}", name)).on_hover_text(reference.as_str());
             }
-            _ => {
-                ui.label("Ready to scan...");
+            None => {
+                ui.heading("Similar images");
+            }
+        }
+        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+            if ui.button("Close").clicked() {
+                state.open = false;
+                state.reference = None;
+            }
+            if state.reference.is_some() && ui.button("All groups").clicked() {
+                state.reference = None;
+            }
+        });
+    });
+    ui.weak("Images are compared by perceptual hash, computed in the background after a folder is scanned.");
+    ui.separator();
+
+    let tile = |ui: &mut egui::Ui, path: &str, caption: String| {
+        ui.vertical(|ui| {
+            ui.set_width(gallery_media_box_size);
+            display_image_async(ui, ctx, path, gallery_media_box_size, image_cache, runtime, &[], None, false);
+            ui.add(egui::Label::new(egui::RichText::new(caption).small()).truncate()).on_hover_text(path);
+        });
+    };
+
+    if let Some(reference) = state.reference.clone() {
+        if state.matches_for.as_ref() != Some(&reference) {
+            state.matches_for = Some(reference.clone());
+            if let Ok(mut matches) = state.matches.try_lock() {
+                matches.invalidate();
+                matches.value = Some(Vec::new());
+            }
+        }
+        let reference_clone = reference.clone();
+        tag_store::refresh_cached_then(
+            ctx,
+            &state.matches,
+            tag_store,
+            runtime,
+            move |conn| perceptual_hash::find_similar(conn, &reference_clone),
+            perceptual_hash::drop_missing_matches,
+        );
+        let matches = state.matches.try_lock().ok().and_then(|cached| cached.value.clone());
+        match matches {
+            None => {
+                ui.label("This image has not been hashed yet. Open its folder and try again once the scan finishes.");
+            }
+            Some(matches) => {
+                ui.label(format!("{} similar images", matches.len()));
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    ui.horizontal_wrapped(|ui| {
+                        tile(ui, &reference, "Original".to_string());
+                        for (path, distance) in &matches {
+                            tile(ui, path, format!("{} bits apart · {}", distance, path));
+                        }
+                    });
+                });
             }
         }
+        return;
+    }
+
+    if state.roots != roots {
+        state.roots = roots.to_vec();
+        if let Ok(mut groups) = state.groups.try_lock() {
+            groups.invalidate();
+        }
+    }
+    let roots_clone = roots.to_vec();
+    tag_store::refresh_cached_then(
+        ctx,
+        &state.groups,
+        tag_store,
+        runtime,
+        move |conn| perceptual_hash::hashed_images(conn, &roots_clone),
+        perceptual_hash::similar_groups,
+    );
+    let groups = state.groups.try_lock().map(|cached| cached.value.clone()).unwrap_or_default();
+
+    ui.label(format!("{} groups", groups.len()));
+    let mut find_similar = None;
+    egui::ScrollArea::vertical().show(ui, |ui| {
+        for group in &groups {
+            ui.group(|ui| {
+                ui.label(format!("{} similar images", group.len()));
+                ui.horizontal_wrapped(|ui| {
+                    for path in group {
+                        ui.vertical(|ui| {
+                            tile(ui, path, path.clone());
+                            if ui.small_button("Find similar").clicked() {
+                                find_similar = Some(path.clone());
+                            }
+                        });
+                    }
+                });
+            });
+        }
+    });
+    if find_similar.is_some() {
+        state.reference = find_similar;
     }
 }
 
-async fn scan_directory_async(path: &str) -> Vec<String> {
-    match tokio::fs::read_dir(path).await {
-        Ok(mut entries) => {
-            let mut files = Vec::new();
-            let mut count = 0;
-            const MAX_FILES: usize = 1000; // Limit to prevent memory issues
-            
-            while let Ok(Some(entry)) = entries.next_entry().await {
-                if count >= MAX_FILES {
-                    break; // Prevent loading too many files at once
+/// Draws the search field and parses its contents on every edit so syntax errors show up inline.
+pub fn search_bar(ui: &mut egui::Ui, search: &mut SearchState, completions: &[TagCompletion]) {
+    ui.horizontal(|ui| {
+        let width = ui.available_width() - 30.0;
+        let response = tag_autocomplete::tag_input(
+            ui,
+            "search",
+            &mut search.input,
+            TagField::Query,
+            completions,
+            "Search tags, e.g. cat AND (outdoor OR garden) AND NOT blurry rating>=4 \"final approved\"",
+            width,
+        );
+        if ui.button("✖").on_hover_text("Clear search").clicked() {
+            search.input.clear();
+            search.query = None;
+            search.error = None;
+        } else if response.changed() {
+            match tag_query::parse(&search.input) {
+                Ok(query) => {
+                    search.query = query;
+                    search.error = None;
                 }
-                
-                let path = entry.path();
-                if path.is_file() {
-                    if let Some(ext) = path.extension() {
-                        let ext_str = ext.to_string_lossy().to_lowercase();
-                        if matches!(ext_str.as_str(), "png" | "jpg" | "jpeg" | "gif" | "bmp" | "webp" | "mp4" | "avi" | "mov" | "mkv" | "webm" | "m4v" | "flv") {
-                            files.push(path.display().to_string());
-                            count += 1;
-                        }
+                Err(e) => {
+                    search.query = None;
+                    search.error = Some(e);
+                }
+            }
+        }
+    });
+
+    if let Some(error) = &search.error {
+        ui.colored_label(ui.visuals().error_fg_color, error.to_string());
+    }
+}
+
+/// Runs the current query against the store whenever the query or the store changes.
+fn refresh_search_results(
+    ctx: &egui::Context,
+    query: Query,
+    tag_store: &TagStore,
+    search: &SearchState,
+    runtime: &Arc<tokio::runtime::Runtime>,
+) {
+    let revision = tag_store.revision();
+    let Ok(mut results) = search.results.try_lock() else {
+        ctx.request_repaint_after(tag_store::BUSY_RETRY);
+        return;
+    };
+    if results.query.as_ref() == Some(&query) && results.revision == Some(revision) {
+        return;
+    }
+    if results.query.as_ref() != Some(&query) {
+        results.files = None;
+    }
+    results.query = Some(query.clone());
+    results.revision = Some(revision);
+    drop(results);
+
+    let store_clone = tag_store.clone();
+    let results_clone = search.results.clone();
+    let ctx_clone = ctx.clone();
+    runtime.spawn(async move {
+        let query_clone = query.clone();
+        let result = store_clone.read(move |conn| tag_query::search(conn, &query_clone)).await;
+        let mut results = results_clone.lock().await;
+        if results.query.as_ref() == Some(&query) {
+            results.files = Some(result.map_err(|e| format!("Search failed: {}", e)));
+        }
+        ctx_clone.request_repaint();
+    });
+}
+
+/// Loads the files of the open collection, again whenever the store changes so saved searches stay live.
+fn refresh_collection_files(
+    ctx: &egui::Context,
+    collection_id: i64,
+    tag_store: &TagStore,
+    collections: &CollectionsState,
+    runtime: &Arc<tokio::runtime::Runtime>,
+) {
+    let revision = tag_store.revision();
+    let Ok(mut listing) = collections.files.try_lock() else {
+        ctx.request_repaint_after(tag_store::BUSY_RETRY);
+        return;
+    };
+    if listing.id == Some(collection_id) && listing.revision == Some(revision) {
+        return;
+    }
+    if listing.id != Some(collection_id) {
+        listing.files = None;
+    }
+    listing.id = Some(collection_id);
+    listing.revision = Some(revision);
+    drop(listing);
+
+    let store_clone = tag_store.clone();
+    let files_clone = collections.files.clone();
+    let ctx_clone = ctx.clone();
+    runtime.spawn(async move {
+        let result = store_clone.read(move |conn| collections::collection_files(conn, collection_id)).await;
+        let mut listing = files_clone.lock().await;
+        if listing.id == Some(collection_id) {
+            listing.files = Some(result.map_err(|e| e.to_string()));
+        }
+        ctx_clone.request_repaint();
+    });
+}
+
+type AlbumEdit = Box<dyn FnOnce(&rusqlite::Transaction) -> rusqlite::Result<()> + Send>;
+
+/// Adds the selection to an album and, inside an album, reorders or removes it.
+#[allow(clippy::too_many_arguments)]
+fn album_bar(
+    ui: &mut egui::Ui,
+    ctx: &egui::Context,
+    files: &[String],
+    selection: &mut GallerySelection,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    albums: &[Collection],
+    album: Option<i64>,
+) {
+    let mut edit: Option<(String, AlbumEdit)> = None;
+
+    ui.add_enabled_ui(!selection.selected.is_empty(), |ui| {
+        ui.horizontal(|ui| {
+            ui.menu_button("Add to album", |ui| {
+                for target in albums.iter().filter(|a| Some(a.id) != album) {
+                    if ui.button(target.name.as_str()).clicked() {
+                        let (id, paths) = (target.id, selection.selected_in(files));
+                        let description = format!("Add {} file(s) to album {}", paths.len(), target.name);
+                        edit = Some((description, Box::new(move |tx| collections::add_to_album(tx, id, &paths))));
+                        ui.close();
                     }
                 }
+            });
+
+            let Some(album_id) = album else {
+                return;
+            };
+            ui.separator();
+            for (label, hover, to) in [
+                ("⏮", "Move to the start", AlbumMove::Start),
+                ("◀", "Move earlier", AlbumMove::Earlier),
+                ("▶", "Move later", AlbumMove::Later),
+                ("⏭", "Move to the end", AlbumMove::End),
+            ] {
+                if ui.button(label).on_hover_text(hover).clicked() {
+                    let chosen = selection.selected.clone();
+                    let description = format!("Reorder {} file(s) in an album", chosen.len());
+                    edit = Some((description, Box::new(move |tx| collections::move_in_album(tx, album_id, &chosen, to))));
+                }
+            }
+            if ui.button("Remove from album").clicked() {
+                let paths = selection.selected_in(files);
+                let description = format!("Remove {} file(s) from an album", paths.len());
+                edit = Some((description, Box::new(move |tx| collections::remove_from_album(tx, album_id, &paths))));
             }
-            files
+        });
+    });
+
+    if let Some((description, edit)) = edit {
+        journal::spawn_journaled_edit(ctx, tag_store, runtime, &selection.error, description, edit);
+    }
+}
+
+/// Shows the selection count and a tag box that adds or removes tags on every selected file.
+#[allow(clippy::too_many_arguments)]
+fn bulk_tag_bar(
+    ui: &mut egui::Ui,
+    ctx: &egui::Context,
+    files: &[String],
+    selection: &mut GallerySelection,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    write_xmp_sidecars: bool,
+    completions: &[TagCompletion],
+) {
+    if ui.input(|i| i.key_pressed(egui::Key::Escape)) && !ctx.wants_keyboard_input() {
+        selection.selected.clear();
+    }
+    if ui.input(|i| i.modifiers.command && i.key_pressed(egui::Key::A)) && !ctx.wants_keyboard_input() {
+        selection.selected = files.iter().cloned().collect();
+    }
+
+    ui.horizontal(|ui| {
+        ui.label(format!("{} selected", selection.selected.len()));
+        if ui.button("Select all").clicked() {
+            selection.selected = files.iter().cloned().collect();
         }
-        Err(_) => Vec::new(),
+        if ui.button("Clear").clicked() {
+            selection.selected.clear();
+        }
+        ui.separator();
+
+        ui.add_enabled_ui(!selection.selected.is_empty(), |ui| {
+            tag_autocomplete::tag_input(
+                ui,
+                "bulk_tags",
+                &mut selection.bulk_tag_input,
+                TagField::List,
+                completions,
+                "tag, artist:name, animal > cat",
+                160.0,
+            );
+            let add = ui.button("Add tags").clicked();
+            let remove = ui.button("Remove tags").clicked();
+            if !(add || remove) {
+                return;
+            }
+
+            let tags: Vec<Vec<String>> = selection
+                .bulk_tag_input
+                .split(',')
+                .filter_map(tag_store::parse_tag_path)
+                .collect();
+            if tags.is_empty() {
+                return;
+            }
+            selection.bulk_tag_input.clear();
+
+            let paths = selection.selected_in(files);
+            let store_clone = tag_store.clone();
+            let error_clone = selection.error.clone();
+            let ctx_clone = ctx.clone();
+            runtime.spawn(async move {
+                // One transaction, and one undo step, for the whole selection
+                let paths_clone = paths.clone();
+                let names: Vec<String> = tags.iter().map(|path| path.join(" > ")).collect();
+                let description = format!(
+                    "{} {} {} {} file(s)",
+                    if add { "Add" } else { "Remove" },
+                    names.join(", "),
+                    if add { "to" } else { "from" },
+                    paths.len()
+                );
+                let result = journal::journaled_edit(&store_clone, &error_clone, description, move |tx| {
+                    if add {
+                        tag_store::add_tags_to_files(tx, &paths_clone, &tags)
+                    } else {
+                        let leaves: Vec<String> = tags.iter().filter_map(|path| path.last().cloned()).collect();
+                        tag_store::remove_tags_from_files(tx, &paths_clone, &leaves)
+                    }
+                }).await;
+                if result.is_some()
+                    && write_xmp_sidecars
+                    && let Some(message) = xmp_sidecar::rewrite_sidecars(&store_clone, paths).await
+                {
+                    *error_clone.lock().await = Some(message);
+                }
+                ctx_clone.request_repaint();
+            });
+        });
+    });
+
+    if let Some(error) = selection.error.try_lock().ok().and_then(|e| e.clone()) {
+        ui.colored_label(ui.visuals().error_fg_color, error);
+    }
+}
+
+/// Rates, labels and favorites the selection from the keyboard, as in Lightroom:
+/// 0 to 5 set the stars, 6 to 9 toggle the red, yellow, green and blue labels, and F toggles favorite.
+fn attribute_shortcuts(
+    ctx: &egui::Context,
+    files: &[String],
+    selection: &mut GallerySelection,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    attributes_by_path: &HashMap<String, FileAttributes>,
+) {
+    if selection.selected.is_empty() || ctx.wants_keyboard_input() {
+        return;
+    }
+    const RATING_KEYS: [egui::Key; 6] =
+        [egui::Key::Num0, egui::Key::Num1, egui::Key::Num2, egui::Key::Num3, egui::Key::Num4, egui::Key::Num5];
+    const LABEL_KEYS: [(egui::Key, ColorLabel); 4] = [
+        (egui::Key::Num6, ColorLabel::Red),
+        (egui::Key::Num7, ColorLabel::Yellow),
+        (egui::Key::Num8, ColorLabel::Green),
+        (egui::Key::Num9, ColorLabel::Blue),
+    ];
+    let pressed = |key| ctx.input_mut(|i| i.consume_key(egui::Modifiers::NONE, key));
+    let attributes = |path: &String| attributes_by_path.get(path).copied().unwrap_or_default();
+
+    if let Some(rating) = RATING_KEYS.iter().position(|key| pressed(*key)) {
+        let paths = selection.selected_in(files);
+        let rating = rating as u8;
+        let description = format!("Rate {} file(s) {} star(s)", paths.len(), rating);
+        journal::spawn_journaled_edit(ctx, tag_store, runtime, &selection.error, description, move |tx| {
+            file_attributes::set_rating(tx, &paths, rating)
+        });
+    } else if let Some((_, label)) = LABEL_KEYS.iter().find(|(key, _)| pressed(*key)) {
+        let paths = selection.selected_in(files);
+        // Pressing the key of a label the whole selection already has clears it
+        let label = (!paths.iter().all(|path| attributes(path).color_label == Some(*label))).then_some(*label);
+        let description = match label {
+            Some(label) => format!("Label {} file(s) {}", paths.len(), label.name()),
+            None => format!("Clear the color label of {} file(s)", paths.len()),
+        };
+        journal::spawn_journaled_edit(ctx, tag_store, runtime, &selection.error, description, move |tx| {
+            file_attributes::set_color_label(tx, &paths, label)
+        });
+    } else if pressed(egui::Key::F) {
+        let paths = selection.selected_in(files);
+        let favorite = !paths.iter().all(|path| attributes(path).favorite);
+        let description = format!(
+            "{} {} file(s) {} favorites",
+            if favorite { "Add" } else { "Remove" },
+            paths.len(),
+            if favorite { "to" } else { "from" },
+        );
+        journal::spawn_journaled_edit(ctx, tag_store, runtime, &selection.error, description, move |tx| {
+            file_attributes::set_favorite(tx, &paths, favorite)
+        });
     }
 }
 
+/// Edits the notes of the selected file when exactly one is selected. A changed draft is
+/// saved when the selection moves on, so switching files never loses what was typed.
+fn notes_editor(
+    ui: &mut egui::Ui,
+    ctx: &egui::Context,
+    selection: &mut GallerySelection,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+) {
+    let selected = match selection.selected.len() {
+        1 => selection.selected.iter().next().cloned(),
+        _ => None,
+    };
+    let saved = |editor: &NotesEditor, path: &str| {
+        editor
+            .saved
+            .try_lock()
+            .ok()
+            .and_then(|saved| saved.clone())
+            .filter(|(saved_path, _)| saved_path == path)
+            .map(|(_, body)| body)
+    };
+
+    let editor = &mut selection.notes;
+    if editor.path != selected {
+        if let Some(path) = editor.path.take()
+            && editor.loaded
+            && saved(editor, &path).as_deref() != Some(editor.draft.as_str())
+        {
+            save_notes(ctx, tag_store, runtime, &selection.error, editor, path);
+        }
+        editor.path = selected.clone();
+        editor.draft.clear();
+        editor.loaded = false;
+
+        if let Some(path) = selected.clone() {
+            let store_clone = tag_store.clone();
+            let saved_clone = editor.saved.clone();
+            let ctx_clone = ctx.clone();
+            runtime.spawn(async move {
+                let path_clone = path.clone();
+                if let Ok(body) = store_clone.read(move |conn| file_notes::note_for_path(conn, &path_clone)).await {
+                    *saved_clone.lock().await = Some((path, body));
+                }
+                ctx_clone.request_repaint();
+            });
+        }
+    }
+    let Some(path) = selected else {
+        return;
+    };
+
+    let saved_body = saved(editor, &path);
+    if !editor.loaded && let Some(body) = &saved_body {
+        editor.draft = body.clone();
+        editor.loaded = true;
+    }
+
+    egui::CollapsingHeader::new("Notes").id_salt("file_notes").show(ui, |ui| {
+        if !editor.loaded {
+            ui.spinner();
+            return;
+        }
+        ui.add(
+            egui::TextEdit::multiline(&mut editor.draft)
+                .desired_rows(3)
+                .desired_width(f32::INFINITY)
+                .hint_text("Notes on this file, found by searching for a \"quoted phrase\""),
+        );
+        let changed = saved_body.as_deref() != Some(editor.draft.as_str());
+        if ui.add_enabled(changed, egui::Button::new("Save notes")).clicked() {
+            save_notes(ctx, tag_store, runtime, &selection.error, editor, path);
+        }
+    });
+}
+
+/// Stores the draft as the notes of `path`, as one undo step.
+fn save_notes(
+    ctx: &egui::Context,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    error: &Arc<Mutex<Option<String>>>,
+    editor: &NotesEditor,
+    path: String,
+) {
+    let body = editor.draft.clone();
+    let file_name = std::path::Path::new(&path).file_name().map(|name| name.to_string_lossy().to_string());
+    let description = format!("Edit the notes of {}", file_name.as_deref().unwrap_or(&path));
+    let store_clone = tag_store.clone();
+    let saved_clone = editor.saved.clone();
+    let error_clone = error.clone();
+    let ctx_clone = ctx.clone();
+    runtime.spawn(async move {
+        let (path_clone, body_clone) = (path.clone(), body.clone());
+        let result = journal::journaled_edit(&store_clone, &error_clone, description, move |tx| {
+            file_notes::set_note(tx, &path_clone, &body_clone)
+        })
+        .await;
+        if result.is_some() {
+            // The editor may have moved on and loaded another file's notes meanwhile
+            let mut saved = saved_clone.lock().await;
+            if saved.as_ref().is_none_or(|(saved_path, _)| *saved_path == path) {
+                *saved = Some((path, body));
+            }
+        }
+        ctx_clone.request_repaint();
+    });
+}
+
+/// The colour label dot, stars and favorite heart of a gallery tile.
+fn attribute_badges(ui: &mut egui::Ui, attributes: &FileAttributes) {
+    let mut summary = Vec::new();
+    let response = ui.horizontal(|ui| {
+        ui.spacing_mut().item_spacing.x = 4.0;
+        if let Some(label) = attributes.color_label {
+            let (rect, _) = ui.allocate_exact_size(egui::vec2(10.0, 10.0), egui::Sense::hover());
+            ui.painter().circle_filled(rect.center(), 5.0, label.color());
+            summary.push(format!("{} label", label.name()));
+        }
+        if attributes.rating > 0 {
+            let stars = "★".repeat(attributes.rating as usize);
+            ui.label(egui::RichText::new(stars).color(egui::Color32::from_rgb(0xF2, 0xB7, 0x05)));
+            summary.push(format!("{} of {} stars", attributes.rating, file_attributes::MAX_RATING));
+        }
+        if attributes.favorite {
+            ui.label(egui::RichText::new("♥").color(egui::Color32::from_rgb(0xE0, 0x4B, 0x4B)));
+            summary.push("favorite".to_string());
+        }
+    });
+    response.response.on_hover_text(summary.join(", "));
+}
+
+/// Where the tiles of the gallery grid are, laid out or not.
+struct GridLayout {
+    /// Top left corner of the first tile.
+    origin: egui::Pos2,
+    tile_size: egui::Vec2,
+    /// Distance from one tile to the next, spacing included.
+    pitch: egui::Vec2,
+    per_row: usize,
+}
+
+impl GridLayout {
+    /// Indexes of the first `count` tiles that touch `rect`.
+    fn tiles_touching(&self, rect: egui::Rect, count: usize) -> Vec<usize> {
+        let first_row = ((rect.top() - self.origin.y) / self.pitch.y).floor().max(0.0) as usize;
+        let last_row = ((rect.bottom() - self.origin.y) / self.pitch.y).floor();
+        if last_row < 0.0 {
+            return Vec::new();
+        }
+        let rows = first_row..=(last_row as usize).min(count.div_ceil(self.per_row));
+        rows.flat_map(|row| (0..self.per_row).map(move |column| (row, column)))
+            .filter(|&(row, column)| {
+                let min = self.origin + egui::vec2(column as f32 * self.pitch.x, row as f32 * self.pitch.y);
+                egui::Rect::from_min_size(min, self.tile_size).intersects(rect)
+            })
+            .map(|(row, column)| row * self.per_row + column)
+            .filter(|&index| index < count)
+            .collect()
+    }
+}
+
+/// Lets the user drag a rectangle over the grid to select every tile it touches.
+/// Holding ctrl adds to the current selection instead of replacing it.
+fn rubber_band_select(
+    ui: &mut egui::Ui,
+    files: &[String],
+    grid: &GridLayout,
+    selection: &mut GallerySelection,
+) {
+    // Only senses drags, so clicks still reach the tiles underneath
+    let band = ui.interact(ui.min_rect(), ui.id().with("rubber_band"), egui::Sense::drag());
+
+    if band.drag_started() {
+        selection.rubber_band_origin = band.interact_pointer_pos();
+        selection.rubber_band_base = if ui.input(|i| i.modifiers.command) {
+            selection.selected.clone()
+        } else {
+            HashSet::new()
+        };
+    }
+
+    if let (Some(origin), Some(current)) = (selection.rubber_band_origin, band.interact_pointer_pos()) {
+        let band_rect = egui::Rect::from_two_pos(origin, current);
+        let visuals = &ui.visuals().selection;
+        ui.painter().rect(
+            band_rect,
+            0.0,
+            visuals.bg_fill.gamma_multiply(0.3),
+            visuals.stroke,
+            egui::StrokeKind::Inside,
+        );
+
+        selection.selected = selection.rubber_band_base.clone();
+        for index in grid.tiles_touching(band_rect, files.len()) {
+            selection.selected.insert(files[index].clone());
+        }
+    }
+
+    if band.drag_stopped() {
+        selection.rubber_band_origin = None;
+        selection.rubber_band_base.clear();
+    }
+}
+
+/// Reloads the tags and attributes of the listed files when the active path or the tag store changed.
+fn refresh_gallery_tags(
+    ctx: &egui::Context,
+    active_path: &str,
+    files: &[String],
+    tag_store: &TagStore,
+    gallery_tags: &Arc<Mutex<GalleryTags>>,
+    runtime: &Arc<tokio::runtime::Runtime>,
+) {
+    let revision = tag_store.revision();
+    let Ok(mut cache) = gallery_tags.try_lock() else {
+        ctx.request_repaint_after(tag_store::BUSY_RETRY);
+        return;
+    };
+    if cache.path.as_deref() != Some(active_path) {
+        cache.path = Some(active_path.to_string());
+        cache.revision = None;
+        cache.loading = None;
+        cache.tags.clear();
+        cache.attributes.clear();
+    }
+    if cache.revision == Some(revision) || cache.loading == Some(revision) {
+        return;
+    }
+    cache.loading = Some(revision);
+    drop(cache);
+
+    let store_clone = tag_store.clone();
+    let cache_clone = gallery_tags.clone();
+    let ctx_clone = ctx.clone();
+    let path_clone = active_path.to_string();
+    let files_clone = files.to_vec();
+    runtime.spawn(async move {
+        let result = store_clone
+            .read(move |conn| {
+                let tags = tag_store::tags_for_paths(conn, &files_clone)?;
+                Ok((tags, file_attributes::attributes_for_paths(conn, &files_clone)?))
+            })
+            .await;
+        let mut cache = cache_clone.lock().await;
+        if cache.path.as_deref() != Some(path_clone.as_str()) {
+            return;
+        }
+        if cache.loading == Some(revision) {
+            cache.loading = None;
+        }
+        // As in `refresh_cached`, a failed read leaves the revision as it was so the next frame
+        // tries again, and a slow read never replaces a newer one
+        if let Ok((tags, attributes)) = result
+            && cache.revision.is_none_or(|stored| stored < revision)
+        {
+            cache.revision = Some(revision);
+            cache.tags = tags;
+            cache.attributes = attributes;
+        }
+        ctx_clone.request_repaint();
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
 fn display_image_async(
     ui: &mut egui::Ui,
     ctx: &egui::Context,
@@ -129,122 +1332,192 @@ fn display_image_async(
     box_size: f32,
     image_cache: &Arc<Mutex<HashMap<String, ImageData>>>,
     runtime: &Arc<tokio::runtime::Runtime>,
-) {
+    tags: &[String],
+    attributes: Option<&FileAttributes>,
+    selected: bool,
+) -> egui::Response {
+    // Tiles have a fixed size so the gallery can skip rows it does not show
+    let (rect, _) = ui.allocate_exact_size(tile_size(ui, box_size), egui::Sense::hover());
+    let id = ui.id().with(("tile", image_path));
+    if !ui.is_rect_visible(rect) {
+        // Nothing to draw and nothing to load until it scrolls into view
+        return ui.interact(rect, id, egui::Sense::click());
+    }
+
     let cache_clone = image_cache.clone();
     let ctx_clone = ctx.clone();
     let path_clone = image_path.to_string();
+    let size = thumbnails::thumbnail_size(box_size, ctx.pixels_per_point());
+    let frame_nr = ctx.cumulative_frame_nr();
     
     // Check if image is in cache without blocking
-    let cached_image = if let Ok(cache) = cache_clone.try_lock() {
-        cache.get(&path_clone).cloned()
+    let cached_image = if let Ok(mut cache) = cache_clone.try_lock() {
+        cache.get_mut(&path_clone).map(|image_data| {
+            image_data.last_drawn = frame_nr;
+            image_data.clone()
+        })
     } else {
+        ctx.request_repaint_after(tag_store::BUSY_RETRY);
         None
     };
     
-    ui.group(|ui| {
+    let mut frame = egui::Frame::group(ui.style());
+    if selected {
+        frame = frame
+            .fill(ui.visuals().selection.bg_fill.gamma_multiply(0.4))
+            .stroke(ui.visuals().selection.stroke);
+    }
+
+    let mut tile_ui = ui.new_child(egui::UiBuilder::new().max_rect(rect).layout(egui::Layout::top_down(egui::Align::Min)));
+    // Captions that do not fit are cut off rather than growing the tile
+    tile_ui.set_clip_rect(rect.intersect(ui.clip_rect()));
+    frame.show(&mut tile_ui, |ui| {
         ui.vertical(|ui| {
             ui.set_width(box_size);
-            ui.set_height(box_size);
+            ui.set_height(box_size + caption_height(ui));
             
+            // A thumbnail of another size stays up until the new one is ready
+            let needs_load = cached_image.as_ref().is_none_or(|image_data| !image_data.loading && image_data.size != size);
             match cached_image {
-                Some(image_data) if !image_data.loading => {
+                Some(image_data) if image_data.failed => {
+                    ui.label("⚠");
+                    ui.weak("No preview");
+                }
+                Some(image_data) if !image_data.bytes.is_empty() => {
                     // Image is loaded, display it
+                    ui.add(
+                        egui::Image::from_bytes(image_data.uri, image_data.bytes)
+                            .max_width(box_size - 10.0)
+                            .max_height(box_size - 10.0)
+                    );
                     if is_video_file(&path_clone) {
-                        // For videos, show thumbnail if available, otherwise show placeholder
-                        ui.add(
-                            egui::Image::from_bytes(path_clone.clone(), image_data.bytes)
-                                .max_width(box_size - 10.0)
-                                .max_height(box_size - 10.0)
-                        );
                         ui.label("🎬 Video");
-                    } else {
-                        ui.add(
-                            egui::Image::from_bytes(path_clone.clone(), image_data.bytes)
-                                .max_width(box_size - 10.0)
-                                .max_height(box_size - 10.0)
-                        );
                     }
                 }
-                Some(_) => {
+                _ => {
                     // Loading placeholder
                     ui.spinner();
                     ui.label("Loading...");
                 }
-                None => {
-                    // Not loaded yet, start loading
-                    ui.spinner();
-                    ui.label("Loading...");
-                    
-                    // Start loading without blocking
-                    let cache_clone2 = cache_clone.clone();
-                    let path_clone2 = path_clone.clone();
-                    
-                    // Limit concurrent image loading to prevent overwhelming the system
-                    runtime.spawn(async move {
-                        // Check if another task is already loading this image
-                        {
-                            let mut cache = cache_clone2.lock().await;
-                            if cache.contains_key(&path_clone2) {
-                                return; // Already being loaded
-                            }
-                            
-                            // Limit total cache size to prevent memory issues
-                            if cache.len() > 200 {
-                                // Remove some old entries
-                                let keys_to_remove: Vec<String> = cache.keys().take(50).cloned().collect();
-                                for key in keys_to_remove {
-                                    cache.remove(&key);
+            }
+
+            if needs_load {
+                // Start loading without blocking
+                let cache_clone2 = cache_clone.clone();
+                let path_clone2 = path_clone.clone();
+                
+                runtime.spawn(async move {
+                    // Check if another task is already loading this image
+                    {
+                        let mut cache = cache_clone2.lock().await;
+                        if cache.get(&path_clone2).is_some_and(|image_data| image_data.loading || image_data.size == size) {
+                            return; // Already being loaded
+                        }
+                        
+                        // Limit total cache size to prevent memory issues
+                        if cache.len() > 200 {
+                            // Drop the tiles unseen the longest; ones on screen now are never dropped
+                            let mut unseen: Vec<(u64, String)> = cache
+                                .iter()
+                                .filter(|(_, image_data)| image_data.last_drawn < frame_nr)
+                                .map(|(key, image_data)| (image_data.last_drawn, key.clone()))
+                                .collect();
+                            unseen.sort_unstable();
+                            for (_, key) in unseen.into_iter().take(50) {
+                                if let Some(image_data) = cache.remove(&key) {
+                                    ctx_clone.forget_image(&image_data.uri);
                                 }
                             }
-                            
-                            cache.insert(path_clone2.clone(), ImageData {
-                                bytes: Vec::new(),
-                                loading: true,
-                            });
                         }
                         
-                        // Load image/video thumbnail asynchronously
-                        match load_image_or_thumbnail_async(&path_clone2).await {
-                            Ok(bytes) => {
-                                // Check if the bytes are reasonable size (under 10MB)
-                                if bytes.len() < 10 * 1024 * 1024 {
-                                    let mut cache = cache_clone2.lock().await;
-                                    cache.insert(path_clone2, ImageData {
-                                        bytes,
-                                        loading: false,
-                                    });
-                                    ctx_clone.request_repaint();
-                                } else {
-                                    // File too large, remove from cache
-                                    let mut cache = cache_clone2.lock().await;
-                                    cache.remove(&path_clone2);
-                                }
+                        let previous = cache.remove(&path_clone2);
+                        cache.insert(path_clone2.clone(), ImageData {
+                            loading: true,
+                            size,
+                            failed: false,
+                            last_drawn: frame_nr,
+                            ..previous.unwrap_or_default()
+                        });
+                    }
+                    
+                    // Load image/video thumbnail asynchronously
+                    match load_image_or_thumbnail_async(&path_clone2, size).await {
+                        Ok((uri, bytes)) => {
+                            let mut cache = cache_clone2.lock().await;
+                            let previous = cache.insert(path_clone2, ImageData {
+                                uri: uri.clone(),
+                                bytes,
+                                size,
+                                loading: false,
+                                failed: false,
+                                last_drawn: frame_nr,
+                            });
+                            // The texture of a thumbnail of another size is not needed anymore
+                            if let Some(previous) = previous.filter(|previous| previous.uri != uri) {
+                                ctx_clone.forget_image(&previous.uri);
                             }
-                            Err(_) => {
-                                // Handle loading error by removing from cache
-                                let mut cache = cache_clone2.lock().await;
-                                cache.remove(&path_clone2);
+                            ctx_clone.request_repaint();
+                        }
+                        Err(_) => {
+                            // Remembered so the tile is not loaded again every frame; a change
+                            // to the file drops the entry and with it the failure
+                            let mut cache = cache_clone2.lock().await;
+                            let previous = cache.insert(path_clone2, ImageData { size, failed: true, last_drawn: frame_nr, ..ImageData::default() });
+                            if let Some(previous) = previous {
+                                ctx_clone.forget_image(&previous.uri);
                             }
+                            ctx_clone.request_repaint();
                         }
-                    });
-                }
+                    }
+                });
             }
             
+            if let Some(attributes) = attributes.filter(|a| !a.is_empty()) {
+                attribute_badges(ui, attributes);
+            }
+
             // Show filename
             if let Some(filename) = std::path::Path::new(&path_clone).file_name() {
                 ui.label(filename.to_string_lossy());
             }
+
+            if !tags.is_empty() {
+                ui.horizontal_wrapped(|ui| {
+                    for tag in tags {
+                        ui.small(tag);
+                    }
+                });
+            }
         });
     });
+    // Sensed after the contents so a click on the captions still picks the tile
+    ui.interact(rect, id, egui::Sense::click())
 }
 
-async fn load_image_or_thumbnail_async(path: &str) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+/// Room under a thumbnail for the badges, the file name and a line of tags.
+fn caption_height(ui: &egui::Ui) -> f32 {
+    let spacing = ui.spacing().item_spacing.y;
+    2.0 * (ui.text_style_height(&egui::TextStyle::Body) + spacing)
+        + ui.text_style_height(&egui::TextStyle::Small)
+        + spacing
+}
+
+/// Outer size of a gallery tile, frame included. Every tile has this size.
+fn tile_size(ui: &egui::Ui, box_size: f32) -> egui::Vec2 {
+    egui::vec2(box_size, box_size + caption_height(ui)) + egui::Frame::group(ui.style()).total_margin().sum()
+}
+
+/// The bytes to show for `path` and the URI egui caches their texture under.
+async fn load_image_or_thumbnail_async(path: &str, size: u32) -> Result<(String, Vec<u8>), Box<dyn std::error::Error + Send + Sync>> {
     if is_video_file(path) {
         // Generate video thumbnail
-        generate_video_thumbnail_async(path).await
+        Ok((path.to_string(), generate_video_thumbnail_async(path).await?))
+    } else if thumbnails::can_thumbnail(path) {
+        let thumbnail = thumbnails::load_thumbnail(path, size).await?;
+        Ok((thumbnail.uri, thumbnail.bytes))
     } else {
-        // Load image directly
-        tokio::fs::read(path).await.map_err(|e| e.into())
+        // Formats the image crate cannot decode are left to egui's loaders
+        Ok((path.to_string(), tokio::fs::read(path).await?))
     }
 }
 
@@ -270,6 +1543,16 @@ async fn generate_video_thumbnail_async(video_path: &str) -> Result<Vec<u8>, Box
     Ok(placeholder_svg.into_bytes())
 }
 
+/// Whether the gallery can show this file, judged by its extension.
+pub fn is_media_file(path: &str) -> bool {
+    if let Some(ext) = std::path::Path::new(path).extension() {
+        let ext_str = ext.to_string_lossy().to_lowercase();
+        matches!(ext_str.as_str(), "png" | "jpg" | "jpeg" | "gif" | "bmp" | "webp" | "mp4" | "avi" | "mov" | "mkv" | "webm" | "m4v" | "flv")
+    } else {
+        false
+    }
+}
+
 fn is_video_file(path: &str) -> bool {
     if let Some(ext) = std::path::Path::new(path).extension() {
         let ext_str = ext.to_string_lossy().to_lowercase();
@@ -277,4 +1560,4 @@ fn is_video_file(path: &str) -> bool {
     } else {
         false
     }
-}
\ No newline at end of file
+}
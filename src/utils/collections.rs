@@ -0,0 +1,208 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::sync::Mutex;
+use crate::app::tag_query;
+use crate::app::tag_store::{self, Cached};
+
+/// A saved search (with a query) or a manually ordered album (without one).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Collection {
+    pub id: i64,
+    pub name: String,
+    pub query: Option<String>,
+    /// Number of files in an album; saved searches are counted when opened.
+    pub file_count: i64,
+}
+
+impl Collection {
+    pub fn is_album(&self) -> bool {
+        self.query.is_none()
+    }
+}
+
+/// Where the selected files of an album go when reordering.
+#[derive(Clone, Copy, PartialEq)]
+pub enum AlbumMove {
+    Start,
+    Earlier,
+    Later,
+    End,
+}
+
+/// State of the Collections tab, shared with the gallery that shows the open collection.
+#[derive(Default)]
+pub struct CollectionsState {
+    pub collections: Arc<Mutex<Cached<Vec<Collection>>>>,
+    /// The collection the gallery shows instead of a path.
+    pub active: Option<i64>,
+    pub files: Arc<Mutex<CollectionFiles>>,
+    pub search_name_input: String,
+    pub album_name_input: String,
+    pub renaming: Option<(i64, String)>,
+    pub error: Arc<Mutex<Option<String>>>,
+}
+
+/// The files of the open collection and the store revision they were loaded at.
+#[derive(Default)]
+pub struct CollectionFiles {
+    pub id: Option<i64>,
+    pub revision: Option<u64>,
+    pub files: Option<Result<Vec<String>, String>>,
+}
+
+/// Saved searches first, then albums, each sorted by name.
+pub fn list_collections(conn: &Connection) -> rusqlite::Result<Vec<Collection>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT c.id, c.name, c.query, COUNT(a.file_id) FROM collections c
+         LEFT JOIN album_files a ON a.collection_id = c.id
+         GROUP BY c.id
+         ORDER BY c.query IS NULL, c.name COLLATE NOCASE",
+    )?;
+    stmt.query_map([], |row| {
+        Ok(Collection { id: row.get(0)?, name: row.get(1)?, query: row.get(2)?, file_count: row.get(3)? })
+    })?
+    .collect()
+}
+
+fn ensure_unused_name(conn: &Connection, name: &str, except: Option<i64>) -> rusqlite::Result<()> {
+    let taken: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM collections WHERE name = ?1 AND id IS NOT ?2)",
+        params![name, except],
+        |row| row.get(0),
+    )?;
+    if taken {
+        return Err(tag_store::rule_violation(format!("a collection named \"{}\" already exists", name)));
+    }
+    Ok(())
+}
+
+/// Saves `query` under `name`. The query is checked here so a saved search always parses.
+pub fn create_saved_search(conn: &Connection, name: &str, query: &str) -> rusqlite::Result<()> {
+    match tag_query::parse(query) {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err(tag_store::rule_violation("a saved search needs a query".to_string())),
+        Err(e) => return Err(tag_store::rule_violation(e.to_string())),
+    }
+    ensure_unused_name(conn, name, None)?;
+    conn.execute(
+        "INSERT INTO collections (name, query, created_at) VALUES (?1, ?2, ?3)",
+        params![name, query.trim(), tag_store::now_unix()],
+    )?;
+    Ok(())
+}
+
+pub fn create_album(conn: &Connection, name: &str) -> rusqlite::Result<()> {
+    ensure_unused_name(conn, name, None)?;
+    conn.execute(
+        "INSERT INTO collections (name, query, created_at) VALUES (?1, NULL, ?2)",
+        params![name, tag_store::now_unix()],
+    )?;
+    Ok(())
+}
+
+pub fn rename_collection(conn: &Connection, id: i64, name: &str) -> rusqlite::Result<()> {
+    ensure_unused_name(conn, name, Some(id))?;
+    conn.execute("UPDATE collections SET name = ?1 WHERE id = ?2", params![name, id])?;
+    Ok(())
+}
+
+pub fn delete_collection(conn: &Connection, id: i64) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM collections WHERE id = ?1", [id])?;
+    Ok(())
+}
+
+/// The files of a collection: the live results of a saved search, or an album in its own order.
+pub fn collection_files(conn: &Connection, id: i64) -> rusqlite::Result<Vec<String>> {
+    let query: Option<Option<String>> = conn
+        .query_row("SELECT query FROM collections WHERE id = ?1", [id], |row| row.get(0))
+        .optional()?;
+    match query {
+        None => Err(tag_store::rule_violation("this collection no longer exists".to_string())),
+        Some(Some(query)) => match tag_query::parse(&query) {
+            Ok(Some(query)) => tag_query::search(conn, &query),
+            Ok(None) => Ok(Vec::new()),
+            Err(e) => Err(tag_store::rule_violation(e.to_string())),
+        },
+        Some(None) => album_files(conn, id),
+    }
+}
+
+fn album_files(conn: &Connection, album_id: i64) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT f.path FROM album_files a JOIN files f ON f.id = a.file_id
+         WHERE a.collection_id = ?1
+         ORDER BY a.position",
+    )?;
+    stmt.query_map([album_id], |row| row.get(0))?.collect()
+}
+
+/// Appends `paths` to the end of an album in the given order, skipping files already in it.
+pub fn add_to_album(conn: &Connection, album_id: i64, paths: &[String]) -> rusqlite::Result<()> {
+    let mut position: i64 = conn.query_row(
+        "SELECT COALESCE(MAX(position) + 1, 0) FROM album_files WHERE collection_id = ?1",
+        [album_id],
+        |row| row.get(0),
+    )?;
+    let mut stmt = conn.prepare_cached(
+        "INSERT OR IGNORE INTO album_files (collection_id, file_id, position) VALUES (?1, ?2, ?3)",
+    )?;
+    for path in paths {
+        let file_id = tag_store::ensure_file(conn, path)?;
+        if stmt.execute(params![album_id, file_id, position])? > 0 {
+            position += 1;
+        }
+    }
+    Ok(())
+}
+
+pub fn remove_from_album(conn: &Connection, album_id: i64, paths: &[String]) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "DELETE FROM album_files
+         WHERE collection_id = ?1 AND file_id = (SELECT id FROM files WHERE path = ?2)",
+    )?;
+    for path in paths {
+        stmt.execute(params![album_id, path])?;
+    }
+    Ok(())
+}
+
+/// Moves the `selected` files of an album one step or all the way to either end,
+/// keeping their order relative to each other.
+pub fn move_in_album(conn: &Connection, album_id: i64, selected: &HashSet<String>, to: AlbumMove) -> rusqlite::Result<()> {
+    let mut order = album_files(conn, album_id)?;
+    match to {
+        AlbumMove::Start | AlbumMove::End => {
+            let (mut moved, rest): (Vec<String>, Vec<String>) = order.into_iter().partition(|path| selected.contains(path));
+            order = if to == AlbumMove::Start {
+                moved.extend(rest);
+                moved
+            } else {
+                rest.into_iter().chain(moved).collect()
+            };
+        }
+        AlbumMove::Earlier => {
+            for index in 1..order.len() {
+                if selected.contains(&order[index]) && !selected.contains(&order[index - 1]) {
+                    order.swap(index, index - 1);
+                }
+            }
+        }
+        AlbumMove::Later => {
+            for index in (0..order.len().saturating_sub(1)).rev() {
+                if selected.contains(&order[index]) && !selected.contains(&order[index + 1]) {
+                    order.swap(index, index + 1);
+                }
+            }
+        }
+    }
+
+    let mut stmt = conn.prepare_cached(
+        "UPDATE album_files SET position = ?3
+         WHERE collection_id = ?1 AND file_id = (SELECT id FROM files WHERE path = ?2) AND position != ?3",
+    )?;
+    for (position, path) in order.iter().enumerate() {
+        stmt.execute(params![album_id, path, position as i64])?;
+    }
+    Ok(())
+}
@@ -0,0 +1,143 @@
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use ignore::gitignore::GitignoreBuilder;
+use ignore::{Match, WalkBuilder};
+use tokio::sync::Mutex;
+use crate::app::centralpanel_modules::is_media_file;
+
+/// Name of the gitignore-style files that keep paths out of a scan.
+pub const IGNORE_FILE_NAME: &str = ".taggerrsignore";
+
+/// Most files a running scan hands over at once.
+pub const SCAN_BATCH_SIZE: usize = 500;
+
+/// Longest a found file waits before it is handed over, so slow folders still fill in steadily.
+const SCAN_BATCH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How a library path is scanned, stored per path.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(default)]
+pub struct ScanSettings {
+    /// Whether subfolders are scanned as well as the path itself.
+    pub recursive: bool,
+    /// How many folder levels below the path a recursive scan enters; `None` for no limit.
+    pub max_depth: Option<usize>,
+    /// Whether symlinked folders are entered.
+    pub follow_symlinks: bool,
+    /// Whether files and folders starting with a dot are left out.
+    pub skip_hidden: bool,
+}
+
+impl Default for ScanSettings {
+    fn default() -> Self {
+        Self { recursive: false, max_depth: Some(5), follow_symlinks: false, skip_hidden: false }
+    }
+}
+
+impl ScanSettings {
+    /// Depth in the walker's terms, where the path itself is 0 and the files in it are 1.
+    fn walk_depth(&self) -> Option<usize> {
+        if self.recursive { self.max_depth.map(|depth| depth + 1) } else { Some(1) }
+    }
+
+    /// Whether a scan of `root` with these settings would list `path`, for files that
+    /// show up after the scan. The caller checks the file type.
+    pub fn includes(&self, root: &str, path: &str) -> bool {
+        let root = Path::new(root);
+        let Ok(relative) = Path::new(path).strip_prefix(root) else {
+            return false;
+        };
+        let components: Vec<_> = relative.components().collect();
+        if components.is_empty() || self.walk_depth().is_some_and(|depth| components.len() > depth) {
+            return false;
+        }
+        if self.skip_hidden && components.iter().any(|c| c.as_os_str().to_string_lossy().starts_with('.')) {
+            return false;
+        }
+
+        // Walk down from the root, checking the folders in between and their ignore files
+        let mut ignored = false;
+        let mut dir = root.to_path_buf();
+        for (index, component) in components.iter().enumerate() {
+            if index > 0 && !self.follow_symlinks && dir.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink()) {
+                return false;
+            }
+            let ignore_file = dir.join(IGNORE_FILE_NAME);
+            if ignore_file.is_file() {
+                let mut builder = GitignoreBuilder::new(&dir);
+                builder.add(&ignore_file);
+                if let Ok(gitignore) = builder.build() {
+                    // Deeper ignore files override shallower ones, as in git
+                    match gitignore.matched_path_or_any_parents(path, false) {
+                        Match::Ignore(_) => ignored = true,
+                        Match::Whitelist(_) => ignored = false,
+                        Match::None => {}
+                    }
+                }
+            }
+            dir.push(component);
+        }
+        !ignored
+    }
+}
+
+/// Lists the media files of `root` according to `settings`, sorted by path within each folder.
+/// Blocks on the file system, so it runs on tokio's blocking pool.
+pub fn scan_directory(root: &str, settings: &ScanSettings) -> Vec<String> {
+    let mut files = Vec::new();
+    scan_directory_in_batches(root, settings, &AtomicBool::new(false), |batch| files.extend(batch));
+    files
+}
+
+/// Like [`scan_directory`], but hands the files to `on_batch` as they are found, in batches of
+/// up to [`SCAN_BATCH_SIZE`] or whatever turned up within [`SCAN_BATCH_INTERVAL`]. Stops early
+/// once `cancel` is set.
+pub fn scan_directory_in_batches(
+    root: &str,
+    settings: &ScanSettings,
+    cancel: &AtomicBool,
+    mut on_batch: impl FnMut(Vec<String>),
+) {
+    let walker = WalkBuilder::new(root)
+        // Only our own ignore files count, not .gitignore and friends
+        .standard_filters(false)
+        .hidden(settings.skip_hidden)
+        .follow_links(settings.follow_symlinks)
+        .max_depth(settings.walk_depth())
+        .add_custom_ignore_filename(IGNORE_FILE_NAME)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+
+    let mut batch = Vec::new();
+    let mut last_batch = Instant::now();
+    for entry in walker.filter_map(Result::ok) {
+        if cancel.load(Ordering::Relaxed) {
+            return;
+        }
+        let path = entry.path().display().to_string();
+        if entry.depth() > 0 && is_media_file(&path) && entry.path().is_file() {
+            batch.push(path);
+        }
+        if !batch.is_empty() && (batch.len() >= SCAN_BATCH_SIZE || last_batch.elapsed() >= SCAN_BATCH_INTERVAL) {
+            on_batch(std::mem::take(&mut batch));
+            last_batch = Instant::now();
+        }
+    }
+    if !batch.is_empty() {
+        on_batch(batch);
+    }
+}
+
+/// Shows in `status` that the import `step` failed for some of the scanned files, if it did.
+pub async fn report_problems(status: &Arc<Mutex<Option<String>>>, step: &str, problems: &[String]) {
+    if let Some(first) = problems.first() {
+        *status.lock().await = Some(format!("{} failed for {} file(s), first: {}", step, problems.len(), first));
+    }
+}
+
+/// One problem per file in `paths`, for a step that failed before any of them could be handled.
+pub fn failed_for_each(paths: &[String], error: impl std::fmt::Display) -> Vec<String> {
+    paths.iter().map(|path| format!("{}: {}", path, error)).collect()
+}
@@ -0,0 +1,187 @@
+use std::path::Path;
+use std::sync::Arc;
+use rusqlite::{params, Connection};
+use tokio::sync::Mutex;
+use crate::app::file_identity;
+use crate::app::tag_store::{self, Cached, TagStore};
+
+/// Files with identical content, found through their content hashes.
+#[derive(Clone, Debug)]
+pub struct DuplicateGroup {
+    pub content_hash: String,
+    /// Size of a single copy in bytes.
+    pub size: i64,
+    pub paths: Vec<String>,
+}
+
+impl DuplicateGroup {
+    /// Bytes that keeping a single copy would free.
+    pub fn wasted(&self) -> i64 {
+        self.size * (self.paths.len() as i64 - 1)
+    }
+}
+
+/// State of the Duplicates view.
+#[derive(Default)]
+pub struct DuplicatesState {
+    pub open: bool,
+    pub groups: Arc<Mutex<Cached<Vec<DuplicateGroup>>>>,
+    /// The library roots the groups were found in; a change reloads them.
+    pub roots: Vec<String>,
+    /// The copy picked to keep in a group, waiting for the user to confirm.
+    pub pending_keep: Option<(String, String)>,
+    pub status: Arc<Mutex<Option<String>>>,
+}
+
+/// Groups of files under any of `roots` with the same stored hash, to be checked with
+/// [`drop_missing`] once the connection is released.
+pub fn find_duplicates(conn: &Connection, roots: &[String]) -> rusqlite::Result<Vec<DuplicateGroup>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT h.content_hash, h.size, f.path FROM file_hashes h JOIN files f ON f.id = h.file_id
+         WHERE h.content_hash IN (SELECT content_hash FROM file_hashes GROUP BY content_hash HAVING COUNT(*) > 1)
+         ORDER BY h.content_hash, f.path",
+    )?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?, row.get::<_, String>(2)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut groups: Vec<DuplicateGroup> = Vec::new();
+    for (content_hash, size, path) in rows {
+        if !roots.iter().any(|root| Path::new(&path).starts_with(root)) {
+            continue;
+        }
+        match groups.last_mut() {
+            Some(group) if group.content_hash == content_hash => group.paths.push(path),
+            _ => groups.push(DuplicateGroup { content_hash, size, paths: vec![path] }),
+        }
+    }
+    Ok(groups)
+}
+
+/// Leaves out records of files that are no longer on disk, so a group needs two real copies,
+/// and puts the groups wasting the most space first.
+pub fn drop_missing(mut groups: Vec<DuplicateGroup>) -> Vec<DuplicateGroup> {
+    for group in &mut groups {
+        group.paths.retain(|path| Path::new(path).exists());
+    }
+    groups.retain(|group| group.paths.len() > 1);
+    groups.sort_by(|a, b| b.wasted().cmp(&a.wasted()).then(a.paths.cmp(&b.paths)));
+    groups
+}
+
+/// Gives `keep` the tags, album places, attributes and notes of every trashed copy and drops
+/// the records of the copies. The highest rating wins, any favorite makes `keep` one, a copy's
+/// colour label is only taken when `keep` has none, and notes `keep` lacks are appended.
+fn merge_into_survivor(conn: &Connection, keep: &str, trashed: &[String]) -> rusqlite::Result<()> {
+    let keep_id = tag_store::ensure_file(conn, keep)?;
+    for path in trashed {
+        conn.execute(
+            "INSERT OR IGNORE INTO file_tags (file_id, tag_id)
+             SELECT ?1, ft.tag_id FROM file_tags ft JOIN files f ON f.id = ft.file_id WHERE f.path = ?2",
+            params![keep_id, path],
+        )?;
+        conn.execute(
+            "INSERT OR IGNORE INTO album_files (collection_id, file_id, position)
+             SELECT a.collection_id, ?1, a.position FROM album_files a JOIN files f ON f.id = a.file_id WHERE f.path = ?2",
+            params![keep_id, path],
+        )?;
+        conn.execute(
+            "INSERT INTO file_attributes (file_id, rating, favorite, color_label)
+             SELECT ?1, a.rating, a.favorite, a.color_label FROM file_attributes a JOIN files f ON f.id = a.file_id WHERE f.path = ?2
+             ON CONFLICT(file_id) DO UPDATE SET
+                 rating = MAX(rating, excluded.rating),
+                 favorite = favorite OR excluded.favorite,
+                 color_label = COALESCE(color_label, excluded.color_label)",
+            params![keep_id, path],
+        )?;
+        conn.execute(
+            "INSERT INTO file_notes (file_id, body, updated_at)
+             SELECT ?1, n.body, n.updated_at FROM file_notes n JOIN files f ON f.id = n.file_id WHERE f.path = ?2
+             ON CONFLICT(file_id) DO UPDATE SET
+                 body = body || char(10) || char(10) || excluded.body,
+                 updated_at = MAX(updated_at, excluded.updated_at)
+             WHERE instr(body, excluded.body) = 0",
+            params![keep_id, path],
+        )?;
+        conn.execute("DELETE FROM files WHERE path = ?1", [path])?;
+    }
+    Ok(())
+}
+
+/// Moves every copy but `keep` to the system trash, then merges their tags onto `keep`.
+/// The database change is not an undo step: the copies are restored from the trash, not with Ctrl+Z.
+///
+/// The groups come from stored hashes, so every file is hashed again first: copies that no longer
+/// match `keep` are left alone, and nothing is trashed if `keep` itself is gone.
+pub fn spawn_keep_one(
+    ctx: &egui::Context,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    status: &Arc<Mutex<Option<String>>>,
+    keep: String,
+    others: Vec<String>,
+) {
+    let store_clone = tag_store.clone();
+    let status_clone = status.clone();
+    let ctx_clone = ctx.clone();
+    runtime.spawn(async move {
+        let keep_clone = keep.clone();
+        let outcome = tokio::task::spawn_blocking(move || {
+            let keep_hash = file_identity::hash_file(&keep_clone)
+                .map_err(|e| format!("{} could not be read, so nothing was trashed: {}", keep_clone, e))?;
+            let mut trashed = Vec::new();
+            let mut failed = Vec::new();
+            let mut changed = 0;
+            for path in others {
+                if file_identity::hash_file(&path).ok().as_ref() != Some(&keep_hash) {
+                    changed += 1;
+                    continue;
+                }
+                match trash::delete(&path) {
+                    Ok(()) => trashed.push(path),
+                    Err(e) => failed.push(format!("{}: {}", path, e)),
+                }
+            }
+            Ok::<_, String>((trashed, failed, changed))
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()));
+
+        let (trashed, failed, changed) = match outcome {
+            Ok(outcome) => outcome,
+            Err(e) => {
+                *status_clone.lock().await = Some(e);
+                ctx_clone.request_repaint();
+                return;
+            }
+        };
+        let count = trashed.len();
+        let result = store_clone.write(move |tx| merge_into_survivor(tx, &keep, &trashed)).await;
+        let mut message = match (result, failed.first()) {
+            (Err(e), _) => format!("Trashed {} copies but merging their tags failed: {}", count, e),
+            (Ok(()), Some(first)) => format!("Trashed {} copies, {} could not be trashed, first: {}", count, failed.len(), first),
+            (Ok(()), None) => format!("Trashed {} copies and merged their tags", count),
+        };
+        if changed > 0 {
+            message.push_str(&format!("; kept {} that changed since the scan", changed));
+        }
+        *status_clone.lock().await = Some(message);
+        ctx_clone.request_repaint();
+    });
+}
+
+/// A byte count for display, such as `3.4 MB`.
+pub fn format_size(bytes: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
@@ -0,0 +1,549 @@
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use crate::app::directory_scan;
+use crate::app::tag_store::{self, TagStore};
+use crate::app::xmp_sidecar::{self, SidecarKeywords};
+
+/// How many files have their embedded metadata imported per transaction.
+const IMPORT_BATCH_SIZE: usize = 64;
+
+/// Used when the namespace setting is left blank.
+pub const DEFAULT_NAMESPACE: &str = "exif";
+
+const JPEG_XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+const PHOTOSHOP_HEADER: &[u8] = b"Photoshop 3.0\0";
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Most bytes of header segments and chunks read from one file.
+const MAX_HEADER_BYTES: usize = 16 * 1024 * 1024;
+
+/// Keywords and EXIF fields found inside an image file.
+#[derive(Debug, Default)]
+pub struct EmbeddedMetadata {
+    /// IPTC keywords and XMP subjects, merged.
+    pub keywords: SidecarKeywords,
+    pub camera_model: Option<String>,
+    /// Capture date as `(year, month, day)`.
+    pub date: Option<(u16, u8, u8)>,
+}
+
+impl EmbeddedMetadata {
+    /// The tags this metadata maps to. Keywords become regular tags, the same as sidecar
+    /// keywords; camera and date go under `namespace` as `exif:camera > exif:Canon_EOS_R5`
+    /// and `exif:date > exif:2024 > exif:2024-05 > exif:2024-05-17`, so `exif:2024` finds
+    /// a whole year and `exif:camera` every file with a known camera.
+    pub fn tag_paths(&self, namespace: &str) -> Vec<Vec<String>> {
+        let mut paths = self.keywords.tag_paths();
+        let namespaced = |names: Vec<String>| {
+            names
+                .iter()
+                .map(|name| tag_store::normalize_tag_name(&format!("{}:{}", namespace, name)))
+                .collect::<Option<Vec<_>>>()
+        };
+        if let Some(model) = &self.camera_model
+            && let Some(path) = namespaced(vec!["camera".to_string(), model.clone()])
+        {
+            paths.push(path);
+        }
+        if let Some((year, month, day)) = self.date
+            && let Some(path) = namespaced(vec![
+                "date".to_string(),
+                format!("{:04}", year),
+                format!("{:04}-{:02}", year, month),
+                format!("{:04}-{:02}-{:02}", year, month, day),
+            ])
+        {
+            paths.push(path);
+        }
+        paths
+    }
+}
+
+/// Whether `path` is a format embedded metadata is read from.
+pub fn has_embedded_metadata(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .map(|ext| matches!(ext.to_string_lossy().to_lowercase().as_str(), "jpg" | "jpeg" | "png"))
+        .unwrap_or(false)
+}
+
+/// Reads the IPTC keywords, XMP subjects, camera model and capture date of a JPEG or PNG.
+pub fn read_embedded(path: &str) -> io::Result<EmbeddedMetadata> {
+    let mut file = io::BufReader::new(std::fs::File::open(path)?);
+    Ok(parse_embedded(&read_headers(&mut file)?))
+}
+
+/// The parts of a JPEG or PNG that can hold metadata, laid out as a file of their own: the
+/// JPEG segments before the image data, or every PNG chunk but the image data. The pixels
+/// are skipped, so large images cost no more to read than small ones.
+fn read_headers(reader: &mut (impl Read + Seek)) -> io::Result<Vec<u8>> {
+    let mut headers = vec![0; PNG_SIGNATURE.len()];
+    reader.read_exact(&mut headers)?;
+
+    if headers.starts_with(PNG_SIGNATURE) {
+        let mut header = [0; 8];
+        while reader.read_exact(&mut header).is_ok() {
+            let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+            let chunk_type = &header[4..];
+            // Length, type, data and CRC
+            if chunk_type == b"IDAT" || headers.len() + 12 + length > MAX_HEADER_BYTES {
+                reader.seek(SeekFrom::Current(length as i64 + 4))?;
+                continue;
+            }
+            let start = headers.len();
+            headers.extend_from_slice(&header);
+            headers.resize(start + 12 + length, 0);
+            if reader.read_exact(&mut headers[start + 8..]).is_err() {
+                headers.truncate(start);
+                break;
+            }
+            if chunk_type == b"IEND" {
+                break;
+            }
+        }
+    } else if headers.starts_with(&[0xFF, 0xD8]) {
+        headers.truncate(2);
+        reader.seek(SeekFrom::Start(2))?;
+        let mut header = [0; 4];
+        while reader.read_exact(&mut header).is_ok() && header[0] == 0xFF {
+            // Start of scan: only image data follows
+            if header[1] == 0xDA {
+                break;
+            }
+            let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+            if length < 2 || headers.len() + 2 + length > MAX_HEADER_BYTES {
+                break;
+            }
+            let start = headers.len();
+            headers.extend_from_slice(&header);
+            headers.resize(start + 2 + length, 0);
+            if reader.read_exact(&mut headers[start + 4..]).is_err() {
+                headers.truncate(start);
+                break;
+            }
+        }
+        // End of image, so readers of the result stop where the headers do
+        headers.extend_from_slice(&[0xFF, 0xD9]);
+    }
+    Ok(headers)
+}
+
+/// The metadata in the headers of a JPEG or PNG, as [`read_headers`] returns them.
+fn parse_embedded(bytes: &[u8]) -> EmbeddedMetadata {
+    let mut metadata = EmbeddedMetadata::default();
+
+    let (iptc_keywords, xmp) = if bytes.starts_with(PNG_SIGNATURE) {
+        (Vec::new(), png_xmp(bytes))
+    } else {
+        jpeg_keywords(bytes)
+    };
+    if let Some(xmp) = xmp.and_then(|xml| xmp_sidecar::read_keywords(&xml).ok()) {
+        metadata.keywords = xmp;
+    }
+    for keyword in iptc_keywords {
+        if !metadata.keywords.subjects.contains(&keyword) {
+            metadata.keywords.subjects.push(keyword);
+        }
+    }
+
+    if let Ok(exif) = exif::Reader::new().read_from_container(&mut io::Cursor::new(bytes)) {
+        metadata.camera_model = exif
+            .get_field(exif::Tag::Model, exif::In::PRIMARY)
+            .and_then(|field| first_ascii(&field.value))
+            .map(|model| String::from_utf8_lossy(model).trim_matches(|c: char| c == '\0' || c.is_whitespace()).to_string())
+            .filter(|model| !model.is_empty());
+        metadata.date = [exif::Tag::DateTimeOriginal, exif::Tag::DateTime]
+            .into_iter()
+            .filter_map(|tag| exif.get_field(tag, exif::In::PRIMARY))
+            .filter_map(|field| first_ascii(&field.value))
+            .filter_map(|value| exif::DateTime::from_ascii(value).ok())
+            .map(|date| (date.year, date.month, date.day))
+            .find(|&(year, month, day)| year > 0 && (1..=12).contains(&month) && (1..=31).contains(&day));
+    }
+    metadata
+}
+
+fn first_ascii(value: &exif::Value) -> Option<&[u8]> {
+    match value {
+        exif::Value::Ascii(values) => values.first().map(Vec::as_slice),
+        _ => None,
+    }
+}
+
+/// Walks the JPEG header segments up to the image data, returning the IPTC keywords
+/// from the Photoshop (APP13) segment and the XMP packet from the APP1 segment.
+fn jpeg_keywords(bytes: &[u8]) -> (Vec<String>, Option<String>) {
+    let mut keywords = Vec::new();
+    let mut xmp = None;
+    if !bytes.starts_with(&[0xFF, 0xD8]) {
+        return (keywords, xmp);
+    }
+
+    let mut offset = 2;
+    while offset + 4 <= bytes.len() && bytes[offset] == 0xFF {
+        let marker = bytes[offset + 1];
+        // Start of scan: only image data follows
+        if marker == 0xDA {
+            break;
+        }
+        let length = u16::from_be_bytes([bytes[offset + 2], bytes[offset + 3]]) as usize;
+        let Some(segment) = bytes.get(offset + 4..offset + 2 + length) else {
+            break;
+        };
+        match marker {
+            0xE1 if segment.starts_with(JPEG_XMP_HEADER) => {
+                xmp = Some(String::from_utf8_lossy(&segment[JPEG_XMP_HEADER.len()..]).into_owned());
+            }
+            0xED if segment.starts_with(PHOTOSHOP_HEADER) => {
+                keywords.extend(photoshop_iptc_keywords(&segment[PHOTOSHOP_HEADER.len()..]));
+            }
+            _ => {}
+        }
+        offset += 2 + length;
+    }
+    (keywords, xmp)
+}
+
+/// Finds the IPTC-NAA resource (id 0x0404) among Photoshop image resource blocks.
+fn photoshop_iptc_keywords(mut data: &[u8]) -> Vec<String> {
+    let mut keywords = Vec::new();
+    while data.len() >= 12 && data.starts_with(b"8BIM") {
+        let id = u16::from_be_bytes([data[4], data[5]]);
+        // Pascal string name, padded to an even length including its length byte
+        let name_length = data[6] as usize;
+        let name_end = 6 + (name_length + 2) / 2 * 2;
+        let Some(size) = data.get(name_end..name_end + 4) else {
+            break;
+        };
+        let size = u32::from_be_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        let Some(block) = data.get(name_end + 4..name_end + 4 + size) else {
+            break;
+        };
+        if id == 0x0404 {
+            keywords.extend(iptc_keywords(block));
+        }
+        let next = name_end + 4 + size + size % 2;
+        data = data.get(next..).unwrap_or_default();
+    }
+    keywords
+}
+
+/// Reads the keyword datasets (record 2, dataset 25) of an IPTC-IIM block.
+fn iptc_keywords(mut data: &[u8]) -> Vec<String> {
+    let mut keywords = Vec::new();
+    while data.len() >= 5 && data[0] == 0x1C {
+        let (record, dataset) = (data[1], data[2]);
+        let size = u16::from_be_bytes([data[3], data[4]]) as usize;
+        // Extended-length datasets are never keywords
+        if size & 0x8000 != 0 {
+            break;
+        }
+        let Some(value) = data.get(5..5 + size) else {
+            break;
+        };
+        if record == 2 && dataset == 25 {
+            // Most writers use UTF-8; older ones wrote Latin-1
+            let keyword = match std::str::from_utf8(value) {
+                Ok(text) => text.to_string(),
+                Err(_) => value.iter().map(|&b| b as char).collect(),
+            };
+            let keyword = keyword.trim();
+            if !keyword.is_empty() {
+                keywords.push(keyword.to_string());
+            }
+        }
+        data = &data[5 + size..];
+    }
+    keywords
+}
+
+/// The XMP packet of a PNG, stored in an uncompressed `iTXt` chunk.
+fn png_xmp(bytes: &[u8]) -> Option<String> {
+    let mut offset = PNG_SIGNATURE.len();
+    while offset + 8 <= bytes.len() {
+        let length = u32::from_be_bytes(bytes[offset..offset + 4].try_into().ok()?) as usize;
+        let chunk_type = &bytes[offset + 4..offset + 8];
+        let data = bytes.get(offset + 8..offset + 8 + length)?;
+        if chunk_type == b"iTXt" && data.starts_with(b"XML:com.adobe.xmp\0") {
+            let rest = &data[b"XML:com.adobe.xmp\0".len()..];
+            // Compression flag and method, then language tag and translated keyword
+            let (&compressed, rest) = rest.split_first()?;
+            if compressed != 0 {
+                return None;
+            }
+            let rest = rest.get(1..)?;
+            let language_end = rest.iter().position(|&b| b == 0)?;
+            let rest = &rest[language_end + 1..];
+            let translated_end = rest.iter().position(|&b| b == 0)?;
+            return Some(String::from_utf8_lossy(&rest[translated_end + 1..]).into_owned());
+        }
+        if chunk_type == b"IEND" {
+            break;
+        }
+        // Length, type, data and CRC
+        offset += 12 + length;
+    }
+    None
+}
+
+/// Tags files with their embedded keywords and EXIF fields the first time they are indexed.
+/// Each file is only read until it parses once, so tags removed later are not brought back
+/// by a rescan. Returns a message for each file whose tags could not be stored.
+pub async fn import_embedded(tag_store: TagStore, paths: Vec<String>, namespace: String) -> Vec<String> {
+    let paths: Vec<String> = paths.into_iter().filter(|path| has_embedded_metadata(path)).collect();
+    let paths_clone = paths.clone();
+    let done = match tag_store.read(move |conn| tag_store::embedded_metadata_read(conn, &paths_clone)).await {
+        Ok(done) => done,
+        Err(e) => return directory_scan::failed_for_each(&paths, e),
+    };
+    let namespace = match namespace.trim() {
+        "" => DEFAULT_NAMESPACE.to_string(),
+        namespace => namespace.to_string(),
+    };
+
+    let mut problems = Vec::new();
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    for path in paths {
+        if done.contains(&path) {
+            continue;
+        }
+        let path_clone = path.clone();
+        let tags = match tokio::task::spawn_blocking(move || read_embedded(&path_clone)).await {
+            Ok(Ok(metadata)) => metadata.tag_paths(&namespace),
+            // Left unmarked, so a file that was still being written is read again once it changes
+            _ => continue,
+        };
+        batch.push((path, tags));
+
+        if batch.len() >= IMPORT_BATCH_SIZE {
+            problems.extend(write_imports(&tag_store, std::mem::take(&mut batch)).await);
+        }
+    }
+    if !batch.is_empty() {
+        problems.extend(write_imports(&tag_store, batch).await);
+    }
+    problems
+}
+
+/// Stores a batch in one transaction, each file in its own savepoint so one file's tags
+/// failing does not lose the others'.
+async fn write_imports(tag_store: &TagStore, batch: Vec<(String, Vec<Vec<String>>)>) -> Vec<String> {
+    let paths: Vec<String> = batch.iter().map(|(path, _)| path.clone()).collect();
+    let result = tag_store
+        .write(move |tx| {
+            let mut problems = Vec::new();
+            for (path, tags) in &batch {
+                let paths = [path.clone()];
+                if !tags.is_empty()
+                    && let Err(e) = tag_store::in_savepoint(tx, || tag_store::add_tags_to_files(tx, &paths, tags))
+                {
+                    problems.push(format!("{}: {}", path, e));
+                }
+                tag_store::record_embedded_metadata_read(tx, path)?;
+            }
+            Ok(problems)
+        })
+        .await;
+    result.unwrap_or_else(|e| directory_scan::failed_for_each(&paths, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XMP: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+<rdf:Description xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:subject><rdf:Bag>
+<rdf:li>beach</rdf:li><rdf:li>sunset</rdf:li>
+</rdf:Bag></dc:subject></rdf:Description></rdf:RDF></x:xmpmeta>"#;
+
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0xFF, marker];
+        bytes.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        bytes.extend_from_slice(payload);
+        bytes
+    }
+
+    /// A JPEG with the given header segments, followed by a scan that must never be read.
+    fn jpeg(segments: &[Vec<u8>]) -> Vec<u8> {
+        let mut bytes = vec![0xFF, 0xD8];
+        for segment in segments {
+            bytes.extend_from_slice(segment);
+        }
+        bytes.extend(segment(0xDA, &[1, 1, 0, 0, 63, 0]));
+        bytes.extend(std::iter::repeat_n(0xAB, 4096));
+        bytes.extend_from_slice(&[0xFF, 0xD9]);
+        bytes
+    }
+
+    fn xmp_segment(xml: &str) -> Vec<u8> {
+        segment(0xE1, &[JPEG_XMP_HEADER, xml.as_bytes()].concat())
+    }
+
+    fn iptc_keyword(keyword: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![0x1C, 2, 25];
+        bytes.extend_from_slice(&(keyword.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(keyword);
+        bytes
+    }
+
+    fn photoshop_block(id: u16, name: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut bytes = b"8BIM".to_vec();
+        bytes.extend_from_slice(&id.to_be_bytes());
+        bytes.push(name.len() as u8);
+        bytes.extend_from_slice(name);
+        if name.len().is_multiple_of(2) {
+            bytes.push(0);
+        }
+        bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes
+    }
+
+    fn iptc_segment(blocks: &[Vec<u8>]) -> Vec<u8> {
+        segment(0xED, &[PHOTOSHOP_HEADER, &blocks.concat()].concat())
+    }
+
+    fn png_chunk(chunk_type: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut bytes = (data.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(chunk_type);
+        bytes.extend_from_slice(data);
+        // The parsers do not check the CRC
+        bytes.extend_from_slice(&[0; 4]);
+        bytes
+    }
+
+    fn xmp_itxt(compressed: u8, xml: &str) -> Vec<u8> {
+        let data = [b"XML:com.adobe.xmp\0".as_slice(), &[compressed, 0], b"\0\0", xml.as_bytes()].concat();
+        png_chunk(b"iTXt", &data)
+    }
+
+    fn png(chunks: &[Vec<u8>]) -> Vec<u8> {
+        [PNG_SIGNATURE.to_vec(), chunks.concat(), png_chunk(b"IEND", &[])].concat()
+    }
+
+    fn headers(bytes: &[u8]) -> Vec<u8> {
+        read_headers(&mut io::Cursor::new(bytes)).unwrap()
+    }
+
+    #[test]
+    fn jpeg_xmp_and_iptc_keywords_are_merged() {
+        let bytes = jpeg(&[
+            xmp_segment(XMP),
+            iptc_segment(&[photoshop_block(0x0404, b"", &[iptc_keyword(b"sunset"), iptc_keyword(b"holiday")].concat())]),
+        ]);
+        let metadata = parse_embedded(&headers(&bytes));
+        assert_eq!(metadata.keywords.subjects, ["beach", "sunset", "holiday"]);
+    }
+
+    #[test]
+    fn photoshop_blocks_are_padded_to_even_lengths() {
+        let blocks = [
+            // Odd data length, followed by a pad byte
+            photoshop_block(0x0425, b"", &[1, 2, 3]),
+            // Odd name length, so no pad byte after the name
+            photoshop_block(0x0404, b"x", &iptc_keyword(b"padded")),
+        ];
+        assert_eq!(photoshop_iptc_keywords(&blocks.concat()), ["padded"]);
+    }
+
+    #[test]
+    fn iptc_keywords_decode_latin1_and_skip_other_datasets() {
+        let data = [
+            vec![0x1C, 2, 5, 0, 5],
+            b"title".to_vec(),
+            iptc_keyword(b"caf\xE9"),
+            iptc_keyword(b"  "),
+            iptc_keyword("smörgås".as_bytes()),
+        ]
+        .concat();
+        assert_eq!(iptc_keywords(&data), ["café", "smörgås"]);
+    }
+
+    #[test]
+    fn truncated_iptc_keeps_what_came_before() {
+        let mut data = [iptc_keyword(b"whole"), iptc_keyword(b"cut off")].concat();
+        data.truncate(data.len() - 3);
+        assert_eq!(iptc_keywords(&data), ["whole"]);
+
+        let mut block = photoshop_block(0x0404, b"", &iptc_keyword(b"lost"));
+        block.truncate(block.len() - 2);
+        assert!(photoshop_iptc_keywords(&block).is_empty());
+    }
+
+    #[test]
+    fn truncated_jpeg_segments_are_ignored() {
+        let whole = [vec![0xFF, 0xD8], iptc_segment(&[photoshop_block(0x0404, b"", &iptc_keyword(b"kept"))])].concat();
+        let mut bytes = [whole.clone(), xmp_segment(XMP)].concat();
+        bytes.truncate(bytes.len() - 10);
+        let (keywords, xmp) = jpeg_keywords(&bytes);
+        assert_eq!(keywords, ["kept"]);
+        assert_eq!(xmp, None);
+        assert_eq!(headers(&bytes), [whole.as_slice(), &[0xFF, 0xD9]].concat());
+    }
+
+    #[test]
+    fn jpeg_headers_stop_at_the_scan() {
+        let bytes = jpeg(&[xmp_segment(XMP)]);
+        let headers = headers(&bytes);
+        assert_eq!(headers.len(), 2 + xmp_segment(XMP).len() + 2);
+        assert!(!headers.contains(&0xAB));
+    }
+
+    #[test]
+    fn exif_camera_and_date_survive_the_header_read() {
+        let model = exif::Field {
+            tag: exif::Tag::Model,
+            ifd_num: exif::In::PRIMARY,
+            value: exif::Value::Ascii(vec![b"Canon EOS R5".to_vec()]),
+        };
+        let date = exif::Field {
+            tag: exif::Tag::DateTimeOriginal,
+            ifd_num: exif::In::PRIMARY,
+            value: exif::Value::Ascii(vec![b"2024:05:17 10:30:00".to_vec()]),
+        };
+        let mut writer = exif::experimental::Writer::new();
+        writer.push_field(&model);
+        writer.push_field(&date);
+        let mut tiff = io::Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+
+        let bytes = jpeg(&[segment(0xE1, &[b"Exif\0\0".as_slice(), tiff.get_ref()].concat())]);
+        let metadata = parse_embedded(&headers(&bytes));
+        assert_eq!(metadata.camera_model.as_deref(), Some("Canon EOS R5"));
+        assert_eq!(metadata.date, Some((2024, 5, 17)));
+        assert_eq!(
+            metadata.tag_paths("exif"),
+            [
+                vec!["exif:camera".to_string(), "exif:Canon_EOS_R5".to_string()],
+                vec!["exif:date".to_string(), "exif:2024".to_string(), "exif:2024-05".to_string(), "exif:2024-05-17".to_string()],
+            ]
+        );
+    }
+
+    #[test]
+    fn png_xmp_from_itxt() {
+        let bytes = png(&[png_chunk(b"IHDR", &[0; 13]), xmp_itxt(0, XMP)]);
+        assert_eq!(png_xmp(&bytes).as_deref(), Some(XMP));
+        assert_eq!(parse_embedded(&headers(&bytes)).keywords.subjects, ["beach", "sunset"]);
+    }
+
+    #[test]
+    fn png_compressed_or_truncated_itxt_is_skipped() {
+        assert_eq!(png_xmp(&png(&[xmp_itxt(1, XMP)])), None);
+        let mut bytes = png(&[xmp_itxt(0, XMP)]);
+        bytes.truncate(PNG_SIGNATURE.len() + 40);
+        assert_eq!(png_xmp(&bytes), None);
+        assert!(parse_embedded(&headers(&bytes)).keywords.subjects.is_empty());
+    }
+
+    #[test]
+    fn png_headers_skip_image_data_but_keep_later_chunks() {
+        let bytes = png(&[png_chunk(b"IHDR", &[0; 13]), png_chunk(b"IDAT", &[0xAB; 4096]), xmp_itxt(0, XMP)]);
+        let headers = headers(&bytes);
+        assert!(!headers.contains(&0xAB));
+        assert_eq!(png_xmp(&headers).as_deref(), Some(XMP));
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::app::tag_store;
+
+/// Highest star rating a file can have.
+pub const MAX_RATING: u8 = 5;
+
+/// Lightroom's colour labels. Keys 6 to 9 set the first four, as they do there.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorLabel {
+    Red,
+    Yellow,
+    Green,
+    Blue,
+    Purple,
+}
+
+impl ColorLabel {
+    pub const ALL: [ColorLabel; 5] =
+        [ColorLabel::Red, ColorLabel::Yellow, ColorLabel::Green, ColorLabel::Blue, ColorLabel::Purple];
+
+    /// The name stored in the database and used in searches such as `color=red`.
+    pub fn name(self) -> &'static str {
+        match self {
+            ColorLabel::Red => "red",
+            ColorLabel::Yellow => "yellow",
+            ColorLabel::Green => "green",
+            ColorLabel::Blue => "blue",
+            ColorLabel::Purple => "purple",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|label| label.name().eq_ignore_ascii_case(name))
+    }
+
+    pub fn color(self) -> egui::Color32 {
+        match self {
+            ColorLabel::Red => egui::Color32::from_rgb(0xE0, 0x4B, 0x4B),
+            ColorLabel::Yellow => egui::Color32::from_rgb(0xE8, 0xC5, 0x3A),
+            ColorLabel::Green => egui::Color32::from_rgb(0x5C, 0xB8, 0x5C),
+            ColorLabel::Blue => egui::Color32::from_rgb(0x4A, 0x90, 0xD9),
+            ColorLabel::Purple => egui::Color32::from_rgb(0x9B, 0x6B, 0xD0),
+        }
+    }
+}
+
+/// Rating, favorite flag and colour label of one file.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FileAttributes {
+    pub rating: u8,
+    pub favorite: bool,
+    pub color_label: Option<ColorLabel>,
+}
+
+impl FileAttributes {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// Attributes of the given files; files without any are left out.
+pub fn attributes_for_paths(conn: &Connection, paths: &[String]) -> rusqlite::Result<HashMap<String, FileAttributes>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT a.rating, a.favorite, a.color_label FROM file_attributes a
+         JOIN files f ON f.id = a.file_id
+         WHERE f.path = ?1",
+    )?;
+    let mut result = HashMap::new();
+    for path in paths {
+        let attributes = stmt
+            .query_row([path], |row| {
+                Ok(FileAttributes {
+                    rating: row.get::<_, i64>(0)?.clamp(0, MAX_RATING as i64) as u8,
+                    favorite: row.get(1)?,
+                    color_label: row.get::<_, Option<String>>(2)?.as_deref().and_then(ColorLabel::from_name),
+                })
+            })
+            .optional()?;
+        if let Some(attributes) = attributes.filter(|a| !a.is_empty()) {
+            result.insert(path.clone(), attributes);
+        }
+    }
+    Ok(result)
+}
+
+/// Sets `column` to `value` for every file in `paths`, creating their rows as needed.
+fn set_attribute(conn: &Connection, paths: &[String], column: &str, value: rusqlite::types::Value) -> rusqlite::Result<()> {
+    tag_store::register_files(conn, paths)?;
+    let mut stmt = conn.prepare_cached(&format!(
+        "INSERT INTO file_attributes (file_id, {0}) SELECT id, ?2 FROM files WHERE path = ?1
+         ON CONFLICT(file_id) DO UPDATE SET {0} = excluded.{0}",
+        column
+    ))?;
+    for path in paths {
+        stmt.execute(params![path, value])?;
+    }
+    Ok(())
+}
+
+pub fn set_rating(conn: &Connection, paths: &[String], rating: u8) -> rusqlite::Result<()> {
+    set_attribute(conn, paths, "rating", (rating.min(MAX_RATING) as i64).into())
+}
+
+pub fn set_favorite(conn: &Connection, paths: &[String], favorite: bool) -> rusqlite::Result<()> {
+    set_attribute(conn, paths, "favorite", (favorite as i64).into())
+}
+
+pub fn set_color_label(conn: &Connection, paths: &[String], label: Option<ColorLabel>) -> rusqlite::Result<()> {
+    let value = label.map_or(rusqlite::types::Value::Null, |label| label.name().to_string().into());
+    set_attribute(conn, paths, "color_label", value)
+}
@@ -0,0 +1,112 @@
+use std::collections::HashSet;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+use crate::app::directory_scan;
+use crate::app::tag_store::{self, TagStore};
+
+/// How many hashes are written to the store per transaction.
+const HASH_BATCH_SIZE: usize = 64;
+
+/// Size and modification time (unix seconds) of a file, used to skip re-hashing unchanged files.
+pub async fn file_stamp(path: &str) -> io::Result<(i64, i64)> {
+    let metadata = tokio::fs::metadata(path).await?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0);
+    Ok((metadata.len() as i64, mtime))
+}
+
+/// blake3 of the file contents as a hex string, streamed so large videos are not loaded at once.
+pub fn hash_file(path: &str) -> io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Hashes `paths` in the background and stores the results in the tag database.
+///
+/// Files whose size and mtime match the stored hash are skipped, so rescans stay fast.
+/// A new path whose hash matches a file that went missing from one of the library `roots`
+/// inherits that file's record. Returns a description of every file that could not be stored.
+pub async fn hash_files(tag_store: TagStore, paths: Vec<String>, roots: Vec<String>) -> Vec<String> {
+    let paths_clone = paths.clone();
+    let stamps = match tag_store.read(move |conn| tag_store::file_hash_stamps(conn, &paths_clone)).await {
+        Ok(stamps) => stamps,
+        Err(e) => return directory_scan::failed_for_each(&paths, e),
+    };
+
+    let mut problems = Vec::new();
+    let mut batch = Vec::with_capacity(HASH_BATCH_SIZE);
+    for path in paths {
+        let Ok(stamp) = file_stamp(&path).await else {
+            continue;
+        };
+        // Empty files all share one hash, so they could be neither told apart nor re-linked
+        if stamp.0 == 0 || stamps.get(&path) == Some(&stamp) {
+            continue;
+        }
+
+        let path_clone = path.clone();
+        let Ok(Ok(content_hash)) = tokio::task::spawn_blocking(move || hash_file(&path_clone)).await else {
+            continue;
+        };
+        batch.push((path, stamp, content_hash));
+
+        if batch.len() >= HASH_BATCH_SIZE {
+            problems.extend(write_hashes(&tag_store, std::mem::take(&mut batch), &roots).await);
+        }
+    }
+    if !batch.is_empty() {
+        problems.extend(write_hashes(&tag_store, batch, &roots).await);
+    }
+    problems
+}
+
+/// Stores a batch in one transaction, each file in its own savepoint so one file's hash
+/// failing does not lose the others'. Returns one problem per file that was not stored.
+async fn write_hashes(tag_store: &TagStore, batch: Vec<(String, (i64, i64), String)>, roots: &[String]) -> Vec<String> {
+    let paths: Vec<String> = batch.iter().map(|(path, _, _)| path.clone()).collect();
+    // Which records to re-link is settled before the write, so no file system check holds the connection
+    let hashes: Vec<String> = batch.iter().map(|(_, _, content_hash)| content_hash.clone()).collect();
+    let candidates = match tag_store.read(move |conn| tag_store::paths_with_hashes(conn, &hashes)).await {
+        Ok(candidates) => candidates,
+        Err(e) => return directory_scan::failed_for_each(&paths, e),
+    };
+    let roots = roots.to_vec();
+    let gone = match tokio::task::spawn_blocking(move || gone_from_reachable_roots(candidates, &roots)).await {
+        Ok(gone) => gone,
+        Err(e) => return directory_scan::failed_for_each(&paths, e),
+    };
+    let result = tag_store
+        .write(move |tx| {
+            let mut problems = Vec::new();
+            for (path, (size, mtime), content_hash) in &batch {
+                if let Err(e) =
+                    tag_store::in_savepoint(tx, || tag_store::record_file_hash(tx, path, *size, *mtime, content_hash, &gone))
+                {
+                    problems.push(format!("{}: {}", path, e));
+                }
+            }
+            Ok(problems)
+        })
+        .await;
+    result.unwrap_or_else(|e| directory_scan::failed_for_each(&paths, e))
+}
+
+/// The `candidates` missing from a library root that is itself still there. A file on an
+/// unplugged drive is only out of reach, and an unmounted drive often leaves its empty
+/// mount point behind, so roots without any entries do not count.
+fn gone_from_reachable_roots(candidates: Vec<String>, roots: &[String]) -> HashSet<String> {
+    let reachable: Vec<&String> = roots
+        .iter()
+        .filter(|root| std::fs::read_dir(root).is_ok_and(|mut entries| entries.next().is_some()))
+        .collect();
+    candidates
+        .into_iter()
+        .filter(|path| reachable.iter().any(|root| Path::new(path).starts_with(root)) && !Path::new(path).exists())
+        .collect()
+}
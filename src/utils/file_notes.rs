@@ -0,0 +1,49 @@
+use std::sync::Arc;
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::sync::Mutex;
+use crate::app::tag_store;
+
+/// The notes of `path`, or an empty string if it has none.
+pub fn note_for_path(conn: &Connection, path: &str) -> rusqlite::Result<String> {
+    let body = conn
+        .query_row(
+            "SELECT n.body FROM file_notes n JOIN files f ON f.id = n.file_id WHERE f.path = ?1",
+            [path],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(body.unwrap_or_default())
+}
+
+/// Replaces the notes of `path`; blank notes remove them.
+pub fn set_note(conn: &Connection, path: &str, body: &str) -> rusqlite::Result<()> {
+    if body.trim().is_empty() {
+        conn.execute("DELETE FROM file_notes WHERE file_id = (SELECT id FROM files WHERE path = ?1)", [path])?;
+        return Ok(());
+    }
+    let file_id = tag_store::ensure_file(conn, path)?;
+    conn.execute(
+        "INSERT INTO file_notes (file_id, body, updated_at) VALUES (?1, ?2, ?3)
+         ON CONFLICT(file_id) DO UPDATE SET body = excluded.body, updated_at = excluded.updated_at
+         WHERE body != excluded.body",
+        params![file_id, body, tag_store::now_unix()],
+    )?;
+    Ok(())
+}
+
+/// An FTS5 query matching `phrase` as consecutive words, with any quotes in it taken literally.
+pub fn phrase_query(phrase: &str) -> String {
+    format!("\"{}\"", phrase.replace('"', "\"\""))
+}
+
+/// The notes being edited for the single selected file.
+#[derive(Default)]
+pub struct NotesEditor {
+    /// The file the draft belongs to.
+    pub path: Option<String>,
+    pub draft: String,
+    /// The stored notes once loaded, as `(path, body)`; `None` while loading.
+    pub saved: Arc<Mutex<Option<(String, String)>>>,
+    /// Whether the draft was filled from `saved` yet.
+    pub loaded: bool,
+}
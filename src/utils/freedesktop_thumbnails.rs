@@ -0,0 +1,115 @@
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use image::DynamicImage;
+use crate::app::thumbnails;
+
+/// The standard's size folders, smallest first, with the longest side of their thumbnails.
+const SIZES: [(&str, u32); 4] = [("normal", 128), ("large", 256), ("x-large", 512), ("xx-large", 1024)];
+
+/// The thumbnail cache Linux file managers share, as laid out by the freedesktop.org Thumbnail
+/// Managing Standard: `<size>/<md5 of URI>.png`, with the source's URI and modification time in
+/// PNG text chunks. `None` on platforms without one.
+fn cache_root() -> Option<PathBuf> {
+    if !cfg!(all(unix, not(target_os = "macos"))) {
+        return None;
+    }
+    let cache_home = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))?;
+    Some(cache_home.join("thumbnails"))
+}
+
+/// The smallest standard size holding thumbnails of at least `size` pixels.
+pub fn standard_size(size: u32) -> Option<(&'static str, u32)> {
+    SIZES.into_iter().find(|(_, pixels)| *pixels >= size)
+}
+
+/// The `file://` URI of `path` the way GLib writes it, since the file name is the MD5 of
+/// this exact string.
+#[cfg(unix)]
+fn file_uri(path: &Path) -> Option<String> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = std::path::absolute(path).ok()?;
+    let mut uri = String::from("file://");
+    for &byte in path.as_os_str().as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"!$&'()*+,-./:=@_~".contains(&byte) {
+            uri.push(byte as char);
+        } else {
+            uri.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    Some(uri)
+}
+
+#[cfg(not(unix))]
+fn file_uri(_path: &Path) -> Option<String> {
+    None
+}
+
+/// Modification time in whole seconds, as `Thumb::MTime` stores it.
+fn mtime_seconds(metadata: &Metadata) -> Option<u64> {
+    metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok().map(|since| since.as_secs())
+}
+
+/// A still valid thumbnail of `path` with at least `size` pixels, from any of the size folders
+/// that are large enough. Thumbnails whose `Thumb::MTime` or `Thumb::Size` no longer match the
+/// file are stale and skipped.
+pub fn read(path: &str, metadata: &Metadata, size: u32) -> Option<Vec<u8>> {
+    let root = cache_root()?;
+    let name = format!("{:x}.png", md5::compute(file_uri(Path::new(path))?));
+    let mtime = mtime_seconds(metadata)?;
+
+    SIZES.iter().filter(|(_, pixels)| *pixels >= size).find_map(|(folder, _)| {
+        let bytes = std::fs::read(root.join(folder).join(&name)).ok()?;
+        let text = text_chunks(&bytes)?;
+        let field = |key: &str| text.iter().find(|(keyword, _)| keyword == key).map(|(_, value)| value.trim());
+        let fresh = field("Thumb::MTime").and_then(|value| value.parse::<u64>().ok()) == Some(mtime)
+            && field("Thumb::Size").is_none_or(|value| value.parse::<u64>().ok() == Some(metadata.len()));
+        fresh.then_some(bytes)
+    })
+}
+
+/// The text chunks before the image data of a PNG, as `(keyword, text)`.
+fn text_chunks(bytes: &[u8]) -> Option<Vec<(String, String)>> {
+    let reader = png::Decoder::new(bytes).read_info().ok()?;
+    let info = reader.info();
+    let latin1 = info.uncompressed_latin1_text.iter().map(|chunk| (chunk.keyword.clone(), chunk.text.clone()));
+    let utf8 = info.utf8_text.iter().filter_map(|chunk| Some((chunk.keyword.clone(), chunk.get_text().ok()?)));
+    Some(latin1.chain(utf8).collect())
+}
+
+/// Stores `image`, already scaled to the standard size `folder` holds, for `path`.
+pub fn write(path: &str, metadata: &Metadata, folder: &str, image: &DynamicImage) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let root = cache_root().ok_or("no thumbnail cache on this platform")?;
+    // Thumbnails of thumbnails are of no use to anyone
+    if Path::new(path).starts_with(&root) {
+        return Ok(());
+    }
+    let uri = file_uri(Path::new(path)).ok_or("no URI for this path")?;
+    let mtime = mtime_seconds(metadata).ok_or("no modification time")?;
+
+    let image = image.to_rgba8();
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, image.width(), image.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.add_text_chunk("Thumb::URI".to_string(), uri.clone())?;
+    encoder.add_text_chunk("Thumb::MTime".to_string(), mtime.to_string())?;
+    encoder.add_text_chunk("Thumb::Size".to_string(), metadata.len().to_string())?;
+    encoder.add_text_chunk("Software".to_string(), "taggerrs".to_string())?;
+    encoder.write_header()?.write_image_data(&image)?;
+
+    let dir = root.join(folder);
+    let name = format!("{:x}.png", md5::compute(&uri));
+    // The standard keeps thumbnails private to their user, as they reveal what the files show
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        std::fs::DirBuilder::new().recursive(true).mode(0o700).create(&dir)?;
+    }
+    thumbnails::write_atomically(&dir, &name, &bytes, Some(0o600))?;
+    Ok(())
+}
@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use notify::event::{EventKind, ModifyKind, RenameMode};
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::Instant;
+use crate::app::{DirectoryScanState, ImageData};
+use crate::app::centralpanel_modules::is_media_file;
+use crate::app::file_identity;
+use crate::app::perceptual_hash;
+use crate::app::xmp_sidecar;
+use crate::app::embedded_metadata;
+use crate::app::auto_tag::{self, AutoTagRule};
+use crate::app::tag_store::{self, TagStore};
+use crate::app::directory_scan::{self, ScanSettings};
+
+/// How long a file must go without further writes before it is imported, or its tile and
+/// hashes are refreshed, so a file that is still being copied is not read half-written.
+const SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// What happened to a file that is waiting to settle.
+#[derive(Clone, Copy, PartialEq)]
+enum Change {
+    Added,
+    Modified,
+}
+
+/// Watches every library path and applies file changes to the scanned file lists
+/// and the tag database as they happen.
+pub struct LibraryWatcher {
+    watcher: RecommendedWatcher,
+    /// Watched paths, and whether each is watched recursively.
+    watched: HashMap<String, bool>,
+    library_paths: Arc<std::sync::Mutex<Vec<String>>>,
+    scan_settings: Arc<std::sync::Mutex<HashMap<String, ScanSettings>>>,
+    metadata_namespace: Arc<std::sync::Mutex<String>>,
+    auto_tag_rules: Arc<std::sync::Mutex<Vec<AutoTagRule>>>,
+}
+
+/// Everything an event needs to update; events are applied one at a time, in order.
+struct WatchTargets {
+    ctx: egui::Context,
+    directory_scan_state: Arc<Mutex<HashMap<String, DirectoryScanState>>>,
+    image_cache: Arc<Mutex<HashMap<String, ImageData>>>,
+    tag_store: Option<TagStore>,
+    library_paths: Arc<std::sync::Mutex<Vec<String>>>,
+    scan_settings: Arc<std::sync::Mutex<HashMap<String, ScanSettings>>>,
+    metadata_namespace: Arc<std::sync::Mutex<String>>,
+    auto_tag_rules: Arc<std::sync::Mutex<Vec<AutoTagRule>>>,
+    /// Where imports of new files report the files they failed for.
+    import_status: Arc<Mutex<Option<String>>>,
+    auto_tag_status: Arc<Mutex<Option<String>>>,
+}
+
+impl LibraryWatcher {
+    pub fn new(
+        ctx: &egui::Context,
+        runtime: &Arc<tokio::runtime::Runtime>,
+        directory_scan_state: &Arc<Mutex<HashMap<String, DirectoryScanState>>>,
+        image_cache: &Arc<Mutex<HashMap<String, ImageData>>>,
+        tag_store: Option<TagStore>,
+        import_status: &Arc<Mutex<Option<String>>>,
+        auto_tag_status: &Arc<Mutex<Option<String>>>,
+    ) -> notify::Result<Self> {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Event>();
+        let watcher = notify::recommended_watcher(move |result: notify::Result<Event>| {
+            if let Ok(event) = result {
+                let _ = sender.send(event);
+            }
+        })?;
+
+        let metadata_namespace = Arc::new(std::sync::Mutex::new(embedded_metadata::DEFAULT_NAMESPACE.to_string()));
+        let auto_tag_rules = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let scan_settings = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let library_paths = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let targets = WatchTargets {
+            ctx: ctx.clone(),
+            directory_scan_state: directory_scan_state.clone(),
+            image_cache: image_cache.clone(),
+            tag_store,
+            library_paths: library_paths.clone(),
+            scan_settings: scan_settings.clone(),
+            metadata_namespace: metadata_namespace.clone(),
+            auto_tag_rules: auto_tag_rules.clone(),
+            import_status: import_status.clone(),
+            auto_tag_status: auto_tag_status.clone(),
+        };
+        runtime.spawn(async move {
+            // Files that appeared or changed, with when they last did
+            let mut pending: HashMap<String, (Change, Instant)> = HashMap::new();
+            loop {
+                let next_settled = pending.values().map(|(_, last)| *last).min().map(|last| last + SETTLE_TIME);
+                let event = match next_settled {
+                    Some(deadline) => match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                        Ok(event) => event,
+                        Err(_) => {
+                            let now = Instant::now();
+                            let settled: Vec<(String, Change)> = pending
+                                .iter()
+                                .filter(|(_, (_, last))| *last + SETTLE_TIME <= now)
+                                .map(|(path, (change, _))| (path.clone(), *change))
+                                .collect();
+                            pending.retain(|path, _| !settled.iter().any(|(settled, _)| settled == path));
+                            let (added, modified): (Vec<_>, Vec<_>) =
+                                settled.into_iter().partition(|(_, change)| *change == Change::Added);
+                            files_added(&targets, added.into_iter().map(|(path, _)| path).collect()).await;
+                            files_modified(&targets, modified.into_iter().map(|(path, _)| path).collect()).await;
+                            targets.ctx.request_repaint();
+                            continue;
+                        }
+                    },
+                    None => receiver.recv().await,
+                };
+                let Some(event) = event else {
+                    break;
+                };
+                apply_event(&targets, event, &mut pending).await;
+                targets.ctx.request_repaint();
+            }
+        });
+
+        Ok(Self { watcher, watched: HashMap::new(), library_paths, scan_settings, metadata_namespace, auto_tag_rules })
+    }
+
+    /// Starts and stops watches so exactly the library `paths` are watched, recursively where
+    /// their scan settings say so, and picks up the namespace new files get their EXIF tags
+    /// under and the auto-tag rules.
+    pub fn sync(
+        &mut self,
+        paths: &[String],
+        scan_settings: &HashMap<String, ScanSettings>,
+        metadata_namespace: &str,
+        auto_tag_rules: &[AutoTagRule],
+    ) {
+        if let Ok(mut namespace) = self.metadata_namespace.lock()
+            && *namespace != metadata_namespace
+        {
+            *namespace = metadata_namespace.to_string();
+        }
+        if let Ok(mut rules) = self.auto_tag_rules.lock()
+            && *rules != auto_tag_rules
+        {
+            *rules = auto_tag_rules.to_vec();
+        }
+        if let Ok(mut library_paths) = self.library_paths.lock()
+            && *library_paths != paths
+        {
+            *library_paths = paths.to_vec();
+        }
+        if let Ok(mut settings) = self.scan_settings.lock()
+            && *settings != *scan_settings
+        {
+            *settings = scan_settings.clone();
+        }
+        let recursive = |path: &String| scan_settings.get(path).is_some_and(|settings| settings.recursive);
+        if paths.len() == self.watched.len()
+            && paths.iter().all(|p| self.watched.get(p) == Some(&recursive(p)))
+        {
+            return;
+        }
+
+        // Paths whose recursion changed are watched again in the new mode
+        let stale: Vec<String> = self
+            .watched
+            .iter()
+            .filter(|(p, watched_recursive)| !paths.contains(p) || **watched_recursive != recursive(p))
+            .map(|(p, _)| p.clone())
+            .collect();
+        for path in stale {
+            let _ = self.watcher.unwatch(Path::new(&path));
+            self.watched.remove(&path);
+        }
+        for path in paths {
+            let mode = if recursive(path) { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+            if !self.watched.contains_key(path) && self.watcher.watch(Path::new(path), mode).is_ok() {
+                self.watched.insert(path.clone(), recursive(path));
+            }
+        }
+    }
+}
+
+/// Notes that `paths` changed; a file that is new stays new however often it is written to.
+fn note_pending(pending: &mut HashMap<String, (Change, Instant)>, paths: Vec<String>, change: Change) {
+    let now = Instant::now();
+    for path in paths {
+        let entry = pending.entry(path).or_insert((change, now));
+        if change == Change::Added {
+            entry.0 = Change::Added;
+        }
+        entry.1 = now;
+    }
+}
+
+/// Applies one event. New and changed files are only noted in `pending`; they are handled
+/// once they have settled, see [`SETTLE_TIME`].
+async fn apply_event(targets: &WatchTargets, event: Event, pending: &mut HashMap<String, (Change, Instant)>) {
+    let paths: Vec<String> = event.paths.iter().map(|p| p.display().to_string()).collect();
+    match event.kind {
+        EventKind::Create(_) | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+            note_pending(pending, paths, Change::Added);
+        }
+        EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+            files_removed(targets, &paths).await;
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if paths.len() == 2 => {
+            files_removed(targets, &paths[..1]).await;
+            if let Some(store) = &targets.tag_store {
+                let (from, to) = (paths[0].clone(), paths[1].clone());
+                // Not journaled: another program moved the file, so there is no edit of ours for
+                // Ctrl+Z to undo, and undoing only the path would cut the tags off the real file
+                let _ = store.write(move |tx| tag_store::rename_file_path(tx, &from, &to)).await;
+            }
+            note_pending(pending, paths[1..].to_vec(), Change::Added);
+        }
+        EventKind::Modify(ModifyKind::Name(_)) => {
+            let (existing, gone): (Vec<String>, Vec<String>) =
+                paths.into_iter().partition(|p| Path::new(p).exists());
+            files_removed(targets, &gone).await;
+            note_pending(pending, existing, Change::Added);
+        }
+        // Permissions and timestamps alone change nothing that is shown or hashed
+        EventKind::Modify(ModifyKind::Metadata(_)) => {}
+        EventKind::Modify(_) => {
+            note_pending(pending, paths.into_iter().filter(|p| is_media_file(p)).collect(), Change::Modified);
+        }
+        _ => {}
+    }
+}
+
+/// Contents changed: reloads the tiles, refreshes the stored hashes and reads embedded
+/// metadata that could not be read before.
+async fn files_modified(targets: &WatchTargets, paths: Vec<String>) {
+    if paths.is_empty() {
+        return;
+    }
+    {
+        let mut cache = targets.image_cache.lock().await;
+        for path in &paths {
+            if let Some(image_data) = cache.remove(path) {
+                targets.ctx.forget_image(&image_data.uri);
+            }
+        }
+    }
+    if let Some(store) = &targets.tag_store {
+        let namespace = targets.metadata_namespace.lock().map(|n| n.clone()).unwrap_or_default();
+        let problems = embedded_metadata::import_embedded(store.clone(), paths.clone(), namespace).await;
+        directory_scan::report_problems(&targets.import_status, "Importing embedded metadata", &problems).await;
+        let roots = targets.library_paths.lock().map(|p| p.clone()).unwrap_or_default();
+        let problems = file_identity::hash_files(store.clone(), paths.clone(), roots).await;
+        directory_scan::report_problems(&targets.import_status, "Storing content hashes", &problems).await;
+        let problems = perceptual_hash::hash_images(store.clone(), paths).await;
+        directory_scan::report_problems(&targets.import_status, "Storing perceptual hashes", &problems).await;
+    }
+}
+
+/// The library paths whose scan would include `path`. Library paths can be nested, so a
+/// file may belong to more than one. Reads folders and ignore files, so it runs on the
+/// blocking pool before the scan state is locked.
+fn including_roots(path: &str, roots: &[String], scan_settings: &HashMap<String, ScanSettings>) -> Vec<String> {
+    roots
+        .iter()
+        .filter(|root| scan_settings.get(*root).cloned().unwrap_or_default().includes(root, path))
+        .cloned()
+        .collect()
+}
+
+/// The loaded listings of `roots`. Running scans are left alone, they find the file themselves.
+fn owning_roots<'a>(roots: &[String], state_map: &'a mut HashMap<String, DirectoryScanState>) -> Vec<&'a mut Vec<String>> {
+    state_map
+        .iter_mut()
+        .filter_map(|(root, state)| match state {
+            DirectoryScanState::Complete(files) if roots.contains(root) => Some(Arc::make_mut(files)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The media files under a folder that appeared in a recursively scanned path, such as
+/// one moved in with its contents, which report no events of their own.
+fn files_in_new_folder(folder: &str, scan_settings: &HashMap<String, ScanSettings>) -> Vec<String> {
+    let mut files = Vec::new();
+    for (root, settings) in scan_settings {
+        if settings.recursive && Path::new(folder).starts_with(root) {
+            let walk = ScanSettings { max_depth: None, ..settings.clone() };
+            for file in directory_scan::scan_directory(folder, &walk) {
+                if settings.includes(root, &file) && !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+    }
+    files
+}
+
+async fn files_added(targets: &WatchTargets, paths: Vec<String>) {
+    let scan_settings = targets.scan_settings.lock().map(|s| s.clone()).unwrap_or_default();
+    let (folders, paths): (Vec<String>, Vec<String>) = paths.into_iter().partition(|p| Path::new(p).is_dir());
+    let mut paths: Vec<String> = paths
+        .into_iter()
+        .filter(|p| is_media_file(p) && Path::new(p).is_file())
+        .collect();
+    if !folders.is_empty() {
+        let settings_clone = scan_settings.clone();
+        let found = tokio::task::spawn_blocking(move || {
+            folders.iter().flat_map(|folder| files_in_new_folder(folder, &settings_clone)).collect::<Vec<_>>()
+        })
+        .await
+        .unwrap_or_default();
+        for file in found {
+            if !paths.contains(&file) {
+                paths.push(file);
+            }
+        }
+    }
+    if paths.is_empty() {
+        return;
+    }
+
+    let roots = targets.library_paths.lock().map(|p| p.clone()).unwrap_or_default();
+    let paths_clone = paths.clone();
+    let roots_clone = roots.clone();
+    let owners = tokio::task::spawn_blocking(move || {
+        paths_clone
+            .into_iter()
+            .map(|path| {
+                let owners = including_roots(&path, &roots_clone, &scan_settings);
+                (path, owners)
+            })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .unwrap_or_default();
+    {
+        let mut state_map = targets.directory_scan_state.lock().await;
+        for (path, owners) in &owners {
+            for files in owning_roots(owners, &mut state_map) {
+                if !files.contains(path) {
+                    files.push(path.clone());
+                }
+            }
+        }
+    }
+    targets.ctx.request_repaint();
+
+    // Hashing re-links the record of a file that was moved here from elsewhere
+    if let Some(store) = &targets.tag_store {
+        let paths_clone = paths.clone();
+        let _ = store.write(move |tx| tag_store::register_files(tx, &paths_clone)).await;
+        let problems = xmp_sidecar::import_sidecars(store.clone(), paths.clone()).await;
+        directory_scan::report_problems(&targets.import_status, "Importing XMP sidecars", &problems).await;
+        let namespace = targets.metadata_namespace.lock().map(|n| n.clone()).unwrap_or_default();
+        let problems = embedded_metadata::import_embedded(store.clone(), paths.clone(), namespace).await;
+        directory_scan::report_problems(&targets.import_status, "Importing embedded metadata", &problems).await;
+        let rules = targets.auto_tag_rules.lock().map(|r| r.clone()).unwrap_or_default();
+        auto_tag::apply_to_scanned(store.clone(), paths.clone(), rules, &targets.auto_tag_status).await;
+        let problems = file_identity::hash_files(store.clone(), paths.clone(), roots).await;
+        directory_scan::report_problems(&targets.import_status, "Storing content hashes", &problems).await;
+        let problems = perceptual_hash::hash_images(store.clone(), paths).await;
+        directory_scan::report_problems(&targets.import_status, "Storing perceptual hashes", &problems).await;
+    }
+}
+
+/// Drops deleted files, and the files of deleted folders, from the gallery. Their database records
+/// are kept so that a file moved out of a watched folder can be re-linked by hash when it shows up again.
+async fn files_removed(targets: &WatchTargets, paths: &[String]) {
+    {
+        let mut state_map = targets.directory_scan_state.lock().await;
+        for state in state_map.values_mut() {
+            state.files_mut().retain(|f| !paths.iter().any(|path| Path::new(f).starts_with(path)));
+        }
+    }
+    let mut cache = targets.image_cache.lock().await;
+    for path in paths {
+        if let Some(image_data) = cache.remove(path) {
+            targets.ctx.forget_image(&image_data.uri);
+        }
+    }
+}
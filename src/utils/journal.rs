@@ -0,0 +1,386 @@
+use std::sync::Arc;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use tokio::sync::Mutex;
+use crate::app::tag_store::{self, TagStore};
+use crate::app::xmp_sidecar;
+
+/// How many journal entries are kept; older ones can no longer be undone.
+const HISTORY_LIMIT: i64 = 500;
+
+pub const UNDO_SHORTCUT: egui::KeyboardShortcut = egui::KeyboardShortcut::new(egui::Modifiers::COMMAND, egui::Key::Z);
+pub const REDO_SHORTCUT: egui::KeyboardShortcut =
+    egui::KeyboardShortcut::new(egui::Modifiers::COMMAND.plus(egui::Modifiers::SHIFT), egui::Key::Z);
+
+/// What Ctrl+Z and Ctrl+Shift+Z would do next, for the Edit menu.
+#[derive(Clone, Default)]
+pub struct JournalHistory {
+    pub undo: Option<String>,
+    pub redo: Option<String>,
+}
+
+/// Starts recording a new entry; every row change until [`finish`] is journaled under it.
+pub fn begin(conn: &Connection, description: &str) -> rusqlite::Result<i64> {
+    conn.execute(
+        "INSERT INTO journal (description, created_at) VALUES (?1, ?2)",
+        params![description, tag_store::now_unix()],
+    )?;
+    let entry_id = conn.last_insert_rowid();
+    conn.execute("UPDATE journal_state SET recording = 1, entry_id = ?1", [entry_id])?;
+    Ok(entry_id)
+}
+
+/// Stops recording. Entries that changed nothing are dropped; anything else starts a new
+/// branch of history, so undone entries can no longer be redone.
+pub fn finish(conn: &Connection, entry_id: i64) -> rusqlite::Result<()> {
+    conn.execute("UPDATE journal_state SET recording = 0, entry_id = NULL", [])?;
+
+    let changed: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM journal_statements WHERE entry_id = ?1)",
+        [entry_id],
+        |row| row.get(0),
+    )?;
+    if !changed {
+        conn.execute("DELETE FROM journal WHERE id = ?1", [entry_id])?;
+        return Ok(());
+    }
+
+    conn.execute("DELETE FROM journal WHERE undone = 1", [])?;
+    conn.execute("DELETE FROM journal WHERE id <= ?1", [entry_id - HISTORY_LIMIT])?;
+    Ok(())
+}
+
+/// What undoing or redoing the next entry came to.
+#[derive(Debug, PartialEq)]
+pub enum Replayed {
+    /// There was nothing to undo or redo.
+    Nothing,
+    /// The entry with this description was applied.
+    Applied(String),
+    /// Something outside the journal, such as a scan or an import, changed rows the entry
+    /// restores. The entry could not be applied, so it and the history behind it were dropped.
+    Dropped { description: String, reason: String },
+}
+
+/// Reverts the most recent entry.
+pub fn undo(conn: &Connection) -> rusqlite::Result<Replayed> {
+    replay(conn, true)
+}
+
+/// Re-applies the most recently undone entry.
+pub fn redo(conn: &Connection) -> rusqlite::Result<Replayed> {
+    replay(conn, false)
+}
+
+/// Runs an entry's stored statements newest first. While they run the triggers record
+/// their inverses in their place, so the same entry can be flipped back the other way.
+/// Only tag, collection, attribute and note edits are journaled. File moves are not: taggerrs
+/// moves no files itself, and a move another program made is not the user's edit to undo.
+fn replay(conn: &Connection, undo: bool) -> rusqlite::Result<Replayed> {
+    let sql = if undo {
+        "SELECT id, description FROM journal WHERE undone = 0 ORDER BY id DESC LIMIT 1"
+    } else {
+        "SELECT id, description FROM journal WHERE undone = 1 ORDER BY id ASC LIMIT 1"
+    };
+    let Some((entry_id, description)) = conn
+        .query_row(sql, [], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))
+        .optional()?
+    else {
+        return Ok(Replayed::Nothing);
+    };
+
+    // Rows come back in reverse, so a link can be restored before the tag it points to
+    conn.pragma_update(None, "defer_foreign_keys", "ON")?;
+    match tag_store::in_savepoint(conn, || replay_statements(conn, entry_id, undo)) {
+        Ok(()) => Ok(Replayed::Applied(description)),
+        Err(rusqlite::Error::SqliteFailure(e, message)) if e.code == rusqlite::ErrorCode::ConstraintViolation => {
+            // Entries further back build on this one, and undone ones further on follow from it
+            let drop = if undo { "DELETE FROM journal WHERE id <= ?1" } else { "DELETE FROM journal WHERE id >= ?1" };
+            conn.execute(drop, [entry_id])?;
+            let reason = message.unwrap_or_else(|| e.to_string());
+            Ok(Replayed::Dropped { description, reason })
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn replay_statements(conn: &Connection, entry_id: i64, undo: bool) -> rusqlite::Result<()> {
+    let statements = conn
+        .prepare_cached("SELECT statement FROM journal_statements WHERE entry_id = ?1 ORDER BY id DESC")?
+        .query_map([entry_id], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    conn.execute("DELETE FROM journal_statements WHERE entry_id = ?1", [entry_id])?;
+
+    conn.execute("UPDATE journal_state SET recording = 1, entry_id = ?1", [entry_id])?;
+    for statement in &statements {
+        conn.execute_batch(statement)?;
+    }
+    conn.execute("UPDATE journal_state SET recording = 0, entry_id = NULL", [])?;
+
+    // Deferred links would only fail at commit, taking the whole step down with them
+    if conn.prepare("PRAGMA foreign_key_check")?.exists([])? {
+        return Err(tag_store::rule_violation(
+            "rows it restores point at tags or files that were changed outside the undo history".to_string(),
+        ));
+    }
+    conn.execute("UPDATE journal SET undone = ?1 WHERE id = ?2", params![undo, entry_id])?;
+    Ok(())
+}
+
+pub fn history(conn: &Connection) -> rusqlite::Result<JournalHistory> {
+    let undo = conn
+        .query_row("SELECT description FROM journal WHERE undone = 0 ORDER BY id DESC LIMIT 1", [], |row| row.get(0))
+        .optional()?;
+    let redo = conn
+        .query_row("SELECT description FROM journal WHERE undone = 1 ORDER BY id ASC LIMIT 1", [], |row| row.get(0))
+        .optional()?;
+    Ok(JournalHistory { undo, redo })
+}
+
+/// Runs `edit` as one undoable journal entry shown as `description`. A failure is put in
+/// `error` and a success clears it; returns what `edit` returned if it succeeded.
+pub async fn journaled_edit<T, F>(
+    tag_store: &TagStore,
+    error: &Arc<Mutex<Option<String>>>,
+    description: String,
+    edit: F,
+) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Transaction) -> rusqlite::Result<T> + Send + 'static,
+{
+    let result = tag_store.write_journaled(description.clone(), edit).await;
+    *error.lock().await = result.as_ref().err().map(|e| format!("{} failed: {}", description, e));
+    result.ok()
+}
+
+/// Runs [`journaled_edit`] in the background, for edits with nothing to do afterwards.
+pub fn spawn_journaled_edit<F>(
+    ctx: &egui::Context,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    error: &Arc<Mutex<Option<String>>>,
+    description: String,
+    edit: F,
+) where
+    F: FnOnce(&Transaction) -> rusqlite::Result<()> + Send + 'static,
+{
+    let store_clone = tag_store.clone();
+    let error_clone = error.clone();
+    let ctx_clone = ctx.clone();
+    runtime.spawn(async move {
+        journaled_edit(&store_clone, &error_clone, description, edit).await;
+        ctx_clone.request_repaint();
+    });
+}
+
+/// Undoes or redoes one entry in the background and reports the outcome in `status`.
+/// With `write_sidecars` set, the sidecars whose keywords changed are rewritten to match.
+pub fn spawn_step(
+    ctx: &egui::Context,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    status: &Arc<Mutex<Option<String>>>,
+    undo: bool,
+    write_sidecars: bool,
+) {
+    let store_clone = tag_store.clone();
+    let status_clone = status.clone();
+    let ctx_clone = ctx.clone();
+    runtime.spawn(async move {
+        let result = store_clone
+            .write(move |tx| {
+                xmp_sidecar::tracking_sidecars(tx, write_sidecars, |tx| if undo { self::undo(tx) } else { redo(tx) })
+            })
+            .await;
+        let (result, sidecar_error) = match result {
+            Ok((description, changed)) => (Ok(description), xmp_sidecar::rewrite_sidecars(&store_clone, changed).await),
+            Err(e) => (Err(e), None),
+        };
+        let mut message = match (result, undo) {
+            (Ok(Replayed::Applied(description)), true) => format!("Undid: {}", description),
+            (Ok(Replayed::Applied(description)), false) => format!("Redid: {}", description),
+            (Ok(Replayed::Nothing), true) => "Nothing to undo".to_string(),
+            (Ok(Replayed::Nothing), false) => "Nothing to redo".to_string(),
+            (Ok(Replayed::Dropped { description, reason }), true) => {
+                format!("Could not undo {}, so the history before it was cleared: {}", description, reason)
+            }
+            (Ok(Replayed::Dropped { description, reason }), false) => {
+                format!("Could not redo {}, so the history after it was cleared: {}", description, reason)
+            }
+            (Err(e), true) => format!("Undo failed: {}", e),
+            (Err(e), false) => format!("Redo failed: {}", e),
+        };
+        if let Some(sidecar_error) = sidecar_error {
+            message = format!("{}; {}", message, sidecar_error);
+        }
+        *status_clone.lock().await = Some(message);
+        ctx_clone.request_repaint();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every row an edit can touch, so a state can be compared after undo and redo.
+    fn snapshot(conn: &Connection) -> Vec<String> {
+        let queries = [
+            "SELECT 'tag ' || id || ' ' || name FROM tags",
+            "SELECT 'file_tag ' || f.path || ' ' || t.name FROM file_tags ft
+             JOIN files f ON f.id = ft.file_id JOIN tags t ON t.id = ft.tag_id",
+            "SELECT 'parent ' || tag_id || ' ' || parent_id FROM tag_parents",
+            "SELECT 'alias ' || alias || ' ' || tag_id FROM tag_aliases",
+            "SELECT 'implies ' || tag_id || ' ' || implied_tag_id FROM tag_implications",
+        ];
+        let mut rows: Vec<String> = queries
+            .iter()
+            .flat_map(|sql| {
+                let mut stmt = conn.prepare(sql).unwrap();
+                stmt.query_map([], |row| row.get::<_, String>(0)).unwrap().map(Result::unwrap).collect::<Vec<_>>()
+            })
+            .collect();
+        rows.sort();
+        rows
+    }
+
+    fn journaled(conn: &mut Connection, description: &str, edit: impl FnOnce(&Connection) -> rusqlite::Result<()>) {
+        let tx = conn.transaction().unwrap();
+        let entry_id = begin(&tx, description).unwrap();
+        edit(&tx).unwrap();
+        finish(&tx, entry_id).unwrap();
+        tx.commit().unwrap();
+    }
+
+    fn step(conn: &mut Connection, undo: bool) -> Replayed {
+        let tx = conn.transaction().unwrap();
+        let replayed = if undo { self::undo(&tx) } else { redo(&tx) }.unwrap();
+        tx.commit().unwrap();
+        replayed
+    }
+
+    fn tag_id(conn: &Connection, name: &str) -> i64 {
+        conn.query_row("SELECT id FROM tags WHERE name = ?1", [name], |row| row.get(0)).unwrap()
+    }
+
+    /// Runs `edit` as an entry, then checks undo restores the state before it and redo the state after.
+    fn assert_round_trip(conn: &mut Connection, description: &str, edit: impl FnOnce(&Connection) -> rusqlite::Result<()>) {
+        let before = snapshot(conn);
+        journaled(conn, description, edit);
+        let after = snapshot(conn);
+        assert_ne!(before, after);
+
+        assert_eq!(step(conn, true), Replayed::Applied(description.to_string()));
+        assert_eq!(snapshot(conn), before);
+        assert_eq!(step(conn, false), Replayed::Applied(description.to_string()));
+        assert_eq!(snapshot(conn), after);
+    }
+
+    fn seeded() -> Connection {
+        let conn = tag_store::open_in_memory();
+        let paths = ["/a.jpg".to_string(), "/b.jpg".to_string()];
+        let tags = [vec!["animal".to_string(), "cat".to_string()], vec!["pet".to_string()]];
+        tag_store::add_tags_to_files(&conn, &paths, &tags).unwrap();
+        tag_store::add_alias(&conn, "kitty", "cat").unwrap();
+        tag_store::add_implication(&conn, "cat", "pet").unwrap();
+        conn
+    }
+
+    #[test]
+    fn adding_tags_round_trips() {
+        let mut conn = seeded();
+        assert_round_trip(&mut conn, "Add tags", |tx| {
+            tag_store::add_tags_to_files(tx, &["/a.jpg".to_string()], &[vec!["place".to_string(), "beach".to_string()]])
+        });
+    }
+
+    #[test]
+    fn renaming_a_tag_round_trips() {
+        let mut conn = seeded();
+        let cat = tag_id(&conn, "cat");
+        assert_round_trip(&mut conn, "Rename tag", |tx| tag_store::rename_tag(tx, cat, "feline"));
+    }
+
+    #[test]
+    fn deleting_a_tag_restores_its_links_aliases_and_rules() {
+        let mut conn = seeded();
+        let cat = tag_id(&conn, "cat");
+        assert_round_trip(&mut conn, "Delete tag", |tx| tag_store::delete_tag(tx, cat));
+    }
+
+    #[test]
+    fn merging_tags_round_trips() {
+        let mut conn = seeded();
+        tag_store::add_tags_to_files(&conn, &["/c.jpg".to_string()], &[vec!["kitten".to_string()]]).unwrap();
+        tag_store::add_implication(&conn, "kitten", "pet").unwrap();
+        let (kitten, cat) = (tag_id(&conn, "kitten"), tag_id(&conn, "cat"));
+        assert_round_trip(&mut conn, "Merge tags", |tx| tag_store::merge_tags(tx, kitten, cat));
+    }
+
+    #[test]
+    fn undo_and_redo_walk_the_history_in_order() {
+        let mut conn = seeded();
+        journaled(&mut conn, "First", |tx| tag_store::ensure_tag(tx, "one").map(|_| ()));
+        journaled(&mut conn, "Second", |tx| tag_store::ensure_tag(tx, "two").map(|_| ()));
+
+        let history = |conn: &Connection| {
+            let history = super::history(conn).unwrap();
+            (history.undo, history.redo)
+        };
+        assert_eq!(history(&conn), (Some("Second".to_string()), None));
+        assert_eq!(step(&mut conn, true), Replayed::Applied("Second".to_string()));
+        assert_eq!(step(&mut conn, true), Replayed::Applied("First".to_string()));
+        assert_eq!(step(&mut conn, true), Replayed::Nothing);
+        assert_eq!(history(&conn), (None, Some("First".to_string())));
+
+        // A new edit starts a new branch, so the undone entries are gone for good
+        assert_eq!(step(&mut conn, false), Replayed::Applied("First".to_string()));
+        journaled(&mut conn, "Third", |tx| tag_store::ensure_tag(tx, "three").map(|_| ()));
+        assert_eq!(history(&conn), (Some("Third".to_string()), None));
+        assert_eq!(step(&mut conn, false), Replayed::Nothing);
+    }
+
+    #[test]
+    fn entries_that_changed_nothing_are_dropped() {
+        let mut conn = seeded();
+        journaled(&mut conn, "Add dog", |tx| tag_store::ensure_tag(tx, "dog").map(|_| ()));
+        journaled(&mut conn, "Remove a missing alias", |tx| tag_store::remove_alias(tx, "nothing"));
+        let entries: i64 = conn.query_row("SELECT COUNT(*) FROM journal", [], |row| row.get(0)).unwrap();
+        assert_eq!(entries, 1);
+        assert_eq!(super::history(&conn).unwrap().undo.as_deref(), Some("Add dog"));
+    }
+
+    #[test]
+    fn history_is_pruned_beyond_the_limit() {
+        let mut conn = tag_store::open_in_memory();
+        for i in 0..HISTORY_LIMIT + 10 {
+            journaled(&mut conn, &format!("Add {}", i), |tx| tag_store::ensure_tag(tx, &format!("tag{}", i)).map(|_| ()));
+        }
+        let (entries, oldest): (i64, String) = conn
+            .query_row("SELECT COUNT(*), (SELECT description FROM journal ORDER BY id LIMIT 1) FROM journal", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(entries, HISTORY_LIMIT);
+        assert_eq!(oldest, "Add 10");
+        let statements: i64 = conn.query_row("SELECT COUNT(*) FROM journal_statements", [], |row| row.get(0)).unwrap();
+        assert_eq!(statements, HISTORY_LIMIT);
+    }
+
+    #[test]
+    fn entries_conflicting_with_outside_changes_are_dropped() {
+        let mut conn = seeded();
+        journaled(&mut conn, "Add dog", |tx| tag_store::ensure_tag(tx, "dog").map(|_| ()));
+        let cat = tag_id(&conn, "cat");
+        journaled(&mut conn, "Delete tag cat", |tx| tag_store::delete_tag(tx, cat));
+        // A scan or an import brings the name back under a new id, outside the journal
+        tag_store::add_tags_to_files(&conn, &["/a.jpg".to_string()], &[vec!["cat".to_string()]]).unwrap();
+        let before = snapshot(&conn);
+
+        assert!(matches!(step(&mut conn, true), Replayed::Dropped { description, .. } if description == "Delete tag cat"));
+        assert_eq!(snapshot(&conn), before);
+        // The entries behind it are gone too, so the next undo has nothing left
+        assert_eq!(step(&mut conn, true), Replayed::Nothing);
+        let violations = conn.prepare("PRAGMA foreign_key_check").unwrap().exists([]).unwrap();
+        assert!(!violations);
+    }
+}
@@ -0,0 +1,348 @@
+use std::path::Path;
+use rusqlite::{params, Connection, OptionalExtension};
+use crate::app::tag_store::{self, TagStore};
+
+/// Bumped whenever the export layout changes incompatibly.
+pub const EXPORT_FORMAT_VERSION: u32 = 1;
+
+/// A portable dump of the tag library. Every list is sorted so exports diff cleanly in git.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct LibraryExport {
+    pub format_version: u32,
+    pub files: Vec<ExportedFile>,
+    pub tags: Vec<ExportedTag>,
+    pub aliases: Vec<ExportedAlias>,
+    pub implications: Vec<ExportedImplication>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ExportedFile {
+    pub id: String,
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_hash: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtime: Option<i64>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ExportedTag {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ExportedAlias {
+    pub alias: String,
+    pub tag: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ExportedImplication {
+    pub tag: String,
+    pub implies: String,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ImportMode {
+    /// Adds the imported tags to what is already in the library.
+    Merge,
+    /// Drops every existing tag and rule first. File records are kept.
+    Replace,
+}
+
+pub struct ImportReport {
+    pub files: usize,
+    pub tags: usize,
+    /// Exported paths that matched no file on disk, by path or by content hash.
+    pub unmatched: Vec<String>,
+}
+
+pub fn export_library(conn: &Connection) -> rusqlite::Result<LibraryExport> {
+    let mut tags_stmt = conn.prepare_cached(
+        "SELECT t.name FROM file_tags ft JOIN tags t ON t.id = ft.tag_id WHERE ft.file_id = ?1 ORDER BY t.name",
+    )?;
+    let files = conn
+        .prepare(
+            "SELECT f.id, f.path, h.content_hash, h.size, h.mtime FROM files f
+             LEFT JOIN file_hashes h ON h.file_id = f.id
+             ORDER BY f.path",
+        )?
+        .query_map([], |row| {
+            Ok(ExportedFile {
+                id: row.get(0)?,
+                path: row.get(1)?,
+                content_hash: row.get(2)?,
+                size: row.get(3)?,
+                mtime: row.get(4)?,
+                tags: Vec::new(),
+            })
+        })?
+        .map(|file| {
+            let mut file = file?;
+            file.tags = tags_stmt
+                .query_map([&file.id], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+            Ok(file)
+        })
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let tags = conn
+        .prepare(
+            "SELECT t.name, parent.name FROM tags t
+             LEFT JOIN tag_parents tp ON tp.tag_id = t.id
+             LEFT JOIN tags parent ON parent.id = tp.parent_id
+             ORDER BY t.name",
+        )?
+        .query_map([], |row| Ok(ExportedTag { name: row.get(0)?, parent: row.get(1)? }))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    let rules = tag_store::list_tag_rules(conn)?;
+    Ok(LibraryExport {
+        format_version: EXPORT_FORMAT_VERSION,
+        files,
+        tags,
+        aliases: rules
+            .aliases
+            .into_iter()
+            .map(|(alias, tag)| ExportedAlias { alias, tag })
+            .collect(),
+        implications: rules
+            .implications
+            .into_iter()
+            .map(|(tag, implies)| ExportedImplication { tag, implies })
+            .collect(),
+    })
+}
+
+/// Finds where an exported file lives on this machine: at its old path, or else
+/// at any known path with the same content hash.
+fn locate_file(conn: &Connection, file: &ExportedFile) -> rusqlite::Result<Option<String>> {
+    if Path::new(&file.path).exists() {
+        return Ok(Some(file.path.clone()));
+    }
+    let Some(content_hash) = &file.content_hash else {
+        return Ok(None);
+    };
+    let candidates = conn
+        .prepare_cached(
+            "SELECT f.path FROM files f JOIN file_hashes h ON h.file_id = f.id WHERE h.content_hash = ?1",
+        )?
+        .query_map([content_hash], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(candidates.into_iter().find(|path| Path::new(path).exists()))
+}
+
+/// Loads an export into the store. Unmatched files still get a record with their
+/// content hash, so they are re-linked automatically once a copy is scanned.
+pub fn import_library(conn: &Connection, export: &LibraryExport, mode: ImportMode) -> rusqlite::Result<ImportReport> {
+    if export.format_version > EXPORT_FORMAT_VERSION {
+        return Err(tag_store::rule_violation(format!(
+            "this export uses format version {}, newer than the {} this build understands",
+            export.format_version, EXPORT_FORMAT_VERSION
+        )));
+    }
+
+    if mode == ImportMode::Replace {
+        // Cascades to file links, hierarchy, aliases and implications
+        conn.execute("DELETE FROM tags", [])?;
+    }
+
+    for tag in &export.tags {
+        tag_store::ensure_tag(conn, &tag.name)?;
+    }
+    for tag in &export.tags {
+        if let Some(parent) = &tag.parent {
+            let tag_id = tag_store::ensure_tag(conn, &tag.name)?;
+            let parent_id = tag_store::ensure_tag(conn, parent)?;
+            tag_store::set_tag_parent(conn, tag_id, Some(parent_id))?;
+        }
+    }
+    for alias in &export.aliases {
+        tag_store::add_alias(conn, &alias.alias, &alias.tag)?;
+    }
+    for implication in &export.implications {
+        tag_store::add_implication(conn, &implication.tag, &implication.implies)?;
+    }
+
+    let mut unmatched = Vec::new();
+    let mut link = conn.prepare_cached("INSERT OR IGNORE INTO file_tags (file_id, tag_id) VALUES (?1, ?2)")?;
+    for file in &export.files {
+        let path = match locate_file(conn, file)? {
+            Some(path) => path,
+            None => {
+                unmatched.push(file.path.clone());
+                file.path.clone()
+            }
+        };
+
+        // Keep the exported uuid when it is free
+        conn.execute(
+            "INSERT OR IGNORE INTO files (id, path, added_at) VALUES (?1, ?2, strftime('%s', 'now'))",
+            params![file.id, path],
+        )?;
+        let file_id = tag_store::ensure_file(conn, &path)?;
+
+        let has_hash = conn
+            .query_row("SELECT 1 FROM file_hashes WHERE file_id = ?1", [&file_id], |_| Ok(()))
+            .optional()?
+            .is_some();
+        if !has_hash && let Some(content_hash) = &file.content_hash {
+            conn.execute(
+                "INSERT INTO file_hashes (file_id, size, mtime, content_hash) VALUES (?1, ?2, ?3, ?4)",
+                params![file_id, file.size.unwrap_or(0), file.mtime.unwrap_or(0), content_hash],
+            )?;
+        }
+
+        for tag in &file.tags {
+            let tag_id = tag_store::ensure_tag(conn, tag)?;
+            link.execute(params![file_id, tag_id])?;
+        }
+    }
+    tag_store::apply_implications(conn, None)?;
+
+    Ok(ImportReport { files: export.files.len(), tags: export.tags.len(), unmatched })
+}
+
+/// What the library file dialog was opened for.
+#[derive(Clone, Copy)]
+pub enum LibraryDialogAction {
+    Export,
+    Import(ImportMode),
+}
+
+#[derive(Clone)]
+pub enum LibraryIoStatus {
+    Running(String),
+    Finished { message: String, unmatched: Vec<String> },
+    Failed(String),
+}
+
+pub async fn export_to_file(tag_store: &TagStore, path: &Path) -> Result<usize, String> {
+    let export = tag_store.read(export_library).await.map_err(|e| e.to_string())?;
+    let json = serde_json::to_string_pretty(&export).map_err(|e| e.to_string())?;
+    tokio::fs::write(path, json).await.map_err(|e| e.to_string())?;
+    Ok(export.files.len())
+}
+
+pub async fn import_from_file(tag_store: &TagStore, path: &Path, mode: ImportMode) -> Result<ImportReport, String> {
+    let json = tokio::fs::read_to_string(path).await.map_err(|e| e.to_string())?;
+    let export: LibraryExport = serde_json::from_str(&json).map_err(|e| format!("not a taggerrs export: {}", e))?;
+    let description = match mode {
+        ImportMode::Merge => format!("Import {} (merge)", path.display()),
+        ImportMode::Replace => format!("Import {} (replace)", path.display()),
+    };
+    tag_store
+        .write_journaled(description, move |tx| import_library(tx, &export, mode))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn library() -> Connection {
+        let conn = tag_store::open_in_memory();
+        let paths = ["/photos/a.jpg".to_string(), "/photos/b.jpg".to_string()];
+        tag_store::add_tags_to_files(&conn, &paths, &[vec!["animal".to_string(), "cat".to_string()]]).unwrap();
+        tag_store::add_tags_to_files(&conn, &paths[..1], &[vec!["beach".to_string()]]).unwrap();
+        tag_store::add_alias(&conn, "kitty", "cat").unwrap();
+        tag_store::add_implication(&conn, "cat", "pet").unwrap();
+        tag_store::apply_implications(&conn, None).unwrap();
+        tag_store::record_file_hash(&conn, "/photos/b.jpg", 10, 20, "b-hash", &Default::default()).unwrap();
+        conn
+    }
+
+    /// The export as it would be written to and read back from disk.
+    fn exported(conn: &Connection) -> LibraryExport {
+        let json = serde_json::to_string_pretty(&export_library(conn).unwrap()).unwrap();
+        serde_json::from_str(&json).unwrap()
+    }
+
+    fn as_json(conn: &Connection) -> serde_json::Value {
+        serde_json::to_value(export_library(conn).unwrap()).unwrap()
+    }
+
+    fn tag_names(conn: &Connection) -> Vec<String> {
+        export_library(conn).unwrap().tags.into_iter().map(|tag| tag.name).collect()
+    }
+
+    #[test]
+    fn merge_into_an_empty_library_round_trips() {
+        let source = library();
+        let target = tag_store::open_in_memory();
+        let report = import_library(&target, &exported(&source), ImportMode::Merge).unwrap();
+        assert_eq!(report.files, 2);
+        assert_eq!(report.tags, 4);
+        assert_eq!(report.unmatched, ["/photos/a.jpg", "/photos/b.jpg"]);
+        // Same uuids, tags, hierarchy, rules and hashes
+        assert_eq!(as_json(&target), as_json(&source));
+    }
+
+    #[test]
+    fn merge_keeps_what_the_library_already_has() {
+        let source = library();
+        let target = tag_store::open_in_memory();
+        tag_store::add_tags_to_files(&target, &["/photos/a.jpg".to_string()], &[vec!["dog".to_string()]]).unwrap();
+        tag_store::add_alias(&target, "puppy", "dog").unwrap();
+        import_library(&target, &exported(&source), ImportMode::Merge).unwrap();
+
+        assert_eq!(tag_names(&target), ["animal", "beach", "cat", "dog", "pet"]);
+        let export = export_library(&target).unwrap();
+        let a = export.files.iter().find(|file| file.path == "/photos/a.jpg").unwrap();
+        assert_eq!(a.tags, ["beach", "cat", "dog", "pet"]);
+        assert_eq!(export.aliases.len(), 2);
+    }
+
+    #[test]
+    fn replace_drops_existing_tags_but_keeps_file_records() {
+        let source = library();
+        let target = tag_store::open_in_memory();
+        tag_store::add_tags_to_files(&target, &["/other/c.jpg".to_string()], &[vec!["dog".to_string()]]).unwrap();
+        tag_store::add_implication(&target, "dog", "pet").unwrap();
+        import_library(&target, &exported(&source), ImportMode::Replace).unwrap();
+
+        let export = export_library(&target).unwrap();
+        assert_eq!(tag_names(&target), tag_names(&source));
+        assert_eq!(export.implications.len(), 1);
+        let c = export.files.iter().find(|file| file.path == "/other/c.jpg").unwrap();
+        assert!(c.tags.is_empty());
+        // Apart from the kept record, the library is now the export
+        let mut without_c = as_json(&target);
+        without_c["files"].as_array_mut().unwrap().retain(|file| file["path"] != "/other/c.jpg");
+        assert_eq!(without_c, as_json(&source));
+    }
+
+    #[test]
+    fn moved_files_are_found_by_content_hash() {
+        let source = library();
+        let target = tag_store::open_in_memory();
+        // A file that exists on this machine, known under its new path with the same contents
+        let moved = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        tag_store::record_file_hash(&target, moved, 10, 20, "b-hash", &Default::default()).unwrap();
+        let report = import_library(&target, &exported(&source), ImportMode::Merge).unwrap();
+
+        assert_eq!(report.unmatched, ["/photos/a.jpg"]);
+        let tags = tag_store::tags_for_paths(&target, &[moved.to_string(), "/photos/b.jpg".to_string()]).unwrap();
+        let mut moved_tags = tags[moved].clone();
+        moved_tags.sort();
+        assert_eq!(moved_tags, ["cat", "pet"]);
+        assert!(!tags.contains_key("/photos/b.jpg"));
+    }
+
+    #[test]
+    fn newer_formats_are_refused() {
+        let source = library();
+        let target = tag_store::open_in_memory();
+        let mut export = exported(&source);
+        export.format_version = EXPORT_FORMAT_VERSION + 1;
+        assert!(import_library(&target, &export, ImportMode::Replace).is_err());
+        assert!(tag_names(&target).is_empty());
+    }
+}
@@ -0,0 +1,565 @@
+use std::fmt;
+use rusqlite::{params, Connection};
+
+/// One schema change, applied in a single transaction together with its version bump.
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+/// Every schema change in order. Append new migrations at the end and never edit
+/// one that has shipped; `version` must keep counting up from 1.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "files, tags, hierarchy, aliases, implications and content hashes",
+        sql: "
+            CREATE TABLE files (
+                id       TEXT PRIMARY KEY,
+                path     TEXT NOT NULL UNIQUE,
+                added_at INTEGER NOT NULL
+            );
+            CREATE TABLE tags (
+                id   INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL UNIQUE
+            );
+            CREATE TABLE file_tags (
+                file_id TEXT NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+                tag_id  INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+                PRIMARY KEY (file_id, tag_id)
+            );
+            CREATE INDEX idx_file_tags_tag ON file_tags(tag_id);
+            CREATE TABLE tag_parents (
+                tag_id    INTEGER PRIMARY KEY REFERENCES tags(id) ON DELETE CASCADE,
+                parent_id INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE
+            );
+            CREATE INDEX idx_tag_parents_parent ON tag_parents(parent_id);
+            CREATE TABLE tag_aliases (
+                alias  TEXT PRIMARY KEY,
+                tag_id INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE
+            );
+            CREATE TABLE tag_implications (
+                tag_id         INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+                implied_tag_id INTEGER NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+                PRIMARY KEY (tag_id, implied_tag_id)
+            );
+            CREATE TABLE file_hashes (
+                file_id      TEXT PRIMARY KEY REFERENCES files(id) ON DELETE CASCADE,
+                size         INTEGER NOT NULL,
+                mtime        INTEGER NOT NULL,
+                content_hash TEXT NOT NULL
+            );
+            CREATE INDEX idx_file_hashes_hash ON file_hashes(content_hash);
+        ",
+    },
+    Migration {
+        version: 2,
+        description: "XMP sidecar import stamps",
+        sql: "
+            CREATE TABLE xmp_sidecars (
+                file_id TEXT PRIMARY KEY REFERENCES files(id) ON DELETE CASCADE,
+                mtime   INTEGER NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 3,
+        description: "embedded metadata import markers",
+        sql: "
+            CREATE TABLE embedded_metadata (
+                file_id TEXT PRIMARY KEY REFERENCES files(id) ON DELETE CASCADE,
+                read_at INTEGER NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 4,
+        description: "undo/redo journal",
+        // Triggers store the statement that reverts each row change, but only while a
+        // journaled write has switched recording on; background scans are not journaled
+        sql: "
+            CREATE TABLE journal (
+                id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                description TEXT NOT NULL,
+                created_at  INTEGER NOT NULL,
+                undone      INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE journal_statements (
+                id        INTEGER PRIMARY KEY AUTOINCREMENT,
+                entry_id  INTEGER NOT NULL REFERENCES journal(id) ON DELETE CASCADE,
+                statement TEXT NOT NULL
+            );
+            CREATE INDEX idx_journal_statements_entry ON journal_statements(entry_id);
+            CREATE TABLE journal_state (
+                recording INTEGER NOT NULL,
+                entry_id  INTEGER
+            );
+            INSERT INTO journal_state (recording, entry_id) VALUES (0, NULL);
+            CREATE TRIGGER journal_files_update AFTER UPDATE ON files
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'UPDATE files SET id = ' || quote(old.id) || ', path = ' || quote(old.path) || ', added_at = ' || quote(old.added_at) || ' WHERE id = ' || quote(new.id) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_files_delete AFTER DELETE ON files
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'INSERT OR IGNORE INTO files (id, path, added_at) VALUES (' || quote(old.id) || ', ' || quote(old.path) || ', ' || quote(old.added_at) || ')' FROM journal_state;
+            END;
+            CREATE TRIGGER journal_tags_insert AFTER INSERT ON tags
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'DELETE FROM tags WHERE id = ' || quote(new.id) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_tags_update AFTER UPDATE ON tags
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'UPDATE tags SET id = ' || quote(old.id) || ', name = ' || quote(old.name) || ' WHERE id = ' || quote(new.id) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_tags_delete AFTER DELETE ON tags
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'INSERT INTO tags (id, name) VALUES (' || quote(old.id) || ', ' || quote(old.name) || ')' FROM journal_state;
+            END;
+            CREATE TRIGGER journal_file_tags_insert AFTER INSERT ON file_tags
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'DELETE FROM file_tags WHERE file_id = ' || quote(new.file_id) || ' AND tag_id = ' || quote(new.tag_id) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_file_tags_delete AFTER DELETE ON file_tags
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'INSERT OR IGNORE INTO file_tags (file_id, tag_id) VALUES (' || quote(old.file_id) || ', ' || quote(old.tag_id) || ')' FROM journal_state;
+            END;
+            CREATE TRIGGER journal_tag_parents_insert AFTER INSERT ON tag_parents
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'DELETE FROM tag_parents WHERE tag_id = ' || quote(new.tag_id) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_tag_parents_update AFTER UPDATE ON tag_parents
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'UPDATE tag_parents SET tag_id = ' || quote(old.tag_id) || ', parent_id = ' || quote(old.parent_id) || ' WHERE tag_id = ' || quote(new.tag_id) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_tag_parents_delete AFTER DELETE ON tag_parents
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'INSERT OR IGNORE INTO tag_parents (tag_id, parent_id) VALUES (' || quote(old.tag_id) || ', ' || quote(old.parent_id) || ')' FROM journal_state;
+            END;
+            CREATE TRIGGER journal_tag_aliases_insert AFTER INSERT ON tag_aliases
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'DELETE FROM tag_aliases WHERE alias = ' || quote(new.alias) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_tag_aliases_update AFTER UPDATE ON tag_aliases
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'UPDATE tag_aliases SET alias = ' || quote(old.alias) || ', tag_id = ' || quote(old.tag_id) || ' WHERE alias = ' || quote(new.alias) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_tag_aliases_delete AFTER DELETE ON tag_aliases
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'INSERT OR IGNORE INTO tag_aliases (alias, tag_id) VALUES (' || quote(old.alias) || ', ' || quote(old.tag_id) || ')' FROM journal_state;
+            END;
+            CREATE TRIGGER journal_tag_implications_insert AFTER INSERT ON tag_implications
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'DELETE FROM tag_implications WHERE tag_id = ' || quote(new.tag_id) || ' AND implied_tag_id = ' || quote(new.implied_tag_id) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_tag_implications_update AFTER UPDATE ON tag_implications
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'UPDATE tag_implications SET tag_id = ' || quote(old.tag_id) || ', implied_tag_id = ' || quote(old.implied_tag_id) || ' WHERE tag_id = ' || quote(new.tag_id) || ' AND implied_tag_id = ' || quote(new.implied_tag_id) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_tag_implications_delete AFTER DELETE ON tag_implications
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'INSERT OR IGNORE INTO tag_implications (tag_id, implied_tag_id) VALUES (' || quote(old.tag_id) || ', ' || quote(old.implied_tag_id) || ')' FROM journal_state;
+            END;
+        ",
+    },
+    Migration {
+        version: 5,
+        description: "saved searches and albums",
+        sql: "
+            CREATE TABLE collections (
+                id         INTEGER PRIMARY KEY AUTOINCREMENT,
+                name       TEXT NOT NULL UNIQUE,
+                query      TEXT,
+                created_at INTEGER NOT NULL
+            );
+            CREATE TABLE album_files (
+                collection_id INTEGER NOT NULL REFERENCES collections(id) ON DELETE CASCADE,
+                file_id       TEXT NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+                position      INTEGER NOT NULL,
+                PRIMARY KEY (collection_id, file_id)
+            );
+            CREATE INDEX idx_album_files_file ON album_files(file_id);
+            CREATE TRIGGER journal_collections_insert AFTER INSERT ON collections
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'DELETE FROM collections WHERE id = ' || quote(new.id) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_collections_update AFTER UPDATE ON collections
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'UPDATE collections SET id = ' || quote(old.id) || ', name = ' || quote(old.name) || ', query = ' || quote(old.query) || ', created_at = ' || quote(old.created_at) || ' WHERE id = ' || quote(new.id) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_collections_delete AFTER DELETE ON collections
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'INSERT OR IGNORE INTO collections (id, name, query, created_at) VALUES (' || quote(old.id) || ', ' || quote(old.name) || ', ' || quote(old.query) || ', ' || quote(old.created_at) || ')' FROM journal_state;
+            END;
+            CREATE TRIGGER journal_album_files_insert AFTER INSERT ON album_files
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'DELETE FROM album_files WHERE collection_id = ' || quote(new.collection_id) || ' AND file_id = ' || quote(new.file_id) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_album_files_update AFTER UPDATE ON album_files
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'UPDATE album_files SET collection_id = ' || quote(old.collection_id) || ', file_id = ' || quote(old.file_id) || ', position = ' || quote(old.position) || ' WHERE collection_id = ' || quote(new.collection_id) || ' AND file_id = ' || quote(new.file_id) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_album_files_delete AFTER DELETE ON album_files
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'INSERT OR IGNORE INTO album_files (collection_id, file_id, position) VALUES (' || quote(old.collection_id) || ', ' || quote(old.file_id) || ', ' || quote(old.position) || ')' FROM journal_state;
+            END;
+        ",
+    },
+    Migration {
+        version: 6,
+        description: "perceptual image hashes",
+        // band0-7 are the bytes of phash, indexed so near matches can be looked up exactly
+        sql: "
+            CREATE TABLE perceptual_hashes (
+                file_id TEXT PRIMARY KEY REFERENCES files(id) ON DELETE CASCADE,
+                size    INTEGER NOT NULL,
+                mtime   INTEGER NOT NULL,
+                dhash   INTEGER NOT NULL,
+                phash   INTEGER NOT NULL,
+                band0   INTEGER NOT NULL,
+                band1   INTEGER NOT NULL,
+                band2   INTEGER NOT NULL,
+                band3   INTEGER NOT NULL,
+                band4   INTEGER NOT NULL,
+                band5   INTEGER NOT NULL,
+                band6   INTEGER NOT NULL,
+                band7   INTEGER NOT NULL
+            );
+            CREATE INDEX idx_perceptual_hashes_band0 ON perceptual_hashes(band0);
+            CREATE INDEX idx_perceptual_hashes_band1 ON perceptual_hashes(band1);
+            CREATE INDEX idx_perceptual_hashes_band2 ON perceptual_hashes(band2);
+            CREATE INDEX idx_perceptual_hashes_band3 ON perceptual_hashes(band3);
+            CREATE INDEX idx_perceptual_hashes_band4 ON perceptual_hashes(band4);
+            CREATE INDEX idx_perceptual_hashes_band5 ON perceptual_hashes(band5);
+            CREATE INDEX idx_perceptual_hashes_band6 ON perceptual_hashes(band6);
+            CREATE INDEX idx_perceptual_hashes_band7 ON perceptual_hashes(band7);
+        ",
+    },
+    Migration {
+        version: 7,
+        description: "ratings, favorites and color labels",
+        // A file without a row has no rating, is not a favorite and has no label
+        sql: "
+            CREATE TABLE file_attributes (
+                file_id     TEXT PRIMARY KEY REFERENCES files(id) ON DELETE CASCADE,
+                rating      INTEGER NOT NULL DEFAULT 0 CHECK (rating BETWEEN 0 AND 5),
+                favorite    INTEGER NOT NULL DEFAULT 0,
+                color_label TEXT
+            );
+            CREATE INDEX idx_file_attributes_rating ON file_attributes(rating);
+            CREATE TRIGGER journal_file_attributes_insert AFTER INSERT ON file_attributes
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'DELETE FROM file_attributes WHERE file_id = ' || quote(new.file_id) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_file_attributes_update AFTER UPDATE ON file_attributes
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'UPDATE file_attributes SET file_id = ' || quote(old.file_id) || ', rating = ' || quote(old.rating) || ', favorite = ' || quote(old.favorite) || ', color_label = ' || quote(old.color_label) || ' WHERE file_id = ' || quote(new.file_id) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_file_attributes_delete AFTER DELETE ON file_attributes
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'INSERT OR IGNORE INTO file_attributes (file_id, rating, favorite, color_label) VALUES (' || quote(old.file_id) || ', ' || quote(old.rating) || ', ' || quote(old.favorite) || ', ' || quote(old.color_label) || ')' FROM journal_state;
+            END;
+        ",
+    },
+    Migration {
+        version: 8,
+        description: "file notes with full-text search",
+        // file_notes_fts is kept in step by triggers, so undo replaying file_notes updates it too
+        sql: "
+            CREATE TABLE file_notes (
+                file_id    TEXT PRIMARY KEY REFERENCES files(id) ON DELETE CASCADE,
+                body       TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            );
+            CREATE VIRTUAL TABLE file_notes_fts USING fts5(file_id UNINDEXED, body);
+            CREATE TRIGGER file_notes_fts_insert AFTER INSERT ON file_notes BEGIN
+                INSERT INTO file_notes_fts (file_id, body) VALUES (new.file_id, new.body);
+            END;
+            CREATE TRIGGER file_notes_fts_update AFTER UPDATE ON file_notes BEGIN
+                DELETE FROM file_notes_fts WHERE file_id = old.file_id;
+                INSERT INTO file_notes_fts (file_id, body) VALUES (new.file_id, new.body);
+            END;
+            CREATE TRIGGER file_notes_fts_delete AFTER DELETE ON file_notes BEGIN
+                DELETE FROM file_notes_fts WHERE file_id = old.file_id;
+            END;
+            CREATE TRIGGER journal_file_notes_insert AFTER INSERT ON file_notes
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'DELETE FROM file_notes WHERE file_id = ' || quote(new.file_id) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_file_notes_update AFTER UPDATE ON file_notes
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'UPDATE file_notes SET file_id = ' || quote(old.file_id) || ', body = ' || quote(old.body) || ', updated_at = ' || quote(old.updated_at) || ' WHERE file_id = ' || quote(new.file_id) FROM journal_state;
+            END;
+            CREATE TRIGGER journal_file_notes_delete AFTER DELETE ON file_notes
+            WHEN (SELECT recording FROM journal_state) BEGIN
+                INSERT INTO journal_statements (entry_id, statement)
+                SELECT entry_id, 'INSERT OR IGNORE INTO file_notes (file_id, body, updated_at) VALUES (' || quote(old.file_id) || ', ' || quote(old.body) || ', ' || quote(old.updated_at) || ')' FROM journal_state;
+            END;
+        ",
+    },
+];
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Sqlite(rusqlite::Error),
+    /// The database was last written by a taggerrs with a newer schema.
+    NewerSchema { found: i64, supported: i64 },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sqlite(e) => write!(f, "{}", e),
+            Self::NewerSchema { found, supported } => write!(
+                f,
+                "This tag database was created by a newer version of taggerrs (schema version {}, \
+                 this build supports up to {}). Update taggerrs to open it; the database was left untouched.",
+                found, supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MigrationError {}
+
+impl From<rusqlite::Error> for MigrationError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Sqlite(e)
+    }
+}
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+/// The schema version of the database, 0 for one taggerrs has not written yet. Only reads.
+fn current_version(conn: &Connection) -> rusqlite::Result<i64> {
+    let tracked: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_migrations')",
+        [],
+        |row| row.get(0),
+    )?;
+    if !tracked {
+        return Ok(0);
+    }
+    conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_migrations", [], |row| row.get(0))
+}
+
+/// Refuses databases written by a newer taggerrs, returning the version of any other.
+/// Only reads, so it can run before anything else changes the file.
+pub fn check_version(conn: &Connection) -> Result<i64, MigrationError> {
+    let found = current_version(conn)?;
+    if found > latest_version() {
+        return Err(MigrationError::NewerSchema { found, supported: latest_version() });
+    }
+    Ok(found)
+}
+
+/// Copies the database next to itself before a migration touches it.
+fn backup(conn: &Connection, from_version: i64) -> rusqlite::Result<()> {
+    let Some(path) = conn.path().filter(|p| !p.is_empty()) else {
+        return Ok(());
+    };
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let backup_path = format!("{}.v{}-{}.bak", path, from_version, timestamp);
+    conn.execute("VACUUM INTO ?1", [backup_path])?;
+    Ok(())
+}
+
+/// Brings the schema up to [`latest_version`], backing up before each pending migration.
+pub fn migrate(conn: &mut Connection) -> Result<(), MigrationError> {
+    let found = check_version(conn)?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+             version     INTEGER PRIMARY KEY,
+             description TEXT NOT NULL,
+             applied_at  INTEGER NOT NULL
+         );",
+    )?;
+
+    let mut version = found;
+    for migration in MIGRATIONS.iter().filter(|m| m.version > found) {
+        // A brand new database has nothing worth keeping
+        if found > 0 {
+            backup(conn, version)?;
+        }
+
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration.sql)?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, description, applied_at) VALUES (?1, ?2, strftime('%s', 'now'))",
+            params![migration.version, migration.description],
+        )?;
+        tx.commit()?;
+        version = migration.version;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every table, index and trigger definition, to compare schemas.
+    fn schema(conn: &Connection) -> Vec<String> {
+        let mut stmt = conn
+            .prepare("SELECT sql FROM sqlite_master WHERE sql IS NOT NULL AND name != 'schema_migrations' ORDER BY name")
+            .unwrap();
+        stmt.query_map([], |row| row.get(0)).unwrap().map(Result::unwrap).collect()
+    }
+
+    /// Brings an empty database to `version` the way an older taggerrs would have.
+    fn migrate_to(conn: &mut Connection, version: i64) {
+        let mut older: Vec<&Migration> = MIGRATIONS.iter().filter(|m| m.version <= version).collect();
+        older.sort_by_key(|m| m.version);
+        conn.execute_batch(
+            "CREATE TABLE schema_migrations (version INTEGER PRIMARY KEY, description TEXT NOT NULL, applied_at INTEGER NOT NULL);",
+        )
+        .unwrap();
+        for migration in older {
+            conn.execute_batch(migration.sql).unwrap();
+            conn.execute(
+                "INSERT INTO schema_migrations (version, description, applied_at) VALUES (?1, ?2, 0)",
+                params![migration.version, migration.description],
+            )
+            .unwrap();
+        }
+    }
+
+    /// A directory of its own under the system temp dir, removed when dropped.
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("taggerrs-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn versions_count_up_from_one() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, index as i64 + 1);
+        }
+    }
+
+    #[test]
+    fn every_migration_runs_from_empty() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+        // Running again finds nothing left to do
+        migrate(&mut conn).unwrap();
+        let applied: i64 = conn.query_row("SELECT COUNT(*) FROM schema_migrations", [], |row| row.get(0)).unwrap();
+        assert_eq!(applied, latest_version());
+    }
+
+    #[test]
+    fn every_intermediate_version_upgrades_to_the_same_schema() {
+        let mut fresh = Connection::open_in_memory().unwrap();
+        migrate(&mut fresh).unwrap();
+        for version in 1..latest_version() {
+            let mut conn = Connection::open_in_memory().unwrap();
+            migrate_to(&mut conn, version);
+            assert_eq!(check_version(&conn).unwrap(), version);
+            migrate(&mut conn).unwrap();
+            assert_eq!(current_version(&conn).unwrap(), latest_version(), "upgrading from {}", version);
+            assert_eq!(schema(&conn), schema(&fresh), "upgrading from {}", version);
+        }
+    }
+
+    #[test]
+    fn newer_schemas_are_refused_untouched() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate_to(&mut conn, 1);
+        let newer = latest_version() + 1;
+        conn.execute(
+            "INSERT INTO schema_migrations (version, description, applied_at) VALUES (?1, 'from the future', 0)",
+            [newer],
+        )
+        .unwrap();
+        let before = schema(&conn);
+
+        assert!(matches!(
+            migrate(&mut conn),
+            Err(MigrationError::NewerSchema { found, supported }) if found == newer && supported == latest_version()
+        ));
+        assert_eq!(schema(&conn), before);
+    }
+
+    #[test]
+    fn each_pending_migration_is_backed_up_first() {
+        let dir = TempDir::new("migration-backups");
+        let path = dir.0.join("tags.db");
+        {
+            let mut conn = Connection::open(&path).unwrap();
+            migrate_to(&mut conn, 2);
+            conn.execute("INSERT INTO tags (name) VALUES ('kept')", []).unwrap();
+            migrate(&mut conn).unwrap();
+        }
+
+        let mut backups: Vec<String> = std::fs::read_dir(&dir.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".bak"))
+            .collect();
+        backups.sort();
+        let expected: Vec<i64> = (2..latest_version()).collect();
+        assert_eq!(backups.len(), expected.len());
+        for (backup, version) in backups.iter().zip(&expected) {
+            assert!(backup.starts_with(&format!("tags.db.v{}-", version)), "{}", backup);
+        }
+
+        let first = Connection::open(dir.0.join(&backups[0])).unwrap();
+        assert_eq!(current_version(&first).unwrap(), 2);
+        let kept: String = first.query_row("SELECT name FROM tags", [], |row| row.get(0)).unwrap();
+        assert_eq!(kept, "kept");
+    }
+
+    #[test]
+    fn new_databases_are_not_backed_up() {
+        let dir = TempDir::new("migration-new");
+        let mut conn = Connection::open(dir.0.join("tags.db")).unwrap();
+        migrate(&mut conn).unwrap();
+        let files = std::fs::read_dir(&dir.0).unwrap().count();
+        assert_eq!(files, 1);
+    }
+}
@@ -1,9 +1,159 @@
+use std::sync::Arc;
+use crate::app::library_io::LibraryIoStatus;
+use crate::app::auto_tag::{self, AutoTagRule, AutoTagState, RuleKind};
+use crate::app::tag_store::TagStore;
+
+/// How many files a rule's preview lists before summarizing the rest.
+const PREVIEW_FILE_LIMIT: usize = 50;
+
 pub fn settings_modal (
     ui: &mut egui::Ui,
     gallery_media_box_size: &mut f32,
     gallery_media_boxes_per_row: &mut u32,
+    write_xmp_sidecars: &mut bool,
+    embedded_metadata_namespace: &mut String,
 ) {
     ui.label("Settings");
     ui.add(egui::Slider::new(gallery_media_box_size, 100.0..=500.0).text("Gallery Media Box Size"));
     ui.add(egui::Slider::new(gallery_media_boxes_per_row, 1..=6).text("Boxes per gallery row"));
-}
\ No newline at end of file
+    ui.checkbox(write_xmp_sidecars, "Write gallery tag edits to XMP sidecars")
+        .on_hover_text("Keeps darktable, digiKam and Lightroom in sync; creates <file>.xmp where there is none");
+    ui.horizontal(|ui| {
+        ui.label("Namespace for EXIF camera and date tags");
+        ui.add(egui::TextEdit::singleline(embedded_metadata_namespace).desired_width(80.0));
+    })
+    .response
+    .on_hover_text("Read once when a JPEG or PNG is first indexed; embedded keywords become regular tags");
+}
+
+/// Editor for the auto-tag rules, with a dry-run preview and a run over the whole library.
+pub fn auto_tag_settings(
+    ui: &mut egui::Ui,
+    ctx: &egui::Context,
+    rules: &mut Vec<AutoTagRule>,
+    tag_store: Option<&TagStore>,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    state: &AutoTagState,
+) {
+    ui.label("Auto-tag rules")
+        .on_hover_text("Applied to files on every scan. Regex rules match the full path; $name or $1 in the tags inserts a capture group");
+
+    // Held for the whole editor so a large preview is not copied every frame
+    let mut preview = state.preview.try_lock().ok();
+    let mut changed = false;
+    let mut removed = None;
+    for (index, rule) in rules.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            changed |= ui.checkbox(&mut rule.enabled, "").changed();
+            egui::ComboBox::from_id_salt(("auto_tag_kind", index))
+                .width(80.0)
+                .selected_text(match rule.kind {
+                    RuleKind::Regex => "Regex",
+                    RuleKind::Extension => "Extension",
+                })
+                .show_ui(ui, |ui| {
+                    changed |= ui.selectable_value(&mut rule.kind, RuleKind::Regex, "Regex").changed();
+                    changed |= ui.selectable_value(&mut rule.kind, RuleKind::Extension, "Extension").changed();
+                });
+            let pattern_hint = match rule.kind {
+                RuleKind::Regex => "/clients/(?P<client>[^/]+)/",
+                RuleKind::Extension => "mp4, mkv",
+            };
+            changed |= ui
+                .add(egui::TextEdit::singleline(&mut rule.pattern).hint_text(pattern_hint).desired_width(160.0))
+                .changed();
+            ui.label("→");
+            changed |= ui
+                .add(egui::TextEdit::singleline(&mut rule.tags).hint_text("client:$client, video").desired_width(120.0))
+                .changed();
+            if ui.button("X").on_hover_text("Delete rule").clicked() {
+                removed = Some(index);
+            }
+        });
+        if let Some(error) = rule.error() {
+            ui.colored_label(ui.visuals().error_fg_color, error);
+        }
+
+        if let Some(Some(Ok(preview))) = preview.as_deref()
+            && let Some(files) = preview.get(index)
+            && rule.enabled
+            && rule.error().is_none()
+        {
+            egui::CollapsingHeader::new(format!("Would tag {} files", files.len()))
+                .id_salt(("auto_tag_preview", index))
+                .show(ui, |ui| {
+                    for (path, tags) in files.iter().take(PREVIEW_FILE_LIMIT) {
+                        ui.label(format!("{} → {}", path, tags.join(", ")));
+                    }
+                    if files.len() > PREVIEW_FILE_LIMIT {
+                        ui.weak(format!("…and {} more", files.len() - PREVIEW_FILE_LIMIT));
+                    }
+                });
+        }
+    }
+    if let Some(index) = removed {
+        rules.remove(index);
+        changed = true;
+    }
+    // A preview of rules that have since been edited would be misleading
+    if changed && let Some(preview) = preview.as_mut() {
+        **preview = None;
+    }
+
+    ui.horizontal(|ui| {
+        if ui.button("Add rule").clicked() {
+            rules.push(AutoTagRule::default());
+        }
+        ui.add_enabled_ui(tag_store.is_some() && !rules.is_empty(), |ui| {
+            if ui.button("Preview").on_hover_text("Show which library files each rule would tag").clicked()
+                && let Some(store) = tag_store
+            {
+                auto_tag::spawn_preview(ctx, store, runtime, state, rules.clone());
+            }
+            if ui.button("Apply to library").on_hover_text("Run the rules on every file in the library now").clicked()
+                && let Some(store) = tag_store
+            {
+                auto_tag::spawn_apply_to_library(ctx, store, runtime, state, rules.clone());
+            }
+        });
+    });
+    if let Some(Some(Err(error))) = preview.as_deref() {
+        ui.colored_label(ui.visuals().error_fg_color, format!("Preview failed: {}", error));
+    }
+    if let Some(status) = state.status.try_lock().ok().and_then(|s| s.clone()) {
+        ui.weak(status);
+    }
+}
+
+/// Progress and outcome of a library export or import. Returns true once the user closes it.
+pub fn library_io_report(ui: &mut egui::Ui, status: &LibraryIoStatus) -> bool {
+    match status {
+        LibraryIoStatus::Running(message) => {
+            ui.horizontal(|ui| {
+                ui.spinner();
+                ui.label(message);
+            });
+            false
+        }
+        LibraryIoStatus::Finished { message, unmatched } => {
+            ui.label(message);
+            if !unmatched.is_empty() {
+                ui.add_space(8.0);
+                ui.label(format!(
+                    "{} entries matched no file on disk. They are kept and will be re-linked if a file with the same content is scanned:",
+                    unmatched.len()
+                ));
+                egui::ScrollArea::vertical().max_height(200.0).show(ui, |ui| {
+                    for path in unmatched {
+                        ui.monospace(path);
+                    }
+                });
+            }
+            ui.button("Close").clicked()
+        }
+        LibraryIoStatus::Failed(error) => {
+            ui.colored_label(ui.visuals().error_fg_color, error);
+            ui.button("Close").clicked()
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::sync::Mutex;
+use crate::app::directory_scan;
+use crate::app::file_identity;
+use crate::app::tag_store::{self, Cached, TagStore};
+
+/// How many hashes are written to the store per transaction.
+const HASH_BATCH_SIZE: usize = 32;
+
+/// Largest pHash distance, in bits out of 64, at which two images count as similar.
+/// The hash is indexed as eight 8-bit bands, so any two hashes this close share a band.
+pub const PHASH_THRESHOLD: u32 = 7;
+
+/// dHash has to roughly agree as well, which weeds out images that only share a layout.
+pub const DHASH_THRESHOLD: u32 = 16;
+
+/// Side of the downscaled image the pHash DCT runs on.
+const PHASH_SIZE: usize = 32;
+
+/// Difference and DCT hashes of a decoded image.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageHashes {
+    pub dhash: u64,
+    pub phash: u64,
+}
+
+impl ImageHashes {
+    fn distance(&self, other: &ImageHashes) -> Option<u32> {
+        let phash = (self.phash ^ other.phash).count_ones();
+        let dhash = (self.dhash ^ other.dhash).count_ones();
+        (phash <= PHASH_THRESHOLD && dhash <= DHASH_THRESHOLD).then_some(phash)
+    }
+
+    fn bands(&self) -> [i64; 8] {
+        std::array::from_fn(|i| ((self.phash >> (8 * i)) & 0xFF) as i64)
+    }
+}
+
+/// Whether `path` is an image format this build can decode.
+pub fn has_perceptual_hash(path: &str) -> bool {
+    image::ImageFormat::from_path(path).map(|format| format.reading_enabled()).unwrap_or(false)
+}
+
+/// Decodes the image and computes both hashes. Both work on a tiny grayscale copy, so
+/// resizing and recompression barely change them.
+pub fn compute_hashes(path: &str) -> image::ImageResult<ImageHashes> {
+    let image = image::open(path)?;
+
+    // dHash: whether each pixel is brighter than its right neighbour, on a 9x8 copy
+    let small = image.thumbnail_exact(9, 8).to_luma8();
+    let mut dhash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            dhash = (dhash << 1) | (small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0]) as u64;
+        }
+    }
+
+    // pHash: the lowest 8x8 frequencies of a 32x32 DCT, each above or below their median
+    let small = image.thumbnail_exact(PHASH_SIZE as u32, PHASH_SIZE as u32).to_luma8();
+    let pixels: Vec<f64> = small.pixels().map(|pixel| pixel[0] as f64).collect();
+    let cosines: Vec<[f64; PHASH_SIZE]> = (0..8)
+        .map(|u| {
+            std::array::from_fn(|x| {
+                ((2 * x + 1) as f64 * u as f64 * std::f64::consts::PI / (2 * PHASH_SIZE) as f64).cos()
+            })
+        })
+        .collect();
+    let mut rows = [[0.0; 8]; PHASH_SIZE];
+    for (y, row) in rows.iter_mut().enumerate() {
+        for (u, value) in row.iter_mut().enumerate() {
+            *value = (0..PHASH_SIZE).map(|x| pixels[y * PHASH_SIZE + x] * cosines[u][x]).sum();
+        }
+    }
+    let mut coefficients = Vec::with_capacity(64);
+    for cosine in &cosines {
+        coefficients.extend((0..8).map(|u| (0..PHASH_SIZE).map(|y| rows[y][u] * cosine[y]).sum::<f64>()));
+    }
+    // The first coefficient is the average brightness and would skew the median
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    let phash = coefficients.iter().fold(0u64, |hash, &c| (hash << 1) | (c > median) as u64);
+
+    Ok(ImageHashes { dhash, phash })
+}
+
+fn read_hashes(row: &rusqlite::Row, dhash: usize, phash: usize) -> rusqlite::Result<ImageHashes> {
+    Ok(ImageHashes { dhash: row.get::<_, i64>(dhash)? as u64, phash: row.get::<_, i64>(phash)? as u64 })
+}
+
+/// The `(size, mtime)` each path's perceptual hashes were computed at.
+fn hash_stamps(conn: &Connection, paths: &[String]) -> rusqlite::Result<HashMap<String, (i64, i64)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT p.size, p.mtime FROM perceptual_hashes p JOIN files f ON f.id = p.file_id WHERE f.path = ?1",
+    )?;
+    let mut stamps = HashMap::new();
+    for path in paths {
+        if let Some(stamp) = stmt.query_row([path], |row| Ok((row.get(0)?, row.get(1)?))).optional()? {
+            stamps.insert(path.clone(), stamp);
+        }
+    }
+    Ok(stamps)
+}
+
+fn record_hashes(conn: &Connection, path: &str, (size, mtime): (i64, i64), hashes: &ImageHashes) -> rusqlite::Result<()> {
+    let file_id = tag_store::ensure_file(conn, path)?;
+    let [b0, b1, b2, b3, b4, b5, b6, b7] = hashes.bands();
+    conn.execute(
+        "INSERT INTO perceptual_hashes (file_id, size, mtime, dhash, phash, band0, band1, band2, band3, band4, band5, band6, band7)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)
+         ON CONFLICT(file_id) DO UPDATE SET
+             size = excluded.size, mtime = excluded.mtime, dhash = excluded.dhash, phash = excluded.phash,
+             band0 = excluded.band0, band1 = excluded.band1, band2 = excluded.band2, band3 = excluded.band3,
+             band4 = excluded.band4, band5 = excluded.band5, band6 = excluded.band6, band7 = excluded.band7",
+        params![file_id, size, mtime, hashes.dhash as i64, hashes.phash as i64, b0, b1, b2, b3, b4, b5, b6, b7],
+    )?;
+    Ok(())
+}
+
+/// Computes perceptual hashes of the images among `paths` in the background, skipping
+/// files whose size and mtime match the stored hashes. Returns why files could not be stored.
+pub async fn hash_images(tag_store: TagStore, paths: Vec<String>) -> Vec<String> {
+    let paths: Vec<String> = paths.into_iter().filter(|path| has_perceptual_hash(path)).collect();
+    let paths_clone = paths.clone();
+    let stamps = match tag_store.read(move |conn| hash_stamps(conn, &paths_clone)).await {
+        Ok(stamps) => stamps,
+        Err(e) => return directory_scan::failed_for_each(&paths, e),
+    };
+
+    let mut problems = Vec::new();
+    let mut batch = Vec::with_capacity(HASH_BATCH_SIZE);
+    for path in paths {
+        let Ok(stamp) = file_identity::file_stamp(&path).await else {
+            continue;
+        };
+        if stamps.get(&path) == Some(&stamp) {
+            continue;
+        }
+        let path_clone = path.clone();
+        let Ok(Ok(hashes)) = tokio::task::spawn_blocking(move || compute_hashes(&path_clone)).await else {
+            continue;
+        };
+        batch.push((path, stamp, hashes));
+
+        if batch.len() >= HASH_BATCH_SIZE {
+            problems.extend(write_hashes(&tag_store, std::mem::take(&mut batch)).await);
+        }
+    }
+    if !batch.is_empty() {
+        problems.extend(write_hashes(&tag_store, batch).await);
+    }
+    problems
+}
+
+/// Stores a batch in one transaction, each file in its own savepoint so one file's hashes
+/// failing does not lose the others'.
+async fn write_hashes(tag_store: &TagStore, batch: Vec<(String, (i64, i64), ImageHashes)>) -> Vec<String> {
+    let paths: Vec<String> = batch.iter().map(|(path, _, _)| path.clone()).collect();
+    let result = tag_store
+        .write(move |tx| {
+            let mut problems = Vec::new();
+            for (path, stamp, hashes) in &batch {
+                if let Err(e) = tag_store::in_savepoint(tx, || record_hashes(tx, path, *stamp, hashes)) {
+                    problems.push(format!("{}: {}", path, e));
+                }
+            }
+            Ok(problems)
+        })
+        .await;
+    result.unwrap_or_else(|e| directory_scan::failed_for_each(&paths, e))
+}
+
+/// Images similar to a reference image, with their pHash distance to it.
+pub type SimilarImages = Vec<(String, u32)>;
+
+/// Images that look like `path`, closest first, with their pHash distance, including records
+/// of files that are gone; see [`drop_missing_matches`]. Returns `None` while `path` has not
+/// been hashed yet.
+pub fn find_similar(conn: &Connection, path: &str) -> rusqlite::Result<Option<SimilarImages>> {
+    let reference = conn
+        .query_row(
+            "SELECT p.dhash, p.phash FROM perceptual_hashes p JOIN files f ON f.id = p.file_id WHERE f.path = ?1",
+            [path],
+            |row| read_hashes(row, 0, 1),
+        )
+        .optional()?;
+    let Some(reference) = reference else {
+        return Ok(None);
+    };
+
+    let [b0, b1, b2, b3, b4, b5, b6, b7] = reference.bands();
+    let mut stmt = conn.prepare_cached(
+        "SELECT f.path, p.dhash, p.phash FROM perceptual_hashes p JOIN files f ON f.id = p.file_id
+         WHERE (p.band0 = ?1 OR p.band1 = ?2 OR p.band2 = ?3 OR p.band3 = ?4
+             OR p.band4 = ?5 OR p.band5 = ?6 OR p.band6 = ?7 OR p.band7 = ?8)
+           AND f.path != ?9",
+    )?;
+    let mut matches: SimilarImages = stmt
+        .query_map(params![b0, b1, b2, b3, b4, b5, b6, b7, path], |row| {
+            Ok((row.get::<_, String>(0)?, read_hashes(row, 1, 2)?))
+        })?
+        .filter_map(|row| {
+            let (path, hashes) = row.ok()?;
+            let distance = reference.distance(&hashes)?;
+            Some((path, distance))
+        })
+        .collect();
+    matches.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+    Ok(Some(matches))
+}
+
+/// Leaves out matches whose file is no longer on disk. Run once the connection is released.
+pub fn drop_missing_matches(matches: Option<SimilarImages>) -> Option<SimilarImages> {
+    matches.map(|matches| matches.into_iter().filter(|(path, _)| Path::new(path).exists()).collect())
+}
+
+/// The perceptual hashes of the images under any of `roots`, sorted by path.
+pub fn hashed_images(conn: &Connection, roots: &[String]) -> rusqlite::Result<Vec<(String, ImageHashes)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT f.path, p.dhash, p.phash FROM perceptual_hashes p JOIN files f ON f.id = p.file_id ORDER BY f.path",
+    )?;
+    let images = stmt
+        .query_map([], |row| Ok((row.get::<_, String>(0)?, read_hashes(row, 1, 2)?)))?
+        .filter_map(Result::ok)
+        .filter(|(path, _)| roots.iter().any(|root| Path::new(path).starts_with(root)))
+        .collect();
+    Ok(images)
+}
+
+/// Groups the `images` that still exist by similarity, largest group first. Images are grouped
+/// when they are similar to any member, so a group can drift between its two ends. Checks the
+/// file system, so it runs once the connection is released.
+pub fn similar_groups(images: Vec<(String, ImageHashes)>) -> Vec<Vec<String>> {
+    let images: Vec<(String, ImageHashes)> = images.into_iter().filter(|(path, _)| Path::new(path).exists()).collect();
+
+    // Only images sharing a band can be similar, the same lookup the band indexes serve
+    let mut buckets: HashMap<(usize, i64), Vec<usize>> = HashMap::new();
+    for (index, (_, hashes)) in images.iter().enumerate() {
+        for (band, value) in hashes.bands().into_iter().enumerate() {
+            buckets.entry((band, value)).or_default().push(index);
+        }
+    }
+
+    let mut parents: Vec<usize> = (0..images.len()).collect();
+    fn root(parents: &mut [usize], mut index: usize) -> usize {
+        while parents[index] != index {
+            parents[index] = parents[parents[index]];
+            index = parents[index];
+        }
+        index
+    }
+    for members in buckets.values() {
+        for (i, &a) in members.iter().enumerate() {
+            for &b in &members[i + 1..] {
+                if images[a].1.distance(&images[b].1).is_some() {
+                    let (root_a, root_b) = (root(&mut parents, a), root(&mut parents, b));
+                    parents[root_a] = root_b;
+                }
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<String>> = HashMap::new();
+    for (index, (path, _)) in images.iter().enumerate() {
+        let group = root(&mut parents, index);
+        groups.entry(group).or_default().push(path.clone());
+    }
+    let mut groups: Vec<Vec<String>> = groups.into_values().filter(|group| group.len() > 1).collect();
+    groups.sort_by(|a, b| b.len().cmp(&a.len()).then(a.cmp(b)));
+    groups
+}
+
+/// State of the Similar images view.
+#[derive(Default)]
+pub struct SimilarState {
+    pub open: bool,
+    pub groups: Arc<Mutex<Cached<Vec<Vec<String>>>>>,
+    /// The library roots the groups were found in; a change reloads them.
+    pub roots: Vec<String>,
+    /// The image "Find similar" was used on; its matches replace the groups.
+    pub reference: Option<String>,
+    pub matches: Arc<Mutex<Cached<Option<SimilarImages>>>>,
+    /// The reference the loaded matches belong to.
+    pub matches_for: Option<String>,
+}
@@ -1,17 +1,47 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use crate::app::DirectoryScanState;
+use crate::app::tag_store::{self, Cached, TagInfo, TagRules, TagStore};
+use crate::app::collections::{self, Collection, CollectionsState};
+use crate::app::journal;
+use crate::app::xmp_sidecar;
+use crate::app::centralpanel_modules::SearchState;
+use crate::app::tag_autocomplete::{self, TagCompletion, TagField};
+use crate::app::directory_scan::{self, ScanSettings};
 use egui_file_dialog::FileDialog;
 
+/// UI state of the Tag Manager tab that lives across frames.
+#[derive(Default)]
+pub struct TagManagerState {
+    pub tags: Arc<Mutex<Cached<Vec<TagInfo>>>>,
+    pub new_tag_input: String,
+    pub renaming: Option<(i64, String)>,
+    pub pending: Option<PendingTagAction>,
+    pub error: Arc<Mutex<Option<String>>>,
+    pub rules: Arc<Mutex<Cached<TagRules>>>,
+    pub alias_input: (String, String),
+    pub implication_input: (String, String),
+    pub status: Arc<Mutex<Option<String>>>,
+}
+
+/// A two-step tag edit waiting for the user to pick the target tag.
+#[derive(Clone)]
+pub enum PendingTagAction {
+    Merge(TagInfo),
+    Reparent(TagInfo),
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn sidebar_paths(
     ui: &mut egui::Ui, 
     ctx: &egui::Context,
     paths: &mut Vec<String>, 
     input_path_usestate: &mut String, 
     currently_active_path: &mut Option<String>, 
-    current_path_filepaths: &mut Option<Vec<String>>,
+    current_path_filepaths: &mut Option<Arc<Vec<String>>>,
     directory_scan_state: &Arc<Mutex<HashMap<String, DirectoryScanState>>>,
+    scan_settings: &mut HashMap<String, ScanSettings>,
     file_dialog: &mut FileDialog,
 ) {
     if ui.button("Open file…").clicked() {
@@ -20,11 +50,9 @@ pub fn sidebar_paths(
     }
     ui.horizontal(|ui| {
         ui.add(egui::TextEdit::singleline(input_path_usestate).desired_width(100.0));
-        if ui.button("+").clicked() {
-            if !input_path_usestate.is_empty() && !paths.contains(input_path_usestate) {
-                paths.push(input_path_usestate.clone());
-                input_path_usestate.clear();
-            }
+        if ui.button("+").clicked() && !input_path_usestate.is_empty() && !paths.contains(input_path_usestate) {
+            paths.push(input_path_usestate.clone());
+            input_path_usestate.clear();
         }
     });
 
@@ -43,8 +71,10 @@ pub fn sidebar_paths(
                         *current_path_filepaths = None;
                         
                         // Reset directory scan state for new path
-                        if let Ok(mut state_map) = directory_scan_state.try_lock() {
-                            state_map.remove(path);
+                        if let Ok(mut state_map) = directory_scan_state.try_lock()
+                            && let Some(state) = state_map.remove(path)
+                        {
+                            state.cancel();
                         }
                     };
                     ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
@@ -53,20 +83,581 @@ pub fn sidebar_paths(
                             *currently_active_path = None;
                             *current_path_filepaths = None;
                         }
+                        let settings = scan_settings.entry(path.clone()).or_default();
+                        let before = settings.clone();
+                        ui.menu_button("⚙", |ui| scan_settings_menu(ui, settings)).response.on_hover_text("Scan settings");
+                        // A path is rescanned with its new settings the next time it shows
+                        if *settings != before {
+                            if let Ok(mut state_map) = directory_scan_state.try_lock()
+                                && let Some(state) = state_map.remove(path)
+                            {
+                                state.cancel();
+                            }
+                            if currently_active_path.as_ref() == Some(path) {
+                                *current_path_filepaths = None;
+                            }
+                        }
                     });
                 });
             })
         });
     }
     paths.retain(|p| !paths_to_remove.contains(p));
+    scan_settings.retain(|p, _| paths.contains(p));
 }
 
-pub fn sidebar_tag_manager(ui: &mut egui::Ui) {
+fn scan_settings_menu(ui: &mut egui::Ui, settings: &mut ScanSettings) {
+    ui.checkbox(&mut settings.recursive, "Include subfolders");
+    ui.add_enabled_ui(settings.recursive, |ui| {
+        ui.horizontal(|ui| {
+            let mut limited = settings.max_depth.is_some();
+            if ui.checkbox(&mut limited, "Max depth").changed() {
+                settings.max_depth = limited.then_some(ScanSettings::default().max_depth.unwrap_or(1));
+            }
+            if let Some(depth) = &mut settings.max_depth {
+                ui.add(egui::DragValue::new(depth).range(1..=64));
+            }
+        });
+        ui.checkbox(&mut settings.follow_symlinks, "Follow symlinked folders");
+    });
+    ui.checkbox(&mut settings.skip_hidden, "Skip hidden files and folders");
+    ui.weak(format!(
+        "Files matching the gitignore patterns of a {} file in a scanned folder are skipped.",
+        directory_scan::IGNORE_FILE_NAME
+    ));
+}
+
+pub fn sidebar_tag_manager(
+    ui: &mut egui::Ui,
+    ctx: &egui::Context,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    state: &mut TagManagerState,
+    completions: &[TagCompletion],
+    write_xmp_sidecars: bool,
+) {
     ui.label("Tag Manager");
-    if ui.button("Option 1").clicked() {
-        // Handle option 1
+
+    tag_store::refresh_cached(ctx, &state.tags, tag_store, runtime, tag_store::list_tags);
+    let tags = state
+        .tags
+        .try_lock()
+        .map(|cached| cached.value.clone())
+        .unwrap_or_default();
+
+    ui.horizontal(|ui| {
+        let response = tag_autocomplete::tag_input(
+            ui,
+            "new_tag",
+            &mut state.new_tag_input,
+            TagField::List,
+            completions,
+            "New tag, or animal > mammal > cat",
+            140.0,
+        );
+        let submitted = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+        if (ui.button("+").clicked() || submitted)
+            && let Some(names) = tag_store::parse_tag_path(&state.new_tag_input)
+        {
+            state.new_tag_input.clear();
+            let description = format!("Create tag {}", names.join(" > "));
+            journal::spawn_journaled_edit(ctx, tag_store, runtime, &state.error, description, move |tx| {
+                tag_store::ensure_tag_path(tx, &names).map(|_| ())
+            });
+        }
+    });
+
+    match state.pending.clone() {
+        Some(PendingTagAction::Merge(source)) => {
+            ui.horizontal(|ui| {
+                ui.label(format!("Merge \"{}\" into…", source.name));
+                if ui.button("Cancel").clicked() {
+                    state.pending = None;
+                }
+            });
+        }
+        Some(PendingTagAction::Reparent(source)) => {
+            ui.horizontal(|ui| {
+                ui.label(format!("Move \"{}\" under…", source.name));
+                if ui.button("Top level").clicked() {
+                    let description = format!("Move tag {} to the top level", source.name);
+                    journal::spawn_journaled_edit(ctx, tag_store, runtime, &state.error, description, move |tx| {
+                        tag_store::set_tag_parent(tx, source.id, None)
+                    });
+                    state.pending = None;
+                }
+                if ui.button("Cancel").clicked() {
+                    state.pending = None;
+                }
+            });
+        }
+        None => {}
+    }
+
+    if let Some(error) = state.error.try_lock().ok().and_then(|e| e.clone()) {
+        ui.colored_label(ui.visuals().error_fg_color, error);
+    }
+
+    egui::CollapsingHeader::new("Aliases & implications")
+        .id_salt("tag_rules")
+        .show(ui, |ui| tag_rules_section(ui, ctx, tag_store, runtime, state, completions));
+
+    ui.separator();
+
+    // Children by parent id; tags without a (known) parent are roots
+    let known_ids: HashSet<i64> = tags.iter().map(|tag| tag.id).collect();
+    let mut children: HashMap<i64, Vec<&TagInfo>> = HashMap::new();
+    let mut roots_by_namespace: BTreeMap<Option<&str>, Vec<&TagInfo>> = BTreeMap::new();
+    for tag in &tags {
+        match tag.parent_id {
+            Some(parent_id) if known_ids.contains(&parent_id) => children.entry(parent_id).or_default().push(tag),
+            _ => roots_by_namespace.entry(tag_store::split_namespace(&tag.name).0).or_default().push(tag),
+        }
+    }
+
+    egui::ScrollArea::vertical().show(ui, |ui| {
+        for (namespace, roots) in &roots_by_namespace {
+            let Some(namespace) = namespace else {
+                continue;
+            };
+            egui::CollapsingHeader::new(format!("{}: ({})", namespace, roots.len()))
+                .id_salt(("tag_namespace", *namespace))
+                .show(ui, |ui| {
+                    for tag in roots {
+                        tag_tree(ui, ctx, tag, &tags, &children, tag_store, runtime, state, completions, write_xmp_sidecars);
+                    }
+                });
+        }
+        // Tags without a namespace are listed after the namespace groups
+        for tag in roots_by_namespace.get(&None).into_iter().flatten() {
+            tag_tree(ui, ctx, tag, &tags, &children, tag_store, runtime, state, completions, write_xmp_sidecars);
+        }
+    });
+}
+
+/// Lists alias and implication rules with inputs for adding new ones.
+fn tag_rules_section(
+    ui: &mut egui::Ui,
+    ctx: &egui::Context,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    state: &mut TagManagerState,
+    completions: &[TagCompletion],
+) {
+    tag_store::refresh_cached(ctx, &state.rules, tag_store, runtime, tag_store::list_tag_rules);
+    let rules = state
+        .rules
+        .try_lock()
+        .map(|cached| cached.value.clone())
+        .unwrap_or_default();
+
+    ui.label("Aliases");
+    for (alias, target) in &rules.aliases {
+        ui.horizontal(|ui| {
+            ui.label(format!("{} → {}", alias, target));
+            if ui.small_button("X").clicked() {
+                let alias = alias.clone();
+                let description = format!("Remove alias {}", alias);
+                journal::spawn_journaled_edit(ctx, tag_store, runtime, &state.error, description, move |tx| {
+                    tag_store::remove_alias(tx, &alias)
+                });
+            }
+        });
     }
-    if ui.button("Option 2").clicked() {
-        // Handle option 2
+    ui.horizontal(|ui| {
+        ui.add(egui::TextEdit::singleline(&mut state.alias_input.0).hint_text("kitty").desired_width(60.0));
+        ui.label("→");
+        tag_autocomplete::tag_input(ui, "alias_target", &mut state.alias_input.1, TagField::Single, completions, "cat", 60.0);
+        if ui.button("Add").clicked()
+            && let (Some(alias), Some(target)) = (
+                tag_store::normalize_tag_name(&state.alias_input.0),
+                tag_store::normalize_tag_name(&state.alias_input.1),
+            )
+        {
+            state.alias_input = Default::default();
+            let description = format!("Add alias {} → {}", alias, target);
+            journal::spawn_journaled_edit(ctx, tag_store, runtime, &state.error, description, move |tx| {
+                tag_store::add_alias(tx, &alias, &target)
+            });
+        }
+    });
+
+    ui.separator();
+    ui.label("Implications");
+    for (tag, implied) in &rules.implications {
+        ui.horizontal(|ui| {
+            ui.label(format!("{} ⇒ {}", tag, implied));
+            if ui.small_button("X").clicked() {
+                let (tag, implied) = (tag.clone(), implied.clone());
+                let description = format!("Remove implication {} ⇒ {}", tag, implied);
+                journal::spawn_journaled_edit(ctx, tag_store, runtime, &state.error, description, move |tx| {
+                    tag_store::remove_implication(tx, &tag, &implied)
+                });
+            }
+        });
+    }
+    ui.horizontal(|ui| {
+        tag_autocomplete::tag_input(ui, "implication_tag", &mut state.implication_input.0, TagField::Single, completions, "cat", 60.0);
+        ui.label("⇒");
+        tag_autocomplete::tag_input(
+            ui,
+            "implication_implied",
+            &mut state.implication_input.1,
+            TagField::Single,
+            completions,
+            "animal",
+            60.0,
+        );
+        if ui.button("Add").clicked()
+            && let (Some(tag), Some(implied)) = (
+                tag_store::normalize_tag_name(&state.implication_input.0),
+                tag_store::normalize_tag_name(&state.implication_input.1),
+            )
+        {
+            state.implication_input = Default::default();
+            // Backfill files that already carry the tag once the rule is stored
+            spawn_recompute_implications(ctx, tag_store, runtime, state, Some((tag, implied)));
+        }
+    });
+
+    if ui.button("Recompute implications").clicked() {
+        spawn_recompute_implications(ctx, tag_store, runtime, state, None);
     }
-}
\ No newline at end of file
+    if let Some(status) = state.status.try_lock().ok().and_then(|s| s.clone()) {
+        ui.weak(status);
+    }
+}
+
+/// Optionally stores a new implication rule, then backfills implied tags across the whole library.
+fn spawn_recompute_implications(
+    ctx: &egui::Context,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    state: &TagManagerState,
+    new_rule: Option<(String, String)>,
+) {
+    let store_clone = tag_store.clone();
+    let error_clone = state.error.clone();
+    let status_clone = state.status.clone();
+    let ctx_clone = ctx.clone();
+    runtime.spawn(async move {
+        if let Some((tag, implied)) = new_rule {
+            let description = format!("Add implication {} ⇒ {}", tag, implied);
+            let added = journal::journaled_edit(&store_clone, &error_clone, description, move |tx| {
+                tag_store::add_implication(tx, &tag, &implied)
+            })
+            .await;
+            if added.is_none() {
+                ctx_clone.request_repaint();
+                return;
+            }
+        }
+
+        *status_clone.lock().await = Some("Recomputing implications…".to_string());
+        ctx_clone.request_repaint();
+        let result = store_clone
+            .write_journaled("Apply implications to the library", |tx| tag_store::apply_implications(tx, None))
+            .await;
+        *status_clone.lock().await = match result {
+            Ok(added) => Some(format!("Implications applied, {} tags added", added)),
+            Err(e) => Some(format!("Recomputing implications failed: {}", e)),
+        };
+        ctx_clone.request_repaint();
+    });
+}
+
+/// Draws a tag row, nesting its child tags below it in a collapsible section.
+#[allow(clippy::too_many_arguments)]
+fn tag_tree(
+    ui: &mut egui::Ui,
+    ctx: &egui::Context,
+    tag: &TagInfo,
+    tags: &[TagInfo],
+    children: &HashMap<i64, Vec<&TagInfo>>,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    state: &mut TagManagerState,
+    completions: &[TagCompletion],
+    write_xmp_sidecars: bool,
+) {
+    let Some(tag_children) = children.get(&tag.id) else {
+        ui.horizontal(|ui| {
+            // Line leaf rows up with the labels of collapsible rows
+            ui.add_space(ui.spacing().indent);
+            tag_row(ui, ctx, tag, tags, tag_store, runtime, state, completions, write_xmp_sidecars);
+        });
+        return;
+    };
+
+    let id = ui.make_persistent_id(("tag_tree", tag.id));
+    egui::collapsing_header::CollapsingState::load_with_default_open(ctx, id, false)
+        .show_header(ui, |ui| tag_row(ui, ctx, tag, tags, tag_store, runtime, state, completions, write_xmp_sidecars))
+        .body(|ui| {
+            for child in tag_children {
+                tag_tree(ui, ctx, child, tags, children, tag_store, runtime, state, completions, write_xmp_sidecars);
+            }
+        });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn tag_row(
+    ui: &mut egui::Ui,
+    ctx: &egui::Context,
+    tag: &TagInfo,
+    tags: &[TagInfo],
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    state: &mut TagManagerState,
+    completions: &[TagCompletion],
+    write_xmp_sidecars: bool,
+) {
+    if let Some((renaming_id, new_name)) = state.renaming.as_mut()
+        && *renaming_id == tag.id
+    {
+        tag_autocomplete::tag_input(ui, ("rename_tag", tag.id), new_name, TagField::Single, completions, "", 100.0);
+        if ui.button("✔").clicked() {
+            match tag_store::normalize_tag_name(new_name) {
+                Some(name) if name == tag.name => {}
+                Some(name) if tags.iter().any(|t| t.name == name) => {
+                    set_error(&state.error, format!("A tag named \"{}\" already exists, merge instead", name));
+                    return;
+                }
+                Some(name) => {
+                    let tag_id = tag.id;
+                    let description = format!("Rename tag {} to {}", tag.name, name);
+                    xmp_sidecar::spawn_journaled_edit_syncing(
+                        ctx,
+                        tag_store,
+                        runtime,
+                        &state.error,
+                        write_xmp_sidecars,
+                        description,
+                        move |tx| tag_store::rename_tag(tx, tag_id, &name),
+                    );
+                }
+                None => return,
+            }
+            state.renaming = None;
+        }
+        if ui.button("✖").clicked() {
+            state.renaming = None;
+        }
+        return;
+    }
+
+    ui.label(tag_store::split_namespace(&tag.name).1).on_hover_text(tag.name.as_str());
+    ui.weak(tag.usage.to_string());
+    ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+        if ui.button("X").on_hover_text("Delete tag").clicked() {
+            let tag_id = tag.id;
+            let description = format!("Delete tag {}", tag.name);
+            xmp_sidecar::spawn_journaled_edit_syncing(
+                ctx,
+                tag_store,
+                runtime,
+                &state.error,
+                write_xmp_sidecars,
+                description,
+                move |tx| tag_store::delete_tag(tx, tag_id),
+            );
+        }
+        if ui.button("✏").on_hover_text("Rename tag").clicked() {
+            state.renaming = Some((tag.id, tag.name.clone()));
+        }
+        match &state.pending {
+            Some(PendingTagAction::Merge(source)) if source.id != tag.id => {
+                if ui.button("Merge here").on_hover_text(format!("Merge \"{}\" into this tag", source.name)).clicked() {
+                    let (source_id, target_id) = (source.id, tag.id);
+                    let description = format!("Merge tag {} into {}", source.name, tag.name);
+                    xmp_sidecar::spawn_journaled_edit_syncing(
+                        ctx,
+                        tag_store,
+                        runtime,
+                        &state.error,
+                        write_xmp_sidecars,
+                        description,
+                        move |tx| tag_store::merge_tags(tx, source_id, target_id),
+                    );
+                    state.pending = None;
+                }
+            }
+            Some(PendingTagAction::Reparent(source)) if source.id != tag.id => {
+                if ui.button("Move here").on_hover_text(format!("Make \"{}\" a child of this tag", source.name)).clicked() {
+                    let (tag_id, parent_id) = (source.id, tag.id);
+                    let description = format!("Move tag {} under {}", source.name, tag.name);
+                    xmp_sidecar::spawn_journaled_edit_syncing(
+                        ctx,
+                        tag_store,
+                        runtime,
+                        &state.error,
+                        write_xmp_sidecars,
+                        description,
+                        move |tx| tag_store::set_tag_parent(tx, tag_id, Some(parent_id)),
+                    );
+                    state.pending = None;
+                }
+            }
+            Some(_) => {}
+            None => {
+                if ui.button("Merge").on_hover_text("Merge into another tag").clicked() {
+                    state.pending = Some(PendingTagAction::Merge(tag.clone()));
+                }
+                if ui.button("Move").on_hover_text("Move under another tag").clicked() {
+                    state.pending = Some(PendingTagAction::Reparent(tag.clone()));
+                }
+            }
+        }
+    });
+}
+
+/// Saved searches and albums. Opening one shows it in the gallery in place of a path.
+pub fn sidebar_collections(
+    ui: &mut egui::Ui,
+    ctx: &egui::Context,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    state: &mut CollectionsState,
+    search: &mut SearchState,
+    currently_active_path: &mut Option<String>,
+) {
+    ui.label("Collections");
+
+    tag_store::refresh_cached(ctx, &state.collections, tag_store, runtime, collections::list_collections);
+    let all = state
+        .collections
+        .try_lock()
+        .map(|cached| cached.value.clone())
+        .unwrap_or_default();
+
+    ui.horizontal(|ui| {
+        ui.add(
+            egui::TextEdit::singleline(&mut state.search_name_input)
+                .hint_text("Name for the current search")
+                .desired_width(140.0),
+        );
+        let name = state.search_name_input.trim().to_string();
+        let can_save = search.query.is_some() && !name.is_empty();
+        if ui.add_enabled(can_save, egui::Button::new("Save search")).clicked() {
+            let query = search.input.trim().to_string();
+            state.search_name_input.clear();
+            let description = format!("Save search {}", name);
+            journal::spawn_journaled_edit(ctx, tag_store, runtime, &state.error, description, move |tx| {
+                collections::create_saved_search(tx, &name, &query)
+            });
+        }
+    });
+    ui.horizontal(|ui| {
+        let response = ui.add(
+            egui::TextEdit::singleline(&mut state.album_name_input)
+                .hint_text("New album")
+                .desired_width(140.0),
+        );
+        let submitted = response.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+        let name = state.album_name_input.trim().to_string();
+        if (ui.button("+").clicked() || submitted) && !name.is_empty() {
+            state.album_name_input.clear();
+            let description = format!("Create album {}", name);
+            journal::spawn_journaled_edit(ctx, tag_store, runtime, &state.error, description, move |tx| {
+                collections::create_album(tx, &name)
+            });
+        }
+    });
+
+    if let Some(error) = state.error.try_lock().ok().and_then(|e| e.clone()) {
+        ui.colored_label(ui.visuals().error_fg_color, error);
+    }
+    ui.separator();
+
+    egui::ScrollArea::vertical().show(ui, |ui| {
+        let (albums, searches): (Vec<&Collection>, Vec<&Collection>) = all.iter().partition(|c| c.is_album());
+        ui.strong("Saved searches");
+        if searches.is_empty() {
+            ui.weak("Type a search above the gallery, name it and save it here.");
+        }
+        for collection in searches {
+            collection_row(ui, ctx, tag_store, runtime, state, search, currently_active_path, collection);
+        }
+        ui.add_space(8.0);
+        ui.strong("Albums");
+        if albums.is_empty() {
+            ui.weak("Create an album, then add selected files from the gallery.");
+        }
+        for collection in albums {
+            collection_row(ui, ctx, tag_store, runtime, state, search, currently_active_path, collection);
+        }
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+fn collection_row(
+    ui: &mut egui::Ui,
+    ctx: &egui::Context,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    state: &mut CollectionsState,
+    search: &mut SearchState,
+    currently_active_path: &mut Option<String>,
+    collection: &Collection,
+) {
+    ui.horizontal(|ui| {
+        if let Some((renaming_id, new_name)) = state.renaming.as_mut()
+            && *renaming_id == collection.id
+        {
+            ui.add(egui::TextEdit::singleline(new_name).desired_width(100.0));
+            if ui.button("✔").clicked() {
+                let name = new_name.trim().to_string();
+                if !name.is_empty() && name != collection.name {
+                    let id = collection.id;
+                    let description = format!("Rename collection {} to {}", collection.name, name);
+                    journal::spawn_journaled_edit(ctx, tag_store, runtime, &state.error, description, move |tx| {
+                        collections::rename_collection(tx, id, &name)
+                    });
+                }
+                state.renaming = None;
+            }
+            if ui.button("✖").clicked() {
+                state.renaming = None;
+            }
+            return;
+        }
+
+        let open = state.active == Some(collection.id);
+        let label = ui.selectable_label(open, collection.name.as_str());
+        let label = match &collection.query {
+            Some(query) => label.on_hover_text(query.as_str()),
+            None => label,
+        };
+        if label.clicked() {
+            state.active = Some(collection.id);
+            // The gallery shows one source at a time, like picking a path
+            *currently_active_path = None;
+            search.input.clear();
+            search.query = None;
+            search.error = None;
+        }
+        if collection.is_album() {
+            ui.weak(collection.file_count.to_string());
+        }
+        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+            if ui.button("X").on_hover_text("Delete collection").clicked() {
+                if open {
+                    state.active = None;
+                }
+                let id = collection.id;
+                let description = format!("Delete collection {}", collection.name);
+                journal::spawn_journaled_edit(ctx, tag_store, runtime, &state.error, description, move |tx| {
+                    collections::delete_collection(tx, id)
+                });
+            }
+            if ui.button("✏").on_hover_text("Rename collection").clicked() {
+                state.renaming = Some((collection.id, collection.name.clone()));
+            }
+        });
+    });
+}
+
+fn set_error(error: &Arc<Mutex<Option<String>>>, message: String) {
+    if let Ok(mut error) = error.try_lock() {
+        *error = Some(message);
+    }
+}
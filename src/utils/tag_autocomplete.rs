@@ -0,0 +1,371 @@
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::sync::Arc;
+use rusqlite::Connection;
+use crate::app::tag_store;
+
+/// How many suggestions the dropdown shows at most.
+const SUGGESTION_LIMIT: usize = 8;
+
+/// A tag, or an alias of one, that can be offered while typing.
+#[derive(Clone, Debug)]
+pub struct TagCompletion {
+    /// What the typed text is matched against.
+    pub name: String,
+    /// The tag inserted when picked; differs from `name` for aliases.
+    pub target: String,
+    /// Number of files tagged with `target`.
+    pub usage: i64,
+}
+
+/// How a tag field splits its text, which decides the part being completed.
+#[derive(Clone, Copy, PartialEq)]
+pub enum TagField {
+    /// Comma-separated tags with `>` hierarchies, as in the bulk tagging box.
+    List,
+    /// A search expression; words are split at whitespace, parentheses and quotes.
+    Query,
+    /// The whole text is one tag name, as when renaming.
+    Single,
+}
+
+/// Tags and aliases with their usage counts. Shared behind an `Arc` so each frame can take it cheaply.
+pub fn load_completions(conn: &Connection) -> rusqlite::Result<Arc<Vec<TagCompletion>>> {
+    let mut stmt = conn.prepare_cached(
+        "WITH usage AS (
+             SELECT t.id, t.name, COUNT(ft.file_id) AS usage FROM tags t
+             LEFT JOIN file_tags ft ON ft.tag_id = t.id
+             GROUP BY t.id
+         )
+         SELECT name, name, usage FROM usage
+         UNION ALL
+         SELECT a.alias, u.name, u.usage FROM tag_aliases a JOIN usage u ON u.id = a.tag_id",
+    )?;
+    let completions = stmt
+        .query_map([], |row| Ok(TagCompletion { name: row.get(0)?, target: row.get(1)?, usage: row.get(2)? }))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(Arc::new(completions))
+}
+
+/// Byte offset where the part of `text` being completed starts.
+fn fragment_start(text: &str, field: TagField) -> usize {
+    let separator = |c: char| match field {
+        TagField::List => c == ',' || c == '>',
+        TagField::Query => c.is_whitespace() || c == '(' || c == ')' || c == '"',
+        TagField::Single => false,
+    };
+    let start = text.rfind(separator).map(|i| i + text[i..].chars().next().map_or(1, char::len_utf8)).unwrap_or(0);
+    // Leading spaces after a separator stay with the text before
+    start + (text[start..].len() - text[start..].trim_start().len())
+}
+
+/// The typed fragment in the form tag names are stored, or `None` if there is nothing to complete.
+fn typed_fragment(text: &str, field: TagField) -> Option<String> {
+    let fragment = &text[fragment_start(text, field)..];
+    // Inside a quoted phrase the words are searched in notes, not tags
+    if field == TagField::Query && (matches!(fragment, "AND" | "OR" | "NOT") || text.matches('"').count() % 2 == 1) {
+        return None;
+    }
+    tag_store::normalize_tag_name(fragment).map(|name| name.to_lowercase())
+}
+
+/// How well `fragment` matches `name`, lower is better: exact, prefix, substring, then typos.
+/// Both sides are lowercase. The namespace is optional, so `cano` matches `camera:Canon`.
+fn match_rank(fragment: &str, name: &str) -> Option<(u8, usize)> {
+    let value = tag_store::split_namespace(name).1;
+    if name == fragment || value == fragment {
+        return Some((0, 0));
+    }
+    if name.starts_with(fragment) || value.starts_with(fragment) {
+        return Some((1, 0));
+    }
+    if name.contains(fragment) {
+        return Some((2, 0));
+    }
+
+    // Short fragments match too much with any typo allowed
+    let allowed = match fragment.chars().count() {
+        0..=2 => return None,
+        3..=5 => 1,
+        _ => 2,
+    };
+    // A typo in what is typed so far counts, not the letters still to come
+    let typed_length = fragment.chars().count();
+    let value_prefix: String = value.chars().take(typed_length).collect();
+    let distance = [name, value, value_prefix.as_str()]
+        .into_iter()
+        .map(|candidate| strsim::damerau_levenshtein(fragment, candidate))
+        .min()?;
+    (distance <= allowed).then_some((3, distance))
+}
+
+/// The best completions for the fragment being typed, ranked by match quality and then by usage.
+/// Each tag shows up once, even when an alias of it matches as well.
+pub fn suggest<'a>(completions: &'a [TagCompletion], text: &str, field: TagField) -> Vec<&'a TagCompletion> {
+    let Some(fragment) = typed_fragment(text, field) else {
+        return Vec::new();
+    };
+    let mut matches: Vec<((u8, usize), &TagCompletion)> = completions
+        .iter()
+        // Nothing to offer for a tag that is already typed out in full
+        .filter(|completion| completion.target.to_lowercase() != fragment)
+        .filter_map(|completion| match_rank(&fragment, &completion.name.to_lowercase()).map(|rank| (rank, completion)))
+        .collect();
+    matches.sort_by(|(a_rank, a), (b_rank, b)| {
+        a_rank
+            .cmp(b_rank)
+            .then(b.usage.cmp(&a.usage))
+            .then(a.name.len().cmp(&b.name.len()))
+            .then(a.name.cmp(&b.name))
+    });
+
+    let mut seen = HashSet::new();
+    matches
+        .into_iter()
+        .map(|(_, completion)| completion)
+        .filter(|completion| seen.insert(completion.target.as_str()))
+        .take(SUGGESTION_LIMIT)
+        .collect()
+}
+
+/// Replaces the fragment being typed with `completion`'s tag.
+fn apply_completion(text: &mut String, field: TagField, completion: &TagCompletion) {
+    text.truncate(fragment_start(text, field));
+    text.push_str(&completion.target);
+    if field == TagField::Query {
+        text.push(' ');
+    }
+}
+
+/// A suggestion line: the namespace in the accent colour, aliases followed by their tag, then the usage count.
+fn completion_text(ui: &egui::Ui, completion: &TagCompletion) -> egui::text::LayoutJob {
+    let font = egui::TextStyle::Button.resolve(ui.style());
+    let visuals = ui.visuals();
+    let format = |color| egui::TextFormat::simple(font.clone(), color);
+    let mut job = egui::text::LayoutJob::default();
+
+    let append_tag = |job: &mut egui::text::LayoutJob, name: &str| match tag_store::split_namespace(name) {
+        (Some(namespace), value) => {
+            job.append(&format!("{}:", namespace), 0.0, format(visuals.hyperlink_color));
+            job.append(value, 0.0, format(visuals.strong_text_color()));
+        }
+        (None, value) => job.append(value, 0.0, format(visuals.strong_text_color())),
+    };
+    append_tag(&mut job, &completion.name);
+    if completion.name != completion.target {
+        job.append("→ ", 6.0, format(visuals.weak_text_color()));
+        append_tag(&mut job, &completion.target);
+    }
+    job.append(&completion.usage.to_string(), 8.0, format(visuals.weak_text_color()));
+    job
+}
+
+/// Where the dropdown of a tag input is, kept between frames.
+#[derive(Clone, Copy, Default)]
+struct CompletionPopup {
+    highlighted: Option<usize>,
+    rect: Option<egui::Rect>,
+}
+
+/// A single-line text field that offers existing tags in a dropdown while typing.
+/// Arrow keys move through the suggestions, Tab or a click picks one, and Enter picks
+/// the highlighted one; otherwise Enter behaves as in a plain field.
+pub fn tag_input(
+    ui: &mut egui::Ui,
+    id_salt: impl Hash,
+    text: &mut String,
+    field: TagField,
+    completions: &[TagCompletion],
+    hint: &str,
+    width: f32,
+) -> egui::Response {
+    let id = ui.make_persistent_id(id_salt);
+    let popup_id = id.with("tag_completions");
+    let mut popup: CompletionPopup = ui.data(|data| data.get_temp(popup_id)).unwrap_or_default();
+    let pointer_on_popup = popup.rect.zip(ui.ctx().pointer_hover_pos()).is_some_and(|(rect, pos)| rect.contains(pos));
+
+    let mut picked = None;
+    if ui.memory(|memory| memory.has_focus(id)) {
+        let suggestions = suggest(completions, text, field);
+        if !suggestions.is_empty() {
+            let count = suggestions.len();
+            ui.input_mut(|input| {
+                if input.consume_key(egui::Modifiers::NONE, egui::Key::ArrowDown) {
+                    popup.highlighted = Some(popup.highlighted.map_or(0, |i| (i + 1) % count));
+                }
+                if input.consume_key(egui::Modifiers::NONE, egui::Key::ArrowUp) {
+                    popup.highlighted = Some(popup.highlighted.map_or(count - 1, |i| (i + count - 1) % count));
+                }
+                if input.consume_key(egui::Modifiers::NONE, egui::Key::Tab) {
+                    picked = Some(popup.highlighted.unwrap_or(0).min(count - 1));
+                } else if let Some(highlighted) = popup.highlighted
+                    && input.consume_key(egui::Modifiers::NONE, egui::Key::Enter)
+                {
+                    picked = Some(highlighted.min(count - 1));
+                }
+            });
+            if let Some(index) = picked {
+                let completion = suggestions[index].clone();
+                apply_completion(text, field, &completion);
+            }
+        }
+    }
+
+    let mut output = egui::TextEdit::singleline(text).id(id).hint_text(hint).desired_width(width).show(ui);
+    if picked.is_some() {
+        popup.highlighted = None;
+        move_cursor_to_end(ui, id, text, &mut output);
+        // The text edit did not see the completion go in, so callers would miss it
+        output.response.mark_changed();
+    }
+    if output.response.changed() {
+        popup.highlighted = None;
+    }
+
+    let mut clicked = None;
+    let suggestions = if output.response.has_focus() || pointer_on_popup {
+        suggest(completions, text, field)
+    } else {
+        Vec::new()
+    };
+    popup.rect = None;
+    if !suggestions.is_empty() {
+        let area = egui::Area::new(popup_id)
+            .order(egui::Order::Foreground)
+            .fixed_pos(output.response.rect.left_bottom())
+            .show(ui.ctx(), |ui| {
+                egui::Frame::popup(ui.style()).show(ui, |ui| {
+                    ui.set_min_width(output.response.rect.width());
+                    for (index, completion) in suggestions.iter().enumerate() {
+                        let label = ui.selectable_label(popup.highlighted == Some(index), completion_text(ui, completion));
+                        if label.clicked() {
+                            clicked = Some((*completion).clone());
+                        }
+                    }
+                });
+            });
+        popup.rect = Some(area.response.rect);
+    }
+    if let Some(completion) = clicked {
+        apply_completion(text, field, &completion);
+        popup = CompletionPopup::default();
+        move_cursor_to_end(ui, id, text, &mut output);
+        output.response.mark_changed();
+    }
+
+    ui.data_mut(|data| data.insert_temp(popup_id, popup));
+    output.response
+}
+
+/// Keeps typing after a completion: focus stays in the field with the cursor after the inserted tag.
+fn move_cursor_to_end(ui: &egui::Ui, id: egui::Id, text: &str, output: &mut egui::text_edit::TextEditOutput) {
+    let end = egui::text::CCursor::new(text.chars().count());
+    output.state.cursor.set_char_range(Some(egui::text::CCursorRange::one(end)));
+    output.state.clone().store(ui.ctx(), id);
+    ui.memory_mut(|memory| memory.request_focus(id));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completion(name: &str, target: &str, usage: i64) -> TagCompletion {
+        TagCompletion { name: name.to_string(), target: target.to_string(), usage }
+    }
+
+    fn suggested(completions: &[TagCompletion], text: &str, field: TagField) -> Vec<(String, String)> {
+        suggest(completions, text, field).into_iter().map(|c| (c.name.clone(), c.target.clone())).collect()
+    }
+
+    #[test]
+    fn fragments_start_after_multibyte_separators_and_spaces() {
+        // U+3000 is whitespace and three bytes long
+        let text = "cat\u{3000}do";
+        assert_eq!(&text[fragment_start(text, TagField::Query)..], "do");
+        let text = "猫 >   ne";
+        assert_eq!(&text[fragment_start(text, TagField::List)..], "ne");
+        let text = "(sunset OR 海";
+        assert_eq!(&text[fragment_start(text, TagField::Query)..], "海");
+        assert_eq!(fragment_start("a, b", TagField::Single), 0);
+        assert_eq!(fragment_start("a,  ", TagField::List), 4);
+    }
+
+    #[test]
+    fn completions_replace_only_the_fragment() {
+        let mut text = "猫, ca".to_string();
+        apply_completion(&mut text, TagField::List, &completion("cat", "cat", 1));
+        assert_eq!(text, "猫, cat");
+        let mut text = "beach AND su".to_string();
+        apply_completion(&mut text, TagField::Query, &completion("sunset", "sunset", 1));
+        assert_eq!(text, "beach AND sunset ");
+    }
+
+    #[test]
+    fn typed_fragments_are_normalized() {
+        assert_eq!(typed_fragment("cat, Big Ca", TagField::List).as_deref(), Some("big_ca"));
+        assert_eq!(typed_fragment("Artist : Fo", TagField::Single).as_deref(), Some("artist:fo"));
+        assert_eq!(typed_fragment("cat, ", TagField::List), None);
+    }
+
+    #[test]
+    fn quoted_phrases_and_operators_are_not_completed() {
+        assert_eq!(typed_fragment("beach \"sunny da", TagField::Query), None);
+        assert_eq!(typed_fragment("\"sunny day\" ca", TagField::Query).as_deref(), Some("ca"));
+        assert_eq!(typed_fragment("beach AND", TagField::Query), None);
+        assert_eq!(typed_fragment("beach and", TagField::Query).as_deref(), Some("and"));
+        // Quotes mean nothing in a tag list
+        assert_eq!(typed_fragment("\"ca", TagField::List).as_deref(), Some("\"ca"));
+    }
+
+    #[test]
+    fn values_match_without_their_namespace() {
+        assert_eq!(match_rank("camera:canon", "camera:canon"), Some((0, 0)));
+        assert_eq!(match_rank("canon", "camera:canon"), Some((0, 0)));
+        assert_eq!(match_rank("cano", "camera:canon"), Some((1, 0)));
+        assert_eq!(match_rank("era:ca", "camera:canon"), Some((2, 0)));
+        assert_eq!(match_rank("anon", "camera:canon"), Some((2, 0)));
+    }
+
+    #[test]
+    fn typos_allowed_grow_with_the_fragment() {
+        // Up to two letters, no typos
+        assert_eq!(match_rank("xa", "cat"), None);
+        // Three to five letters, one typo
+        assert_eq!(match_rank("dgo", "dog"), Some((3, 1)));
+        assert_eq!(match_rank("bxnxn", "banana"), None);
+        // Six or more, two
+        assert_eq!(match_rank("bxnxna", "banana"), Some((3, 2)));
+        assert_eq!(match_rank("bxnxnx", "banana"), None);
+        // Only what is typed so far is compared
+        assert_eq!(match_rank("elepj", "animal:elephant"), Some((3, 1)));
+    }
+
+    #[test]
+    fn aliases_show_once_per_target() {
+        let completions = [
+            completion("animal:cat", "animal:cat", 5),
+            completion("cat", "animal:cat", 5),
+            completion("kitty", "animal:cat", 5),
+            completion("kitchen", "kitchen", 10),
+        ];
+        assert_eq!(suggested(&completions, "cat", TagField::List), [("cat".to_string(), "animal:cat".to_string())]);
+        assert_eq!(
+            suggested(&completions, "kit", TagField::List),
+            [("kitchen".to_string(), "kitchen".to_string()), ("kitty".to_string(), "animal:cat".to_string())]
+        );
+        // A tag typed out in full is not offered again, not even through an alias
+        assert!(suggested(&completions, "animal:cat", TagField::List).is_empty());
+    }
+
+    #[test]
+    fn better_matches_come_before_more_used_tags() {
+        let completions = [
+            completion("sunrise", "sunrise", 1),
+            completion("beach:sun", "beach:sun", 2),
+            completion("sunset", "sunset", 30),
+            completion("tsunami", "tsunami", 50),
+        ];
+        let names: Vec<String> = suggested(&completions, "sun", TagField::Single).into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["beach:sun", "sunset", "sunrise", "tsunami"]);
+    }
+}
@@ -0,0 +1,521 @@
+use std::fmt;
+use rusqlite::types::Value;
+use rusqlite::Connection;
+use crate::app::file_attributes::{ColorLabel, MAX_RATING};
+use crate::app::file_notes;
+
+/// A parsed search expression such as `cat AND (outdoor OR garden) AND NOT blurry`.
+///
+/// Terms next to each other without an operator are joined with `AND`. A tag also
+/// matches files tagged with any of its descendants or with a tag implying it,
+/// aliases are followed, and `artist:*` matches every tag in the `artist` namespace.
+/// `rating>=4`, `favorite=yes` and `color=red` match on file attributes instead of tags,
+/// and a quoted phrase such as `"final approved"` matches words in a file's notes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Tag(String),
+    Phrase(String),
+    Rating(Comparison, u8),
+    Favorite(bool),
+    /// `None` matches files without a label, written `color=none`.
+    ColorLabel(Option<ColorLabel>),
+    And(Box<Query>, Box<Query>),
+    Or(Box<Query>, Box<Query>),
+    Not(Box<Query>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Comparison {
+    fn sql(self) -> &'static str {
+        match self {
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Equal => "=",
+            Comparison::GreaterOrEqual => ">=",
+            Comparison::Greater => ">",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryError {
+    pub message: String,
+    /// Character offset into the input where the problem was found.
+    pub position: usize,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at column {})", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Phrase(String),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+}
+
+fn tokenize(input: &str) -> Result<Vec<(Token, usize)>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().enumerate().peekable();
+
+    while let Some(&(position, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push((Token::LParen, position));
+            }
+            ')' => {
+                chars.next();
+                tokens.push((Token::RParen, position));
+            }
+            '"' => {
+                chars.next();
+                let mut phrase = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, c)) => phrase.push(c),
+                        None => return Err(QueryError { message: "unclosed '\"'".to_string(), position }),
+                    }
+                }
+                if phrase.trim().is_empty() {
+                    return Err(QueryError { message: "empty phrase".to_string(), position });
+                }
+                tokens.push((Token::Phrase(phrase.trim().to_string()), position));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                let token = match word.as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Word(word),
+                };
+                tokens.push((token, position));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    index: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(token, _)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.index).map(|(_, position)| *position).unwrap_or(self.end)
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, QueryError> {
+        Err(QueryError { message: message.into(), position: self.position() })
+    }
+
+    fn parse_or(&mut self) -> Result<Query, QueryError> {
+        let mut left = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.index += 1;
+            let right = self.parse_and()?;
+            left = Query::Or(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> Result<Query, QueryError> {
+        let mut left = self.parse_unary()?;
+        loop {
+            match self.peek() {
+                Some(Token::And) => self.index += 1,
+                // Implicit AND between adjacent terms
+                Some(Token::Word(_) | Token::Phrase(_) | Token::Not | Token::LParen) => {}
+                _ => break,
+            }
+            let right = self.parse_unary()?;
+            left = Query::And(Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> Result<Query, QueryError> {
+        if self.peek() == Some(&Token::Not) {
+            self.index += 1;
+            return Ok(Query::Not(Box::new(self.parse_unary()?)));
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Query, QueryError> {
+        match self.peek().cloned() {
+            Some(Token::Word(word)) => {
+                let query = match attribute_term(&word) {
+                    Some(Err(message)) => return self.error(message),
+                    Some(Ok(query)) => query,
+                    None => Query::Tag(word),
+                };
+                self.index += 1;
+                Ok(query)
+            }
+            Some(Token::Phrase(phrase)) => {
+                self.index += 1;
+                Ok(Query::Phrase(phrase))
+            }
+            Some(Token::LParen) => {
+                let open = self.position();
+                self.index += 1;
+                let inner = self.parse_or()?;
+                if self.peek() != Some(&Token::RParen) {
+                    return Err(QueryError { message: "unclosed '('".to_string(), position: open });
+                }
+                self.index += 1;
+                Ok(inner)
+            }
+            Some(Token::RParen) => self.error("unexpected ')'"),
+            Some(Token::And) => self.error("expected a tag before AND"),
+            Some(Token::Or) => self.error("expected a tag before OR"),
+            Some(Token::Not) | None => self.error("expected a tag"),
+        }
+    }
+}
+
+/// Reads a word such as `rating>=4` as an attribute filter. Returns `None` for words that are
+/// not about an attribute, which are searched as tags.
+fn attribute_term(word: &str) -> Option<Result<Query, String>> {
+    let split = word.find(['<', '>', '='])?;
+    let (key, rest) = word.split_at(split);
+    let (comparison, value) = [
+        ("<=", Comparison::LessOrEqual),
+        (">=", Comparison::GreaterOrEqual),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+        ("=", Comparison::Equal),
+    ]
+    .into_iter()
+    .find_map(|(operator, comparison)| rest.strip_prefix(operator).map(|value| (comparison, value)))?;
+
+    let query = match key.to_lowercase().as_str() {
+        "rating" => match value.parse::<u8>() {
+            Ok(rating) if rating <= MAX_RATING => Ok(Query::Rating(comparison, rating)),
+            _ => Err(format!("a rating is a number from 0 to {}", MAX_RATING)),
+        },
+        "favorite" if comparison == Comparison::Equal => match value.to_lowercase().as_str() {
+            "yes" | "true" | "1" => Ok(Query::Favorite(true)),
+            "no" | "false" | "0" => Ok(Query::Favorite(false)),
+            _ => Err("use favorite=yes or favorite=no".to_string()),
+        },
+        "color" | "label" if comparison == Comparison::Equal => match ColorLabel::from_name(value) {
+            Some(label) => Ok(Query::ColorLabel(Some(label))),
+            None if value.eq_ignore_ascii_case("none") => Ok(Query::ColorLabel(None)),
+            None => Err(format!(
+                "a color label is one of {} or none",
+                ColorLabel::ALL.map(ColorLabel::name).join(", ")
+            )),
+        },
+        "favorite" | "color" | "label" => Err(format!("{} can only be compared with =", key)),
+        _ => return None,
+    };
+    Some(query)
+}
+
+/// Parses a search expression. Returns `Ok(None)` for blank input.
+pub fn parse(input: &str) -> Result<Option<Query>, QueryError> {
+    let tokens = tokenize(input)?;
+    if tokens.is_empty() {
+        return Ok(None);
+    }
+
+    let mut parser = Parser { tokens, index: 0, end: input.chars().count() };
+    let query = parser.parse_or()?;
+    if parser.index < parser.tokens.len() {
+        return parser.error("unexpected ')'");
+    }
+    Ok(Some(query))
+}
+
+/// Compiles `query` into a `WHERE` condition over `files f`, appending its bound values to `params`.
+pub fn to_sql(query: &Query, params: &mut Vec<Value>) -> String {
+    match query {
+        Query::Tag(name) => {
+            let base = match name.strip_suffix(":*") {
+                Some(namespace) => {
+                    params.push(Value::Text(format!("{}:%", escape_like(namespace))));
+                    format!("SELECT id FROM tags WHERE name LIKE ?{} ESCAPE '\\'", params.len())
+                }
+                None => {
+                    params.push(Value::Text(name.clone()));
+                    format!(
+                        "SELECT id FROM tags WHERE name = ?{0} \
+                         UNION SELECT tag_id FROM tag_aliases WHERE alias = ?{0}",
+                        params.len()
+                    )
+                }
+            };
+            format!(
+                "f.id IN (SELECT ft.file_id FROM file_tags ft WHERE ft.tag_id IN (\
+                 WITH RECURSIVE matched(id) AS ({} \
+                 UNION SELECT tp.tag_id FROM tag_parents tp JOIN matched m ON tp.parent_id = m.id \
+                 UNION SELECT ti.tag_id FROM tag_implications ti JOIN matched m ON ti.implied_tag_id = m.id) \
+                 SELECT id FROM matched))",
+                base
+            )
+        }
+        Query::Phrase(phrase) => {
+            params.push(Value::Text(file_notes::phrase_query(phrase)));
+            format!(
+                "f.id IN (SELECT file_id FROM file_notes_fts WHERE file_notes_fts MATCH ?{})",
+                params.len()
+            )
+        }
+        // Files without a row count as unrated, not favorite and unlabelled
+        Query::Rating(comparison, rating) => {
+            params.push(Value::Integer(*rating as i64));
+            format!(
+                "COALESCE((SELECT a.rating FROM file_attributes a WHERE a.file_id = f.id), 0) {} ?{}",
+                comparison.sql(),
+                params.len()
+            )
+        }
+        Query::Favorite(favorite) => {
+            params.push(Value::Integer(*favorite as i64));
+            format!("COALESCE((SELECT a.favorite FROM file_attributes a WHERE a.file_id = f.id), 0) = ?{}", params.len())
+        }
+        Query::ColorLabel(label) => {
+            params.push(label.map_or(Value::Null, |label| Value::Text(label.name().to_string())));
+            format!("(SELECT a.color_label FROM file_attributes a WHERE a.file_id = f.id) IS ?{}", params.len())
+        }
+        Query::And(left, right) => format!("({} AND {})", to_sql(left, params), to_sql(right, params)),
+        Query::Or(left, right) => format!("({} OR {})", to_sql(left, params), to_sql(right, params)),
+        Query::Not(inner) => format!("(NOT {})", to_sql(inner, params)),
+    }
+}
+
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+}
+
+/// Paths of every file in the library matching `query`, sorted by path.
+pub fn search(conn: &Connection, query: &Query) -> rusqlite::Result<Vec<String>> {
+    let mut params = Vec::new();
+    let condition = to_sql(query, &mut params);
+    let sql = format!("SELECT f.path FROM files f WHERE {} ORDER BY f.path", condition);
+
+    let mut stmt = conn.prepare(&sql)?;
+    stmt.query_map(rusqlite::params_from_iter(params), |row| row.get(0))?
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::tag_store;
+
+    fn tag(name: &str) -> Box<Query> {
+        Box::new(Query::Tag(name.to_string()))
+    }
+
+    fn parse_ok(input: &str) -> Query {
+        parse(input).unwrap().unwrap()
+    }
+
+    fn parse_err(input: &str) -> QueryError {
+        parse(input).unwrap_err()
+    }
+
+    #[test]
+    fn blank_input_is_no_query() {
+        assert_eq!(parse("   "), Ok(None));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(parse_ok("a OR b AND c"), Query::Or(tag("a"), Box::new(Query::And(tag("b"), tag("c")))));
+        assert_eq!(parse_ok("a AND b OR c"), Query::Or(Box::new(Query::And(tag("a"), tag("b"))), tag("c")));
+    }
+
+    #[test]
+    fn not_applies_to_the_next_term_only() {
+        assert_eq!(parse_ok("NOT a b"), Query::And(Box::new(Query::Not(tag("a"))), tag("b")));
+    }
+
+    #[test]
+    fn adjacent_terms_are_joined_with_and() {
+        assert_eq!(parse_ok("a b"), parse_ok("a AND b"));
+        assert_eq!(parse_ok("a (b OR c)"), Query::And(tag("a"), Box::new(Query::Or(tag("b"), tag("c")))));
+        assert_eq!(parse_ok("a NOT b"), Query::And(tag("a"), Box::new(Query::Not(tag("b")))));
+    }
+
+    #[test]
+    fn parentheses_group() {
+        assert_eq!(parse_ok("(a OR b) AND c"), Query::And(Box::new(Query::Or(tag("a"), tag("b"))), tag("c")));
+    }
+
+    #[test]
+    fn unbalanced_parentheses_point_at_the_culprit() {
+        let error = parse_err("a AND (b OR c");
+        assert_eq!((error.message.as_str(), error.position), ("unclosed '('", 6));
+        let error = parse_err("a b) c");
+        assert_eq!((error.message.as_str(), error.position), ("unexpected ')'", 3));
+        let error = parse_err("()");
+        assert_eq!((error.message.as_str(), error.position), ("unexpected ')'", 1));
+    }
+
+    #[test]
+    fn dangling_operators_are_errors() {
+        let error = parse_err("a AND");
+        assert_eq!((error.message.as_str(), error.position), ("expected a tag", 5));
+        let error = parse_err("OR a");
+        assert_eq!((error.message.as_str(), error.position), ("expected a tag before OR", 0));
+    }
+
+    #[test]
+    fn positions_count_characters_not_bytes() {
+        assert_eq!(parse_err("ünïcode )").position, 8);
+    }
+
+    #[test]
+    fn rating_comparisons() {
+        assert_eq!(parse_ok("rating>=4"), Query::Rating(Comparison::GreaterOrEqual, 4));
+        assert_eq!(parse_ok("rating<2"), Query::Rating(Comparison::Less, 2));
+        assert_eq!(parse_ok("Rating=0"), Query::Rating(Comparison::Equal, 0));
+        let error = parse_err("a rating>=9");
+        assert_eq!(error.position, 2);
+        assert!(error.message.starts_with("a rating is a number"));
+    }
+
+    #[test]
+    fn favorite_and_color_labels() {
+        assert_eq!(parse_ok("favorite=yes"), Query::Favorite(true));
+        assert_eq!(parse_ok("color=red"), Query::ColorLabel(Some(ColorLabel::Red)));
+        assert_eq!(parse_ok("color=none"), Query::ColorLabel(None));
+        assert_eq!(parse_err("color>red").message, "color can only be compared with =");
+        assert!(parse_err("color=teal").message.starts_with("a color label is one of"));
+    }
+
+    #[test]
+    fn words_without_a_known_attribute_are_tags() {
+        assert_eq!(parse_ok("artist:*"), Query::Tag("artist:*".to_string()));
+        assert_eq!(parse_ok("size>=4"), Query::Tag("size>=4".to_string()));
+    }
+
+    #[test]
+    fn quoted_phrases() {
+        assert_eq!(parse_ok("\"final approved\""), Query::Phrase("final approved".to_string()));
+        assert_eq!(
+            parse_ok("cat \" big \"OR dog"),
+            Query::Or(Box::new(Query::And(tag("cat"), Box::new(Query::Phrase("big".to_string())))), tag("dog"))
+        );
+        let error = parse_err("a \"open");
+        assert_eq!((error.message.as_str(), error.position), ("unclosed '\"'", 2));
+        assert_eq!(parse_err("\"  \"").message, "empty phrase");
+    }
+
+    #[test]
+    fn keywords_are_case_sensitive() {
+        assert_eq!(parse_ok("a or b"), Query::And(Box::new(Query::And(tag("a"), tag("or"))), tag("b")));
+    }
+
+    /// A small library exercising every kind of match `to_sql` produces.
+    fn library() -> Connection {
+        let conn = tag_store::open_in_memory();
+        let tag = |path: &str, tag_path: &[&str]| {
+            let tag_path = tag_path.iter().map(|name| name.to_string()).collect();
+            tag_store::add_tags_to_files(&conn, &[path.to_string()], &[tag_path]).unwrap();
+        };
+        tag("/cat.jpg", &["animal", "cat"]);
+        tag("/dog.jpg", &["dog"]);
+        tag("/monet.jpg", &["artist:monet"]);
+        tag("/wildcard.jpg", &["artist_x:monet"]);
+        tag("/percent.jpg", &["50%:off"]);
+        tag_store::add_alias(&conn, "kitty", "cat").unwrap();
+        tag_store::add_implication(&conn, "dog", "pet").unwrap();
+        file_notes::set_note(&conn, "/notes.jpg", "the final approved version").unwrap();
+        file_notes::set_note(&conn, "/reversed.jpg", "approved, but not final").unwrap();
+        file_notes::set_note(&conn, "/quoted.jpg", "he said \"ship it\" twice").unwrap();
+        conn
+    }
+
+    fn matches(conn: &Connection, input: &str) -> Vec<String> {
+        search(conn, &parse_ok(input)).unwrap()
+    }
+
+    #[test]
+    fn tags_match_their_descendants() {
+        let conn = library();
+        assert_eq!(matches(&conn, "animal"), ["/cat.jpg"]);
+        assert_eq!(matches(&conn, "cat"), ["/cat.jpg"]);
+    }
+
+    #[test]
+    fn tags_match_files_tagged_with_a_tag_implying_them() {
+        let conn = library();
+        assert_eq!(matches(&conn, "pet"), ["/dog.jpg"]);
+        assert_eq!(matches(&conn, "pet OR animal"), ["/cat.jpg", "/dog.jpg"]);
+        assert!(matches(&conn, "pet NOT dog").is_empty());
+    }
+
+    #[test]
+    fn aliases_are_followed() {
+        let conn = library();
+        assert_eq!(matches(&conn, "kitty"), ["/cat.jpg"]);
+        assert!(matches(&conn, "kittens").is_empty());
+    }
+
+    #[test]
+    fn namespace_wildcards_take_like_characters_literally() {
+        let conn = library();
+        assert_eq!(matches(&conn, "artist:*"), ["/monet.jpg"]);
+        assert_eq!(matches(&conn, "artist_x:*"), ["/wildcard.jpg"]);
+        assert_eq!(matches(&conn, "50%:*"), ["/percent.jpg"]);
+        assert!(matches(&conn, "art_st:*").is_empty());
+        assert!(matches(&conn, "5%:*").is_empty());
+    }
+
+    #[test]
+    fn phrases_match_consecutive_words_in_notes() {
+        let conn = library();
+        assert_eq!(matches(&conn, "\"final approved\""), ["/notes.jpg"]);
+        assert_eq!(matches(&conn, "\"APPROVED\""), ["/notes.jpg", "/reversed.jpg"]);
+        assert_eq!(matches(&conn, "\"ship it\""), ["/quoted.jpg"]);
+        assert!(matches(&conn, "\"approved final\" cat").is_empty());
+    }
+
+    #[test]
+    fn phrases_and_tags_combine() {
+        let conn = library();
+        file_notes::set_note(&conn, "/cat.jpg", "final approved").unwrap();
+        assert_eq!(matches(&conn, "animal \"final approved\""), ["/cat.jpg"]);
+        assert_eq!(matches(&conn, "\"final approved\" NOT kitty"), ["/notes.jpg"]);
+    }
+}
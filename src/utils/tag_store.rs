@@ -0,0 +1,921 @@
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use tokio::sync::Mutex;
+use crate::app::file_attributes::FileAttributes;
+use crate::app::journal;
+use crate::app::migrations::{self, MigrationError};
+
+const DATABASE_FILE_NAME: &str = "taggerrs.sqlite3";
+
+/// Handle to the sqlite tag database, cheap to clone and share with async tasks.
+///
+/// All queries go through [`TagStore::read`] / [`TagStore::write`], which run on
+/// tokio's blocking pool so the egui `update()` loop never waits on disk.
+#[derive(Clone)]
+pub struct TagStore {
+    conn: Arc<std::sync::Mutex<Connection>>,
+    revision: Arc<AtomicU64>,
+}
+
+/// Tags and attributes of the files currently shown in the gallery, reloaded whenever the store changes.
+#[derive(Default)]
+pub struct GalleryTags {
+    pub path: Option<String>,
+    pub revision: Option<u64>,
+    /// The revision a reload is running for, so only one is in flight at a time.
+    pub loading: Option<u64>,
+    pub tags: HashMap<String, Vec<String>>,
+    pub attributes: HashMap<String, FileAttributes>,
+}
+
+/// A query result kept for the UI, tagged with the store revision it was loaded at.
+pub struct Cached<T> {
+    pub revision: Option<u64>,
+    pub value: T,
+    /// The revision a reload is running for, so only one is in flight at a time.
+    loading: Option<u64>,
+    /// Bumped by [`Cached::invalidate`], so reloads started for the old query are dropped.
+    generation: u64,
+}
+
+impl<T: Default> Default for Cached<T> {
+    fn default() -> Self {
+        Self { revision: None, value: T::default(), loading: None, generation: 0 }
+    }
+}
+
+impl<T> Cached<T> {
+    /// Reloads on the next refresh, for when the query itself changed rather than the store.
+    pub fn invalidate(&mut self) {
+        self.revision = None;
+        self.loading = None;
+        self.generation += 1;
+    }
+}
+
+#[derive(Clone)]
+pub struct TagInfo {
+    pub id: i64,
+    pub name: String,
+    pub usage: i64,
+    pub parent_id: Option<i64>,
+}
+
+/// Alias and implication rules, as `(alias, tag)` and `(tag, implied tag)` name pairs.
+#[derive(Clone, Default)]
+pub struct TagRules {
+    pub aliases: Vec<(String, String)>,
+    pub implications: Vec<(String, String)>,
+}
+
+impl TagStore {
+    /// Opens the database at `path`, upgrading its schema first.
+    /// Databases written by a newer taggerrs are refused rather than touched.
+    pub fn open(path: &Path) -> Result<Self, MigrationError> {
+        let mut conn = Connection::open(path)?;
+        // Switching to WAL rewrites the file header, which a newer taggerrs may not expect
+        migrations::check_version(&conn)?;
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        migrations::migrate(&mut conn)?;
+
+        Ok(Self {
+            conn: Arc::new(std::sync::Mutex::new(conn)),
+            revision: Arc::new(AtomicU64::new(0)),
+        })
+    }
+
+    /// Opens the database next to eframe's own app state.
+    pub fn open_default() -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        let dir = eframe::storage_dir("taggerrs").ok_or("no data directory available")?;
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self::open(&dir.join(DATABASE_FILE_NAME))?)
+    }
+
+    /// Bumped after every successful write, so UI caches know when to reload.
+    pub fn revision(&self) -> u64 {
+        self.revision.load(Ordering::Acquire)
+    }
+
+    pub async fn read<T, F>(&self, f: F) -> rusqlite::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            f(&conn)
+        })
+        .await
+        .expect("tag store worker panicked")
+    }
+
+    /// Runs `f` inside a single transaction and commits it if `f` succeeds.
+    pub async fn write<T, F>(&self, f: F) -> rusqlite::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Transaction) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        let revision = self.revision.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut conn = conn.lock().unwrap_or_else(|e| e.into_inner());
+            let tx = conn.transaction()?;
+            let result = f(&tx)?;
+            tx.commit()?;
+            revision.fetch_add(1, Ordering::AcqRel);
+            Ok(result)
+        })
+        .await
+        .expect("tag store worker panicked")
+    }
+
+    /// Like [`TagStore::write`], but records every change `f` makes as one undoable
+    /// journal entry shown to the user as `description`.
+    pub async fn write_journaled<T, F>(&self, description: impl Into<String>, f: F) -> rusqlite::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Transaction) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let description = description.into();
+        self.write(move |tx| {
+            let entry_id = journal::begin(tx, &description)?;
+            let result = f(tx)?;
+            journal::finish(tx, entry_id)?;
+            Ok(result)
+        })
+        .await
+    }
+}
+
+/// An empty in-memory database with the current schema, set up like [`TagStore::open`].
+#[cfg(test)]
+pub fn open_in_memory() -> Connection {
+    let mut conn = Connection::open_in_memory().unwrap();
+    conn.pragma_update(None, "foreign_keys", "ON").unwrap();
+    migrations::migrate(&mut conn).unwrap();
+    conn
+}
+
+/// How long the UI waits before looking again at state a background task has locked.
+/// Asking for the very next frame instead would spin until the task lets go.
+pub const BUSY_RETRY: Duration = Duration::from_millis(50);
+
+/// Reloads `cache` in the background if the store changed since it was last loaded.
+pub fn refresh_cached<T, F>(
+    ctx: &egui::Context,
+    cache: &Arc<Mutex<Cached<T>>>,
+    store: &TagStore,
+    runtime: &tokio::runtime::Runtime,
+    query: F,
+) where
+    T: Send + 'static,
+    F: FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+{
+    refresh_cached_then(ctx, cache, store, runtime, query, |value| value);
+}
+
+/// Like [`refresh_cached`], but passes the rows through `finish` once the connection is
+/// released, for checks such as file existence that should not hold up other database users.
+pub fn refresh_cached_then<R, T, F, G>(
+    ctx: &egui::Context,
+    cache: &Arc<Mutex<Cached<T>>>,
+    store: &TagStore,
+    runtime: &tokio::runtime::Runtime,
+    query: F,
+    finish: G,
+) where
+    R: Send + 'static,
+    T: Send + 'static,
+    F: FnOnce(&Connection) -> rusqlite::Result<R> + Send + 'static,
+    G: FnOnce(R) -> T + Send + 'static,
+{
+    let revision = store.revision();
+    let Ok(mut cached) = cache.try_lock() else {
+        ctx.request_repaint_after(BUSY_RETRY);
+        return;
+    };
+    if cached.revision == Some(revision) || cached.loading == Some(revision) {
+        return;
+    }
+    cached.loading = Some(revision);
+    let generation = cached.generation;
+    drop(cached);
+
+    let cache_clone = cache.clone();
+    let store_clone = store.clone();
+    let ctx_clone = ctx.clone();
+    runtime.spawn(async move {
+        let value = match store_clone.read(query).await {
+            Ok(rows) => tokio::task::spawn_blocking(move || finish(rows)).await.ok(),
+            Err(_) => None,
+        };
+        let mut cached = cache_clone.lock().await;
+        if cached.generation != generation {
+            return;
+        }
+        if cached.loading == Some(revision) {
+            cached.loading = None;
+        }
+        // A failed read leaves the revision unset so the next frame tries again, and a slow
+        // read never replaces what a reload for a newer revision already stored
+        if let Some(value) = value
+            && cached.revision.is_none_or(|stored| stored < revision)
+        {
+            cached.revision = Some(revision);
+            cached.value = value;
+            ctx_clone.request_repaint();
+        }
+    });
+}
+
+/// Runs `f` inside a savepoint, so a failure undoes only what `f` changed and the
+/// surrounding transaction can go on with the next item.
+pub fn in_savepoint<T>(conn: &Connection, f: impl FnOnce() -> rusqlite::Result<T>) -> rusqlite::Result<T> {
+    conn.execute_batch("SAVEPOINT item")?;
+    match f() {
+        Ok(value) => {
+            conn.execute_batch("RELEASE item")?;
+            Ok(value)
+        }
+        Err(e) => {
+            conn.execute_batch("ROLLBACK TO item; RELEASE item")?;
+            Err(e)
+        }
+    }
+}
+
+pub fn now_unix() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Makes sure every path has a file record, giving new files a fresh uuid.
+pub fn register_files(conn: &Connection, paths: &[String]) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT OR IGNORE INTO files (id, path, added_at) VALUES (?1, ?2, ?3)",
+    )?;
+    let now = now_unix();
+    for path in paths {
+        stmt.execute(params![uuid::Uuid::new_v4().to_string(), path, now])?;
+    }
+    Ok(())
+}
+
+/// Looks up the tags of each given path; paths without tags are left out.
+pub fn tags_for_paths(conn: &Connection, paths: &[String]) -> rusqlite::Result<HashMap<String, Vec<String>>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT t.name FROM file_tags ft
+         JOIN files f ON f.id = ft.file_id
+         JOIN tags t ON t.id = ft.tag_id
+         WHERE f.path = ?1
+         ORDER BY t.name",
+    )?;
+    let mut result = HashMap::new();
+    for path in paths {
+        let tags = stmt
+            .query_map([path], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        if !tags.is_empty() {
+            result.insert(path.clone(), tags);
+        }
+    }
+    Ok(result)
+}
+
+/// Trims a user-entered tag name and joins inner whitespace with `_` so it stays a
+/// single search term, rejecting names that are empty. `artist : foo` becomes `artist:foo`.
+pub fn normalize_tag_name(name: &str) -> Option<String> {
+    let join = |s: &str| s.split_whitespace().collect::<Vec<_>>().join("_");
+    let name = match name.split_once(':') {
+        Some((namespace, value)) if !join(namespace).is_empty() && !join(value).is_empty() => {
+            format!("{}:{}", join(namespace), join(value))
+        }
+        _ => join(name),
+    };
+    (!name.is_empty()).then_some(name)
+}
+
+/// Splits a `namespace:value` tag; tags without a namespace return `None`.
+pub fn split_namespace(name: &str) -> (Option<&str>, &str) {
+    match name.split_once(':') {
+        Some((namespace, value)) if !namespace.is_empty() && !value.is_empty() => (Some(namespace), value),
+        _ => (None, name),
+    }
+}
+
+/// Parses a hierarchy such as `animal > mammal > cat` into normalized names, root first.
+pub fn parse_tag_path(input: &str) -> Option<Vec<String>> {
+    let names = input.split('>').map(normalize_tag_name).collect::<Option<Vec<_>>>()?;
+    (!names.is_empty()).then_some(names)
+}
+
+/// An error for edits that are valid SQL but break a tag rule, such as a hierarchy cycle.
+pub fn rule_violation(message: String) -> rusqlite::Error {
+    rusqlite::Error::SqliteFailure(
+        rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_CONSTRAINT),
+        Some(message),
+    )
+}
+
+/// Returns the tag an alias points to, or `name` itself when it is not an alias.
+pub fn resolve_alias(conn: &Connection, name: &str) -> rusqlite::Result<String> {
+    let target = conn
+        .query_row(
+            "SELECT t.name FROM tag_aliases a JOIN tags t ON t.id = a.tag_id WHERE a.alias = ?1",
+            [name],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(target.unwrap_or_else(|| name.to_string()))
+}
+
+/// Looks up (or creates) the tag called `name`, following aliases.
+pub fn ensure_tag(conn: &Connection, name: &str) -> rusqlite::Result<i64> {
+    let name = resolve_alias(conn, name)?;
+    let name = name.as_str();
+    conn.execute("INSERT OR IGNORE INTO tags (name) VALUES (?1)", [name])?;
+    conn.query_row("SELECT id FROM tags WHERE name = ?1", [name], |row| row.get(0))
+}
+
+/// Every tag with the number of files it is attached to, sorted by name.
+pub fn list_tags(conn: &Connection) -> rusqlite::Result<Vec<TagInfo>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT t.id, t.name, COUNT(ft.file_id), tp.parent_id FROM tags t
+         LEFT JOIN file_tags ft ON ft.tag_id = t.id
+         LEFT JOIN tag_parents tp ON tp.tag_id = t.id
+         GROUP BY t.id
+         ORDER BY t.name COLLATE NOCASE",
+    )?;
+    stmt.query_map([], |row| {
+        Ok(TagInfo { id: row.get(0)?, name: row.get(1)?, usage: row.get(2)?, parent_id: row.get(3)? })
+    })?
+    .collect()
+}
+
+/// Moves `tag_id` under `parent_id` in the hierarchy, or makes it a root for `None`.
+pub fn set_tag_parent(conn: &Connection, tag_id: i64, parent_id: Option<i64>) -> rusqlite::Result<()> {
+    let Some(parent_id) = parent_id else {
+        conn.execute("DELETE FROM tag_parents WHERE tag_id = ?1", [tag_id])?;
+        return Ok(());
+    };
+
+    // The new parent must not already sit somewhere below the tag
+    let creates_cycle: bool = conn.query_row(
+        "WITH RECURSIVE ancestors(id) AS (
+             SELECT ?2
+             UNION SELECT tp.parent_id FROM tag_parents tp JOIN ancestors a ON tp.tag_id = a.id
+         )
+         SELECT EXISTS (SELECT 1 FROM ancestors WHERE id = ?1)",
+        params![tag_id, parent_id],
+        |row| row.get(0),
+    )?;
+    if creates_cycle {
+        return Err(rule_violation("a tag cannot be placed under itself or one of its children".to_string()));
+    }
+
+    conn.execute(
+        "INSERT INTO tag_parents (tag_id, parent_id) VALUES (?1, ?2)
+         ON CONFLICT(tag_id) DO UPDATE SET parent_id = excluded.parent_id",
+        params![tag_id, parent_id],
+    )?;
+    Ok(())
+}
+
+/// Creates every tag of a hierarchy path, linking each one under the previous, and returns the leaf id.
+pub fn ensure_tag_path(conn: &Connection, names: &[String]) -> rusqlite::Result<i64> {
+    let mut parent_id = None;
+    let mut tag_id = None;
+    for name in names {
+        let id = ensure_tag(conn, name)?;
+        if parent_id.is_some() {
+            set_tag_parent(conn, id, parent_id)?;
+        }
+        parent_id = Some(id);
+        tag_id = Some(id);
+    }
+    tag_id.ok_or(rusqlite::Error::QueryReturnedNoRows)
+}
+
+pub fn rename_tag(conn: &Connection, tag_id: i64, new_name: &str) -> rusqlite::Result<()> {
+    conn.execute("UPDATE tags SET name = ?1 WHERE id = ?2", params![new_name, tag_id])?;
+    Ok(())
+}
+
+pub fn delete_tag(conn: &Connection, tag_id: i64) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM tags WHERE id = ?1", [tag_id])?;
+    Ok(())
+}
+
+/// Moves every file link, child tag, alias and implication of `source_id` onto `target_id`,
+/// then deletes the source tag. A target without a parent takes over the source's parent.
+pub fn merge_tags(conn: &Connection, source_id: i64, target_id: i64) -> rusqlite::Result<()> {
+    if source_id == target_id {
+        return Ok(());
+    }
+    // The children move under the target, which must not be one of them or below them
+    let target_below_source: bool = conn.query_row(
+        "WITH RECURSIVE ancestors(id) AS (
+             SELECT parent_id FROM tag_parents WHERE tag_id = ?2
+             UNION SELECT tp.parent_id FROM tag_parents tp JOIN ancestors a ON tp.tag_id = a.id
+         )
+         SELECT EXISTS (SELECT 1 FROM ancestors WHERE id = ?1)",
+        params![source_id, target_id],
+        |row| row.get(0),
+    )?;
+    if target_below_source {
+        return Err(rule_violation("a tag cannot be merged into one of its children".to_string()));
+    }
+
+    conn.execute(
+        "INSERT OR IGNORE INTO file_tags (file_id, tag_id)
+         SELECT file_id, ?2 FROM file_tags WHERE tag_id = ?1",
+        params![source_id, target_id],
+    )?;
+    // A tag has a single parent, so its row is re-pointed rather than copied
+    conn.execute("UPDATE tag_parents SET parent_id = ?2 WHERE parent_id = ?1", params![source_id, target_id])?;
+    // Aliases would otherwise go with the source; searches by them now find the target
+    conn.execute("UPDATE tag_aliases SET tag_id = ?2 WHERE tag_id = ?1", params![source_id, target_id])?;
+    // Rules the target already has are left to the cascade, and "target implies target" means nothing
+    conn.execute(
+        "UPDATE OR IGNORE tag_implications SET tag_id = ?2 WHERE tag_id = ?1",
+        params![source_id, target_id],
+    )?;
+    conn.execute(
+        "UPDATE OR IGNORE tag_implications SET implied_tag_id = ?2 WHERE implied_tag_id = ?1",
+        params![source_id, target_id],
+    )?;
+    conn.execute("DELETE FROM tag_implications WHERE tag_id = ?1 AND implied_tag_id = ?1", [target_id])?;
+
+    // The source's place in the hierarchy is kept unless the target has one, or it sits below the target
+    let inherited_parent: Option<i64> = conn
+        .query_row(
+            "WITH RECURSIVE ancestors(id) AS (
+                 SELECT parent_id FROM tag_parents WHERE tag_id = ?1
+                 UNION SELECT tp.parent_id FROM tag_parents tp JOIN ancestors a ON tp.tag_id = a.id
+             )
+             SELECT parent_id FROM tag_parents
+             WHERE tag_id = ?1
+               AND NOT EXISTS (SELECT 1 FROM tag_parents WHERE tag_id = ?2)
+               AND NOT EXISTS (SELECT 1 FROM ancestors WHERE id = ?2)",
+            params![source_id, target_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if inherited_parent.is_some() {
+        set_tag_parent(conn, target_id, inherited_parent)?;
+    }
+    delete_tag(conn, source_id)
+}
+
+/// Attaches every tag to every file, creating missing file and tag records.
+/// Each tag is given as its hierarchy path and only the leaf is attached.
+pub fn add_tags_to_files(conn: &Connection, paths: &[String], tags: &[Vec<String>]) -> rusqlite::Result<()> {
+    register_files(conn, paths)?;
+    let mut stmt = conn.prepare_cached(
+        "INSERT OR IGNORE INTO file_tags (file_id, tag_id)
+         SELECT id, ?2 FROM files WHERE path = ?1",
+    )?;
+    for tag in tags {
+        let tag_id = ensure_tag_path(conn, tag)?;
+        for path in paths {
+            stmt.execute(params![path, tag_id])?;
+        }
+    }
+    for path in paths {
+        apply_implications(conn, Some(path))?;
+    }
+    Ok(())
+}
+
+pub fn remove_tags_from_files(conn: &Connection, paths: &[String], tags: &[String]) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "DELETE FROM file_tags
+         WHERE file_id = (SELECT id FROM files WHERE path = ?1)
+           AND tag_id = (SELECT id FROM tags WHERE name = ?2)",
+    )?;
+    for tag in tags {
+        let tag = resolve_alias(conn, tag)?;
+        for path in paths {
+            stmt.execute(params![path, tag])?;
+        }
+    }
+    Ok(())
+}
+
+pub fn list_tag_rules(conn: &Connection) -> rusqlite::Result<TagRules> {
+    let aliases = conn
+        .prepare_cached(
+            "SELECT a.alias, t.name FROM tag_aliases a JOIN tags t ON t.id = a.tag_id ORDER BY a.alias",
+        )?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    let implications = conn
+        .prepare_cached(
+            "SELECT t.name, implied.name FROM tag_implications ti
+             JOIN tags t ON t.id = ti.tag_id
+             JOIN tags implied ON implied.id = ti.implied_tag_id
+             ORDER BY t.name, implied.name",
+        )?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(TagRules { aliases, implications })
+}
+
+/// Makes `alias` stand for `target`. An existing tag named like the alias is merged into the target.
+pub fn add_alias(conn: &Connection, alias: &str, target: &str) -> rusqlite::Result<()> {
+    let target_id = ensure_tag(conn, target)?;
+    let target_name: String = conn.query_row("SELECT name FROM tags WHERE id = ?1", [target_id], |row| row.get(0))?;
+    if target_name == alias {
+        return Err(rule_violation(format!("\"{}\" cannot be an alias of itself", alias)));
+    }
+
+    let existing: Option<i64> = conn
+        .query_row("SELECT id FROM tags WHERE name = ?1", [alias], |row| row.get(0))
+        .optional()?;
+    if let Some(existing_id) = existing {
+        merge_tags(conn, existing_id, target_id)?;
+    }
+
+    conn.execute(
+        "INSERT INTO tag_aliases (alias, tag_id) VALUES (?1, ?2)
+         ON CONFLICT(alias) DO UPDATE SET tag_id = excluded.tag_id",
+        params![alias, target_id],
+    )?;
+    Ok(())
+}
+
+pub fn remove_alias(conn: &Connection, alias: &str) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM tag_aliases WHERE alias = ?1", [alias])?;
+    Ok(())
+}
+
+/// Adds the rule "tagging `tag` also tags `implied`", refusing rules that would form a cycle.
+pub fn add_implication(conn: &Connection, tag: &str, implied: &str) -> rusqlite::Result<()> {
+    let tag_id = ensure_tag(conn, tag)?;
+    let implied_id = ensure_tag(conn, implied)?;
+
+    let creates_cycle: bool = conn.query_row(
+        "WITH RECURSIVE reachable(id) AS (
+             SELECT ?2
+             UNION SELECT ti.implied_tag_id FROM tag_implications ti JOIN reachable r ON ti.tag_id = r.id
+         )
+         SELECT EXISTS (SELECT 1 FROM reachable WHERE id = ?1)",
+        params![tag_id, implied_id],
+        |row| row.get(0),
+    )?;
+    if creates_cycle {
+        return Err(rule_violation(format!("\"{}\" already follows from \"{}\", this rule would form a cycle", tag, implied)));
+    }
+
+    conn.execute(
+        "INSERT OR IGNORE INTO tag_implications (tag_id, implied_tag_id) VALUES (?1, ?2)",
+        params![tag_id, implied_id],
+    )?;
+    Ok(())
+}
+
+pub fn remove_implication(conn: &Connection, tag: &str, implied: &str) -> rusqlite::Result<()> {
+    conn.execute(
+        "DELETE FROM tag_implications
+         WHERE tag_id = (SELECT id FROM tags WHERE name = ?1)
+           AND implied_tag_id = (SELECT id FROM tags WHERE name = ?2)",
+        params![tag, implied],
+    )?;
+    Ok(())
+}
+
+/// Adds every tag implied (transitively) by the tags already on a file.
+/// `None` backfills the whole library. Returns how many links were added.
+pub fn apply_implications(conn: &Connection, path: Option<&str>) -> rusqlite::Result<usize> {
+    conn.execute(
+        "WITH RECURSIVE implied(file_id, tag_id) AS (
+             SELECT ft.file_id, ft.tag_id FROM file_tags ft
+             JOIN files f ON f.id = ft.file_id
+             WHERE ?1 IS NULL OR f.path = ?1
+             UNION SELECT i.file_id, ti.implied_tag_id FROM implied i
+             JOIN tag_implications ti ON ti.tag_id = i.tag_id
+         )
+         INSERT OR IGNORE INTO file_tags (file_id, tag_id) SELECT file_id, tag_id FROM implied",
+        [path],
+    )
+}
+
+/// The `(size, mtime)` each path was last hashed at, for paths that have a hash.
+pub fn file_hash_stamps(conn: &Connection, paths: &[String]) -> rusqlite::Result<HashMap<String, (i64, i64)>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT h.size, h.mtime FROM file_hashes h JOIN files f ON f.id = h.file_id WHERE f.path = ?1",
+    )?;
+    let mut stamps = HashMap::new();
+    for path in paths {
+        if let Some(stamp) = stmt.query_row([path], |row| Ok((row.get(0)?, row.get(1)?))).optional()? {
+            stamps.insert(path.clone(), stamp);
+        }
+    }
+    Ok(stamps)
+}
+
+/// Paths of the records whose content hash is one of `hashes`.
+pub fn paths_with_hashes(conn: &Connection, hashes: &[String]) -> rusqlite::Result<Vec<String>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT f.path FROM files f JOIN file_hashes h ON h.file_id = f.id WHERE h.content_hash = ?1",
+    )?;
+    let mut paths = Vec::new();
+    for content_hash in hashes {
+        for path in stmt.query_map([content_hash], |row| row.get(0))? {
+            paths.push(path?);
+        }
+    }
+    Ok(paths)
+}
+
+/// Stores the content hash of `path`. If another record with the same hash points at one of
+/// the `gone` paths, that record (and its tags) is moved to `path` instead, so tags survive
+/// renames and moves. Returns whether such a re-link happened.
+pub fn record_file_hash(
+    conn: &Connection,
+    path: &str,
+    size: i64,
+    mtime: i64,
+    content_hash: &str,
+    gone: &HashSet<String>,
+) -> rusqlite::Result<bool> {
+    let file_id = ensure_file(conn, path)?;
+
+    let candidates = conn
+        .prepare_cached(
+            "SELECT f.id, f.path FROM files f JOIN file_hashes h ON h.file_id = f.id
+             WHERE h.content_hash = ?1 AND f.id != ?2",
+        )?
+        .query_map(params![content_hash, file_id], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let missing = candidates.into_iter().find(|(_, old_path)| gone.contains(old_path));
+
+    let target_id = match missing {
+        Some((old_id, _)) => {
+            // Keep the old uuid, carrying over anything already attached to the new record
+            conn.execute(
+                "INSERT OR IGNORE INTO file_tags (file_id, tag_id) SELECT ?2, tag_id FROM file_tags WHERE file_id = ?1",
+                params![file_id, old_id],
+            )?;
+            conn.execute("DELETE FROM files WHERE id = ?1", [&file_id])?;
+            conn.execute("UPDATE files SET path = ?1 WHERE id = ?2", params![path, old_id])?;
+            old_id
+        }
+        None => file_id.clone(),
+    };
+
+    conn.execute(
+        "INSERT INTO file_hashes (file_id, size, mtime, content_hash) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(file_id) DO UPDATE SET size = excluded.size, mtime = excluded.mtime, content_hash = excluded.content_hash",
+        params![target_id, size, mtime, content_hash],
+    )?;
+    Ok(target_id != file_id)
+}
+
+/// The mtime of each path's XMP sidecar when it was last imported or written.
+pub fn sidecar_stamps(conn: &Connection, paths: &[String]) -> rusqlite::Result<HashMap<String, i64>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT x.mtime FROM xmp_sidecars x JOIN files f ON f.id = x.file_id WHERE f.path = ?1",
+    )?;
+    let mut stamps = HashMap::new();
+    for path in paths {
+        if let Some(mtime) = stmt.query_row([path], |row| row.get(0)).optional()? {
+            stamps.insert(path.clone(), mtime);
+        }
+    }
+    Ok(stamps)
+}
+
+pub fn record_sidecar_stamp(conn: &Connection, path: &str, mtime: i64) -> rusqlite::Result<()> {
+    let file_id = ensure_file(conn, path)?;
+    conn.execute(
+        "INSERT INTO xmp_sidecars (file_id, mtime) VALUES (?1, ?2)
+         ON CONFLICT(file_id) DO UPDATE SET mtime = excluded.mtime",
+        params![file_id, mtime],
+    )?;
+    Ok(())
+}
+
+/// Which of `paths` already had their embedded keywords and EXIF fields imported.
+pub fn embedded_metadata_read(conn: &Connection, paths: &[String]) -> rusqlite::Result<HashSet<String>> {
+    let mut stmt = conn.prepare_cached(
+        "SELECT 1 FROM embedded_metadata m JOIN files f ON f.id = m.file_id WHERE f.path = ?1",
+    )?;
+    let mut done = HashSet::new();
+    for path in paths {
+        if stmt.exists([path])? {
+            done.insert(path.clone());
+        }
+    }
+    Ok(done)
+}
+
+pub fn record_embedded_metadata_read(conn: &Connection, path: &str) -> rusqlite::Result<()> {
+    let file_id = ensure_file(conn, path)?;
+    conn.execute(
+        "INSERT OR REPLACE INTO embedded_metadata (file_id, read_at) VALUES (?1, ?2)",
+        params![file_id, now_unix()],
+    )?;
+    Ok(())
+}
+
+/// The tags of `path`, each as its hierarchy path from the root down, sorted by leaf name.
+pub fn tag_paths_for_path(conn: &Connection, path: &str) -> rusqlite::Result<Vec<Vec<String>>> {
+    let mut stmt = conn.prepare_cached(
+        "WITH RECURSIVE chain(leaf, id, depth) AS (
+             SELECT ft.tag_id, ft.tag_id, 0 FROM file_tags ft
+             JOIN files f ON f.id = ft.file_id WHERE f.path = ?1
+             UNION ALL
+             SELECT c.leaf, tp.parent_id, c.depth + 1 FROM chain c
+             JOIN tag_parents tp ON tp.tag_id = c.id
+         )
+         SELECT c.leaf, t.name FROM chain c JOIN tags t ON t.id = c.id
+         ORDER BY c.leaf, c.depth DESC",
+    )?;
+    let mut paths: Vec<(i64, Vec<String>)> = Vec::new();
+    for row in stmt.query_map([path], |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)))? {
+        let (leaf, name) = row?;
+        match paths.last_mut() {
+            Some((last, names)) if *last == leaf => names.push(name),
+            _ => paths.push((leaf, vec![name])),
+        }
+    }
+    let mut paths: Vec<Vec<String>> = paths.into_iter().map(|(_, names)| names).collect();
+    paths.sort_by(|a, b| a.last().cmp(&b.last()));
+    Ok(paths)
+}
+
+pub fn ensure_file(conn: &Connection, path: &str) -> rusqlite::Result<String> {
+    register_files(conn, &[path.to_string()])?;
+    conn.query_row("SELECT id FROM files WHERE path = ?1", [path], |row| row.get(0))
+}
+
+/// Points the record of `from` at `to`, keeping its uuid and tags.
+pub fn rename_file_path(conn: &Connection, from: &str, to: &str) -> rusqlite::Result<()> {
+    let Some(from_id) = conn
+        .query_row("SELECT id FROM files WHERE path = ?1", [from], |row| row.get::<_, String>(0))
+        .optional()?
+    else {
+        return Ok(());
+    };
+    // A record may already exist for the new path if the create was seen first
+    if let Some(to_id) = conn
+        .query_row("SELECT id FROM files WHERE path = ?1", [to], |row| row.get::<_, String>(0))
+        .optional()?
+    {
+        conn.execute(
+            "INSERT OR IGNORE INTO file_tags (file_id, tag_id) SELECT ?2, tag_id FROM file_tags WHERE file_id = ?1",
+            params![to_id, from_id],
+        )?;
+        conn.execute("DELETE FROM files WHERE id = ?1", [to_id])?;
+    }
+    conn.execute("UPDATE files SET path = ?1 WHERE id = ?2", params![to, from_id])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag_id(conn: &Connection, name: &str) -> i64 {
+        conn.query_row("SELECT id FROM tags WHERE name = ?1", [name], |row| row.get(0)).unwrap()
+    }
+
+    fn tag_exists(conn: &Connection, name: &str) -> bool {
+        conn.query_row("SELECT EXISTS (SELECT 1 FROM tags WHERE name = ?1)", [name], |row| row.get(0)).unwrap()
+    }
+
+    fn parent_of(conn: &Connection, name: &str) -> Option<String> {
+        conn.query_row(
+            "SELECT p.name FROM tag_parents tp JOIN tags t ON t.id = tp.tag_id JOIN tags p ON p.id = tp.parent_id
+             WHERE t.name = ?1",
+            [name],
+            |row| row.get(0),
+        )
+        .optional()
+        .unwrap()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(a, b)| (a.to_string(), b.to_string())).collect()
+    }
+
+    fn is_rule_violation(result: rusqlite::Result<()>) -> bool {
+        matches!(result, Err(rusqlite::Error::SqliteFailure(e, Some(_))) if e.code == rusqlite::ErrorCode::ConstraintViolation)
+    }
+
+    #[test]
+    fn parents_cannot_form_cycles() {
+        let conn = open_in_memory();
+        let cat = ensure_tag_path(&conn, &["animal".to_string(), "mammal".to_string(), "cat".to_string()]).unwrap();
+        let animal = tag_id(&conn, "animal");
+        assert!(is_rule_violation(set_tag_parent(&conn, animal, Some(cat))));
+        assert!(is_rule_violation(set_tag_parent(&conn, animal, Some(animal))));
+        assert_eq!(parent_of(&conn, "animal"), None);
+
+        // Moving within the tree is fine
+        set_tag_parent(&conn, cat, Some(animal)).unwrap();
+        assert_eq!(parent_of(&conn, "cat").as_deref(), Some("animal"));
+    }
+
+    #[test]
+    fn implications_cannot_form_cycles() {
+        let conn = open_in_memory();
+        add_implication(&conn, "kitten", "cat").unwrap();
+        add_implication(&conn, "cat", "pet").unwrap();
+        assert!(is_rule_violation(add_implication(&conn, "pet", "kitten")));
+        assert!(is_rule_violation(add_implication(&conn, "cat", "cat")));
+        assert_eq!(list_tag_rules(&conn).unwrap().implications, pairs(&[("cat", "pet"), ("kitten", "cat")]));
+    }
+
+    #[test]
+    fn implications_apply_transitively() {
+        let conn = open_in_memory();
+        add_implication(&conn, "kitten", "cat").unwrap();
+        add_implication(&conn, "cat", "pet").unwrap();
+        add_tags_to_files(&conn, &["/a.jpg".to_string()], &[vec!["kitten".to_string()]]).unwrap();
+        let tags = tags_for_paths(&conn, &["/a.jpg".to_string()]).unwrap();
+        let mut tags = tags["/a.jpg"].clone();
+        tags.sort();
+        assert_eq!(tags, ["cat", "kitten", "pet"]);
+    }
+
+    #[test]
+    fn a_tag_cannot_be_its_own_alias() {
+        let conn = open_in_memory();
+        assert!(is_rule_violation(add_alias(&conn, "cat", "cat")));
+        add_alias(&conn, "kitty", "cat").unwrap();
+        // Its alias leads straight back to it
+        assert!(is_rule_violation(add_alias(&conn, "cat", "kitty")));
+        assert_eq!(list_tag_rules(&conn).unwrap().aliases, pairs(&[("kitty", "cat")]));
+    }
+
+    #[test]
+    fn aliasing_an_existing_tag_merges_it() {
+        let conn = open_in_memory();
+        add_tags_to_files(&conn, &["/a.jpg".to_string()], &[vec!["kitty".to_string()]]).unwrap();
+        add_tags_to_files(&conn, &["/b.jpg".to_string()], &[vec!["cat".to_string()]]).unwrap();
+        add_alias(&conn, "kitty", "cat").unwrap();
+
+        assert!(!tag_exists(&conn, "kitty"));
+        assert_eq!(resolve_alias(&conn, "kitty").unwrap(), "cat");
+        let tags = tags_for_paths(&conn, &["/a.jpg".to_string(), "/b.jpg".to_string()]).unwrap();
+        assert_eq!(tags["/a.jpg"], ["cat"]);
+        assert_eq!(tags["/b.jpg"], ["cat"]);
+    }
+
+    #[test]
+    fn merging_carries_files_children_aliases_and_implications_over() {
+        let conn = open_in_memory();
+        let kitten = ensure_tag_path(&conn, &["animal".to_string(), "kitten".to_string()]).unwrap();
+        ensure_tag_path(&conn, &["kitten".to_string(), "tabby".to_string()]).unwrap();
+        let cat = ensure_tag(&conn, "cat").unwrap();
+        add_tags_to_files(&conn, &["/a.jpg".to_string()], &[vec!["kitten".to_string()]]).unwrap();
+        add_alias(&conn, "kitty", "kitten").unwrap();
+        add_implication(&conn, "kitten", "pet").unwrap();
+        add_implication(&conn, "cub", "kitten").unwrap();
+        // Would become "cat implies cat" after the merge
+        add_implication(&conn, "cat", "kitten").unwrap();
+
+        merge_tags(&conn, kitten, cat).unwrap();
+
+        assert!(!tag_exists(&conn, "kitten"));
+        assert_eq!(tags_for_paths(&conn, &["/a.jpg".to_string()]).unwrap()["/a.jpg"], ["cat"]);
+        assert_eq!(parent_of(&conn, "tabby").as_deref(), Some("cat"));
+        // The target had no parent, so it takes over the source's place
+        assert_eq!(parent_of(&conn, "cat").as_deref(), Some("animal"));
+        let rules = list_tag_rules(&conn).unwrap();
+        assert_eq!(rules.aliases, pairs(&[("kitty", "cat")]));
+        assert_eq!(rules.implications, pairs(&[("cat", "pet"), ("cub", "cat")]));
+    }
+
+    #[test]
+    fn merging_keeps_the_targets_own_parent() {
+        let conn = open_in_memory();
+        let kitten = ensure_tag_path(&conn, &["young".to_string(), "kitten".to_string()]).unwrap();
+        let cat = ensure_tag_path(&conn, &["animal".to_string(), "cat".to_string()]).unwrap();
+        merge_tags(&conn, kitten, cat).unwrap();
+        assert_eq!(parent_of(&conn, "cat").as_deref(), Some("animal"));
+    }
+
+    #[test]
+    fn merging_into_an_ancestor_leaves_it_a_root() {
+        let conn = open_in_memory();
+        let cat = ensure_tag_path(&conn, &["animal".to_string(), "mammal".to_string(), "cat".to_string()]).unwrap();
+        let animal = tag_id(&conn, "animal");
+        let mammal = tag_id(&conn, "mammal");
+        merge_tags(&conn, mammal, animal).unwrap();
+        assert_eq!(parent_of(&conn, "animal"), None);
+        assert_eq!(parent_of(&conn, "cat").as_deref(), Some("animal"));
+        // The other way round the target would end up below itself
+        assert!(is_rule_violation(merge_tags(&conn, animal, cat)));
+    }
+}
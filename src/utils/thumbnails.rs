@@ -0,0 +1,189 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex, Once};
+use std::time::{SystemTime, UNIX_EPOCH};
+use image::{DynamicImage, ImageFormat};
+use tokio::sync::Semaphore;
+use crate::app::freedesktop_thumbnails;
+
+type ThumbnailError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Thumbnail sizes are rounded up to a multiple of this, so dragging the size slider
+/// reuses thumbnails instead of making new ones for every step.
+const SIZE_STEP: u32 = 64;
+
+const MAX_SIZE: u32 = 1024;
+
+/// Most bytes of thumbnails kept on disk. Going over removes the least recently used ones
+/// until a quarter of it is free again.
+const MAX_CACHE_BYTES: u64 = 1024 * 1024 * 1024;
+
+/// Bytes in the disk cache as of the last sweep plus whatever was written since.
+static CACHE_BYTES: AtomicU64 = AtomicU64::new(0);
+static FIRST_SWEEP: Once = Once::new();
+/// Held while sweeping, so workers finishing together do not all sweep at once.
+static SWEEPING: Mutex<()> = Mutex::new(());
+
+/// Thumbnails decoded at once. Decoding is CPU bound, so more would only slow each other down.
+static WORKERS: LazyLock<Semaphore> =
+    LazyLock::new(|| Semaphore::new(std::thread::available_parallelism().map_or(4, |n| n.get())));
+
+/// A thumbnail ready to show.
+pub struct Thumbnail {
+    /// Identifies the thumbnail to egui's texture cache. Differs per size and file version,
+    /// so a stale texture is never reused.
+    pub uri: String,
+    pub bytes: Vec<u8>,
+}
+
+/// Longest side in pixels of the thumbnails for tiles of `box_size` points.
+pub fn thumbnail_size(box_size: f32, pixels_per_point: f32) -> u32 {
+    let pixels = (box_size * pixels_per_point).ceil().max(1.0) as u32;
+    pixels.div_ceil(SIZE_STEP).saturating_mul(SIZE_STEP).min(MAX_SIZE)
+}
+
+/// Whether the image crate can make a thumbnail of `path`. Others are shown from the original file.
+pub fn can_thumbnail(path: &str) -> bool {
+    ImageFormat::from_path(path).is_ok_and(|format| format.reading_enabled())
+}
+
+/// Where thumbnails are kept between runs, next to eframe's own app state.
+fn cache_dir() -> Option<PathBuf> {
+    eframe::storage_dir("taggerrs").map(|dir| dir.join("thumbnails"))
+}
+
+/// Cache file name for `path` at `size`, without extension. Includes the modification
+/// time and length, so an edited file gets a new thumbnail.
+fn cache_key(path: &str, metadata: &std::fs::Metadata, size: u32) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos());
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(path.as_bytes());
+    hasher.update(&modified.to_le_bytes());
+    hasher.update(&metadata.len().to_le_bytes());
+    hasher.update(&size.to_le_bytes());
+    hasher.finalize().to_hex().to_string()
+}
+
+/// The thumbnail of `path` with its longest side at most `size`, from the disk cache if it
+/// has one, otherwise decoded on a worker thread and cached.
+pub async fn load_thumbnail(path: &str, size: u32) -> Result<Thumbnail, ThumbnailError> {
+    let _permit = WORKERS.acquire().await?;
+    let path = path.to_string();
+    tokio::task::spawn_blocking(move || thumbnail_blocking(&path, size)).await?
+}
+
+fn thumbnail_blocking(path: &str, size: u32) -> Result<Thumbnail, ThumbnailError> {
+    let metadata = std::fs::metadata(path)?;
+    let key = cache_key(path, &metadata, size);
+    let cache_dir = cache_dir();
+
+    if let Some(dir) = &cache_dir {
+        // Counts what earlier runs left behind before anything is added
+        FIRST_SWEEP.call_once(|| sweep_cache(dir));
+        for extension in ["jpg", "png"] {
+            let cached = dir.join(format!("{key}.{extension}"));
+            if let Ok(bytes) = std::fs::read(&cached) {
+                // The modification time doubles as the last use, which is what the sweep goes by
+                let _ = std::fs::File::options().write(true).open(&cached).and_then(|file| file.set_modified(SystemTime::now()));
+                return Ok(Thumbnail { uri: thumbnail_uri(&key, extension), bytes });
+            }
+        }
+    }
+    // File managers may have made one already
+    if let Some(bytes) = freedesktop_thumbnails::read(path, &metadata, size) {
+        return Ok(Thumbnail { uri: thumbnail_uri(&key, "png"), bytes });
+    }
+
+    let original = image::open(path)?;
+    // Shared with file managers in turn; a failed write only means they make their own
+    if let Some((folder, pixels)) = freedesktop_thumbnails::standard_size(size) {
+        let _ = freedesktop_thumbnails::write(path, &metadata, folder, &shrink(&original, pixels));
+    }
+    let image = shrink(&original, size);
+    // Photos compress far better as JPEG; images with transparency keep it as PNG
+    let (format, extension) = if image.color().has_alpha() { (ImageFormat::Png, "png") } else { (ImageFormat::Jpeg, "jpg") };
+    let image = if format == ImageFormat::Jpeg { DynamicImage::ImageRgb8(image.to_rgb8()) } else { image };
+    let mut bytes = Vec::new();
+    image.write_to(&mut Cursor::new(&mut bytes), format)?;
+
+    if let Some(dir) = &cache_dir {
+        // A failed write only costs decoding again next time
+        if write_atomically(dir, &format!("{key}.{extension}"), &bytes, None).is_ok()
+            && CACHE_BYTES.fetch_add(bytes.len() as u64, Ordering::Relaxed) + bytes.len() as u64 > MAX_CACHE_BYTES
+        {
+            sweep_cache(dir);
+        }
+    }
+    Ok(Thumbnail { uri: thumbnail_uri(&key, extension), bytes })
+}
+
+/// Measures the disk cache and, if it is over [`MAX_CACHE_BYTES`], removes the thumbnails
+/// used longest ago.
+fn sweep_cache(dir: &Path) {
+    let Ok(_sweeping) = SWEEPING.try_lock() else {
+        return;
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    let mut files: Vec<(SystemTime, u64, PathBuf)> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok().filter(|metadata| metadata.is_file())?;
+            Some((metadata.modified().unwrap_or(UNIX_EPOCH), metadata.len(), entry.path()))
+        })
+        .collect();
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    if total > MAX_CACHE_BYTES {
+        files.sort_unstable_by_key(|(modified, _, _)| *modified);
+        for (_, len, path) in files {
+            if total <= MAX_CACHE_BYTES / 4 * 3 {
+                break;
+            }
+            if std::fs::remove_file(path).is_ok() {
+                total -= len;
+            }
+        }
+    }
+    CACHE_BYTES.store(total, Ordering::Relaxed);
+}
+
+/// `image` scaled down to fit in `size` by `size`; smaller images are kept as they are.
+fn shrink(image: &DynamicImage, size: u32) -> DynamicImage {
+    if image.width() > size || image.height() > size { image.thumbnail(size, size) } else { image.clone() }
+}
+
+/// The extension tells egui which decoder to use.
+fn thumbnail_uri(key: &str, extension: &str) -> String {
+    format!("thumbnail://{key}.{extension}")
+}
+
+/// Writes through a temporary file, so a concurrent reader never sees half a thumbnail.
+/// With a unix `mode`, the file is created with it, so it never exists with looser permissions.
+pub fn write_atomically(dir: &Path, name: &str, bytes: &[u8], mode: Option<u32>) -> std::io::Result<()> {
+    use std::io::Write;
+
+    std::fs::create_dir_all(dir)?;
+    let temporary = dir.join(format!("{name}.{}.tmp", uuid::Uuid::new_v4()));
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    options
+        .open(&temporary)
+        .and_then(|mut file| file.write_all(bytes))
+        .and_then(|()| std::fs::rename(&temporary, dir.join(name)))
+        .inspect_err(|_| {
+            let _ = std::fs::remove_file(&temporary);
+        })
+}
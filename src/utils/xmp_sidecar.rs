@@ -0,0 +1,662 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::name::{Namespace, ResolveResult};
+use quick_xml::{NsReader, Writer};
+use rusqlite::{Connection, Transaction};
+use tokio::sync::Mutex;
+use crate::app::directory_scan;
+use crate::app::tag_store::{self, TagStore};
+use crate::app::file_identity;
+use crate::app::journal;
+use crate::app::thumbnails;
+
+const NS_RDF: &[u8] = b"http://www.w3.org/1999/02/22-rdf-syntax-ns#";
+const NS_DC: &[u8] = b"http://purl.org/dc/elements/1.1/";
+const NS_LR: &[u8] = b"http://ns.adobe.com/lightroom/1.0/";
+
+/// How many files have their sidecar keywords imported per transaction.
+const IMPORT_BATCH_SIZE: usize = 64;
+
+/// Written when a file has no sidecar yet; the keywords go inside the description.
+const EMPTY_SIDECAR: &str = r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/" x:xmptk="taggerrs">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:lr="http://ns.adobe.com/lightroom/1.0/">
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>
+"#;
+
+/// The keyword lists of an XMP packet: flat `dc:subject` entries and
+/// `lr:hierarchicalSubject` entries such as `animal|mammal|cat`, split on `|`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SidecarKeywords {
+    pub subjects: Vec<String>,
+    pub hierarchical: Vec<Vec<String>>,
+}
+
+impl SidecarKeywords {
+    /// Builds the keywords for a file from its tags, each given as its hierarchy path.
+    /// Underscores become spaces again so other programs show readable keywords.
+    pub fn from_tag_paths(tags: &[Vec<String>]) -> Self {
+        let readable = |name: &String| name.replace('_', " ");
+        let mut subjects = Vec::new();
+        for path in tags {
+            if let Some(leaf) = path.last().map(readable) && !subjects.contains(&leaf) {
+                subjects.push(leaf);
+            }
+        }
+        let hierarchical = tags.iter().map(|path| path.iter().map(readable).collect()).collect();
+        Self { subjects, hierarchical }
+    }
+
+    /// The tags these keywords describe as normalized hierarchy paths, skipping blank ones.
+    pub fn tag_paths(&self) -> Vec<Vec<String>> {
+        let mut paths: Vec<Vec<String>> = Vec::new();
+        let hierarchical = self.hierarchical.iter().filter_map(|levels| {
+            levels.iter().map(|name| tag_store::normalize_tag_name(name)).collect::<Option<Vec<_>>>()
+        });
+        let flat = self.subjects.iter().filter_map(|name| tag_store::normalize_tag_name(name)).map(|name| vec![name]);
+        for path in hierarchical.chain(flat) {
+            if !path.is_empty() && !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        paths
+    }
+
+    fn is_empty(&self) -> bool {
+        self.subjects.is_empty() && self.hierarchical.is_empty()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum KeywordList {
+    Subject,
+    Hierarchical,
+}
+
+fn keyword_list(namespace: &ResolveResult, local_name: &[u8]) -> Option<KeywordList> {
+    match (namespace, local_name) {
+        (ResolveResult::Bound(Namespace(NS_DC)), b"subject") => Some(KeywordList::Subject),
+        (ResolveResult::Bound(Namespace(NS_LR)), b"hierarchicalSubject") => Some(KeywordList::Hierarchical),
+        _ => None,
+    }
+}
+
+fn is_rdf(namespace: &ResolveResult, local_name: &[u8], expected: &[u8]) -> bool {
+    matches!(namespace, ResolveResult::Bound(Namespace(NS_RDF))) && local_name == expected
+}
+
+/// Reads the `dc:subject` and `lr:hierarchicalSubject` keywords of an XMP packet.
+pub fn read_keywords(xml: &str) -> Result<SidecarKeywords, quick_xml::Error> {
+    let mut reader = NsReader::from_str(xml);
+    let mut keywords = SidecarKeywords::default();
+    let mut list = None;
+    let mut item: Option<String> = None;
+
+    loop {
+        match reader.read_resolved_event()? {
+            (namespace, Event::Start(e)) => {
+                let local_name = e.local_name();
+                if let Some(kind) = keyword_list(&namespace, local_name.as_ref()) {
+                    list = Some(kind);
+                } else if list.is_some() && is_rdf(&namespace, local_name.as_ref(), b"li") {
+                    item = Some(String::new());
+                }
+            }
+            (namespace, Event::End(e)) => {
+                let local_name = e.local_name();
+                if keyword_list(&namespace, local_name.as_ref()).is_some() {
+                    list = None;
+                } else if is_rdf(&namespace, local_name.as_ref(), b"li")
+                    && let (Some(kind), Some(text)) = (list, item.take())
+                {
+                    let text = text.trim();
+                    if text.is_empty() {
+                        continue;
+                    }
+                    match kind {
+                        KeywordList::Subject => keywords.subjects.push(text.to_string()),
+                        KeywordList::Hierarchical => {
+                            keywords.hierarchical.push(text.split('|').map(|level| level.trim().to_string()).collect())
+                        }
+                    }
+                }
+            }
+            (_, Event::Text(e)) => {
+                if let Some(item) = &mut item {
+                    item.push_str(&e.decode()?);
+                }
+            }
+            (_, Event::CData(e)) => {
+                if let Some(item) = &mut item {
+                    item.push_str(&e.decode()?);
+                }
+            }
+            (_, Event::GeneralRef(e)) => {
+                if let Some(item) = &mut item {
+                    match e.resolve_char_ref()? {
+                        Some(c) => item.push(c),
+                        None => item.push_str(&quick_xml::escape::unescape(&format!("&{};", e.decode()?))?),
+                    }
+                }
+            }
+            (_, Event::Eof) => break,
+            _ => {}
+        }
+    }
+    Ok(keywords)
+}
+
+fn write_keyword_lists(writer: &mut Writer<Vec<u8>>, keywords: &SidecarKeywords) -> std::io::Result<()> {
+    let lists = [
+        ("dc:subject", keywords.subjects.clone()),
+        (
+            "lr:hierarchicalSubject",
+            keywords.hierarchical.iter().map(|levels| levels.join("|")).collect(),
+        ),
+    ];
+    for (name, entries) in lists {
+        if entries.is_empty() {
+            continue;
+        }
+        writer.write_event(Event::Text(BytesText::new("\n   ")))?;
+        writer.write_event(Event::Start(BytesStart::new(name)))?;
+        writer.write_event(Event::Text(BytesText::new("\n    ")))?;
+        writer.write_event(Event::Start(BytesStart::new("rdf:Bag")))?;
+        for entry in &entries {
+            writer.write_event(Event::Text(BytesText::new("\n     ")))?;
+            writer.write_event(Event::Start(BytesStart::new("rdf:li")))?;
+            writer.write_event(Event::Text(BytesText::new(entry)))?;
+            writer.write_event(Event::End(BytesEnd::new("rdf:li")))?;
+        }
+        writer.write_event(Event::Text(BytesText::new("\n    ")))?;
+        writer.write_event(Event::End(BytesEnd::new("rdf:Bag")))?;
+        writer.write_event(Event::Text(BytesText::new("\n   ")))?;
+        writer.write_event(Event::End(BytesEnd::new(name)))?;
+    }
+    Ok(())
+}
+
+/// Declares the `dc` and `lr` prefixes on a description unless it already does.
+fn with_keyword_namespaces(description: BytesStart<'_>) -> BytesStart<'static> {
+    let mut description = description.into_owned();
+    let declared: Vec<Vec<u8>> = description
+        .attributes()
+        .filter_map(Result::ok)
+        .map(|attribute| attribute.key.as_ref().to_vec())
+        .collect();
+    for (key, value) in [("xmlns:dc", NS_DC), ("xmlns:lr", NS_LR)] {
+        if !declared.iter().any(|existing| existing == key.as_bytes()) {
+            description.push_attribute((key.as_bytes(), value));
+        }
+    }
+    description
+}
+
+/// Replaces the keyword lists of an XMP packet (or of a new one when `existing` is `None`),
+/// leaving every other property as it was. The lists go into the first `rdf:Description`.
+pub fn write_keywords(existing: Option<&str>, keywords: &SidecarKeywords) -> Result<String, quick_xml::Error> {
+    let mut reader = NsReader::from_str(existing.unwrap_or(EMPTY_SIDECAR));
+    let mut writer = Writer::new(Vec::new());
+    // Depth inside a keyword list being dropped, and inside the description we write into
+    let mut skip_depth = 0usize;
+    let mut description_depth = None;
+    let mut written = false;
+    // Indentation is held back so it can be dropped together with a list it belongs to
+    let mut pending_space = None;
+
+    loop {
+        let (namespace, event) = reader.read_resolved_event()?;
+        if skip_depth > 0 {
+            match event {
+                Event::Start(_) => skip_depth += 1,
+                Event::End(_) => skip_depth -= 1,
+                Event::Eof => break,
+                _ => {}
+            }
+            continue;
+        }
+        if let Event::Text(e) = &event && e.iter().all(u8::is_ascii_whitespace) {
+            if let Some(space) = pending_space.replace(event) {
+                writer.write_event(space)?;
+            }
+            continue;
+        }
+
+        let closes_description = matches!(event, Event::End(_)) && description_depth == Some(0);
+        let is_keyword_list = match &event {
+            Event::Start(e) | Event::Empty(e) => keyword_list(&namespace, e.local_name().as_ref()).is_some(),
+            _ => false,
+        };
+        if let Some(space) = pending_space.take()
+            && !closes_description
+            && !is_keyword_list
+        {
+            writer.write_event(space)?;
+        }
+
+        match event {
+            Event::Start(_) if is_keyword_list => skip_depth = 1,
+            Event::Empty(_) if is_keyword_list => {}
+            Event::Start(e) if !written && description_depth.is_none() && is_rdf(&namespace, e.local_name().as_ref(), b"Description") => {
+                description_depth = Some(0usize);
+                writer.write_event(Event::Start(with_keyword_namespaces(e)))?;
+            }
+            Event::Empty(e) if !written && is_rdf(&namespace, e.local_name().as_ref(), b"Description") => {
+                let end = e.name().as_ref().to_vec();
+                writer.write_event(Event::Start(with_keyword_namespaces(e)))?;
+                write_keyword_lists(&mut writer, keywords)?;
+                writer.write_event(Event::Text(BytesText::new("\n  ")))?;
+                writer.write_event(Event::End(BytesEnd::new(String::from_utf8_lossy(&end))))?;
+                written = true;
+            }
+            Event::Start(e) => {
+                if let Some(depth) = &mut description_depth {
+                    *depth += 1;
+                }
+                writer.write_event(Event::Start(e))?;
+            }
+            Event::End(e) => {
+                match description_depth {
+                    Some(0) => {
+                        write_keyword_lists(&mut writer, keywords)?;
+                        writer.write_event(Event::Text(BytesText::new("\n  ")))?;
+                        description_depth = None;
+                        written = true;
+                    }
+                    Some(depth) => description_depth = Some(depth - 1),
+                    None => {}
+                }
+                writer.write_event(Event::End(e))?;
+            }
+            Event::Eof => break,
+            event => writer.write_event(event)?,
+        }
+    }
+    if let Some(space) = pending_space {
+        writer.write_event(space)?;
+    }
+
+    if !written {
+        // Not an XMP packet we can extend, so start over with a fresh one
+        return write_keywords(None, keywords);
+    }
+    Ok(String::from_utf8_lossy(&writer.into_inner()).into_owned())
+}
+
+/// `photo.jpg.xmp` as written by darktable and digiKam, then `photo.xmp` as written by Lightroom.
+fn sidecar_candidates(path: &str) -> [PathBuf; 2] {
+    [PathBuf::from(format!("{}.xmp", path)), Path::new(path).with_extension("xmp")]
+}
+
+/// The existing sidecar of `path`, if there is one.
+pub async fn find_sidecar(path: &str) -> Option<PathBuf> {
+    for candidate in sidecar_candidates(path) {
+        if tokio::fs::metadata(&candidate).await.is_ok_and(|m| m.is_file()) {
+            return Some(candidate);
+        }
+    }
+    None
+}
+
+async fn sidecar_mtime(sidecar: &Path) -> Option<i64> {
+    file_identity::file_stamp(&sidecar.display().to_string()).await.ok().map(|(_, mtime)| mtime)
+}
+
+async fn read_sidecar(sidecar: &Path) -> Option<SidecarKeywords> {
+    let xml = tokio::fs::read_to_string(sidecar).await.ok()?;
+    read_keywords(&xml).ok()
+}
+
+/// Imports the keywords of each file's XMP sidecar as tags. Returns a message for each
+/// file whose keywords could not be imported.
+///
+/// A sidecar is only read again once its mtime changes, so tags removed in taggerrs
+/// do not come back on the next scan unless another program edited the sidecar.
+pub async fn import_sidecars(tag_store: TagStore, paths: Vec<String>) -> Vec<String> {
+    let paths_clone = paths.clone();
+    let stamps = match tag_store.read(move |conn| tag_store::sidecar_stamps(conn, &paths_clone)).await {
+        Ok(stamps) => stamps,
+        Err(e) => return directory_scan::failed_for_each(&paths, e),
+    };
+    let mut problems = Vec::new();
+
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_SIZE);
+    for path in paths {
+        let Some(sidecar) = find_sidecar(&path).await else {
+            continue;
+        };
+        let Some(mtime) = sidecar_mtime(&sidecar).await else {
+            continue;
+        };
+        if stamps.get(&path) == Some(&mtime) {
+            continue;
+        }
+        let Some(keywords) = read_sidecar(&sidecar).await else {
+            continue;
+        };
+        batch.push((path, mtime, keywords.tag_paths()));
+
+        if batch.len() >= IMPORT_BATCH_SIZE {
+            problems.extend(write_imports(&tag_store, std::mem::take(&mut batch)).await);
+        }
+    }
+    if !batch.is_empty() {
+        problems.extend(write_imports(&tag_store, batch).await);
+    }
+    problems
+}
+
+/// Imports a batch in one transaction, each file in its own savepoint so one bad sidecar,
+/// such as one whose hierarchy contradicts another's, does not hold up the rest.
+async fn write_imports(tag_store: &TagStore, batch: Vec<(String, i64, Vec<Vec<String>>)>) -> Vec<String> {
+    let paths: Vec<String> = batch.iter().map(|(path, _, _)| path.clone()).collect();
+    let result = tag_store
+        .write(move |tx| {
+            let mut problems = Vec::new();
+            for (path, mtime, tags) in &batch {
+                let paths = [path.clone()];
+                if let Err(e) = tag_store::in_savepoint(tx, || tag_store::add_tags_to_files(tx, &paths, tags)) {
+                    problems.push(format!("{}: {}", path, e));
+                }
+                // Stamped even when it failed, so it is tried again once the sidecar changes rather than on every scan
+                tag_store::record_sidecar_stamp(tx, path, *mtime)?;
+            }
+            Ok(problems)
+        })
+        .await;
+    result.unwrap_or_else(|e| directory_scan::failed_for_each(&paths, e))
+}
+
+/// The tag paths of every file that has a sidecar, keyed by path.
+fn sidecar_tags(conn: &Connection) -> rusqlite::Result<HashMap<String, Vec<Vec<String>>>> {
+    let paths = conn
+        .prepare_cached("SELECT f.path FROM xmp_sidecars x JOIN files f ON f.id = x.file_id")?
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    paths
+        .into_iter()
+        .map(|path| {
+            let tags = tag_store::tag_paths_for_path(conn, &path)?;
+            Ok((path, tags))
+        })
+        .collect()
+}
+
+/// Runs `edit`, and with `track` set also returns the files whose sidecar keywords it changed,
+/// such as every file below a renamed tag. Only files that have a sidecar are compared.
+pub fn tracking_sidecars<T>(
+    tx: &Transaction,
+    track: bool,
+    edit: impl FnOnce(&Transaction) -> rusqlite::Result<T>,
+) -> rusqlite::Result<(T, Vec<String>)> {
+    if !track {
+        return Ok((edit(tx)?, Vec::new()));
+    }
+    let before = sidecar_tags(tx)?;
+    let result = edit(tx)?;
+    let changed = sidecar_tags(tx)?
+        .into_iter()
+        .filter(|(path, tags)| before.get(path) != Some(tags))
+        .map(|(path, _)| path)
+        .collect();
+    Ok((result, changed))
+}
+
+/// Like [`journal::spawn_journaled_edit`], but with `write_sidecars` set also rewrites the
+/// sidecars whose keywords the edit changed.
+pub fn spawn_journaled_edit_syncing<F>(
+    ctx: &egui::Context,
+    tag_store: &TagStore,
+    runtime: &Arc<tokio::runtime::Runtime>,
+    error: &Arc<Mutex<Option<String>>>,
+    write_sidecars: bool,
+    description: String,
+    edit: F,
+) where
+    F: FnOnce(&Transaction) -> rusqlite::Result<()> + Send + 'static,
+{
+    let store_clone = tag_store.clone();
+    let error_clone = error.clone();
+    let ctx_clone = ctx.clone();
+    runtime.spawn(async move {
+        let result = journal::journaled_edit(&store_clone, &error_clone, description, move |tx| {
+            tracking_sidecars(tx, write_sidecars, edit)
+        })
+        .await;
+        if let Some(((), changed)) = result
+            && let Some(message) = rewrite_sidecars(&store_clone, changed).await
+        {
+            *error_clone.lock().await = Some(message);
+        }
+        ctx_clone.request_repaint();
+    });
+}
+
+/// Writes the sidecars of `paths`, describing any that could not be written.
+pub async fn rewrite_sidecars(tag_store: &TagStore, paths: Vec<String>) -> Option<String> {
+    let failed = write_sidecars(tag_store, paths).await;
+    failed
+        .first()
+        .map(|first| format!("Could not write {} XMP sidecar(s), first: {}", failed.len(), first))
+}
+
+/// Writes the current tags of each file to its XMP sidecar, creating `<file>.xmp` where
+/// there is none. Returns the paths whose sidecar could not be written.
+pub async fn write_sidecars(tag_store: &TagStore, paths: Vec<String>) -> Vec<String> {
+    let mut failed = Vec::new();
+    for path in paths {
+        if write_sidecar(tag_store, &path).await.is_err() {
+            failed.push(path);
+        }
+    }
+    failed
+}
+
+async fn write_sidecar(tag_store: &TagStore, path: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let existing = find_sidecar(path).await;
+    let xml = match &existing {
+        Some(sidecar) => Some(tokio::fs::read_to_string(sidecar).await?),
+        None => None,
+    };
+
+    // Pick up keywords another program added since the last import before overwriting them
+    if let (Some(sidecar), Some(xml)) = (&existing, &xml) {
+        let mtime = sidecar_mtime(sidecar).await;
+        let paths = vec![path.to_string()];
+        let stamps = tag_store.read(move |conn| tag_store::sidecar_stamps(conn, &paths)).await?;
+        if let Some(mtime) = mtime && stamps.get(path) != Some(&mtime) {
+            let tags = read_keywords(xml)?.tag_paths();
+            let paths = [path.to_string()];
+            tag_store.write(move |tx| tag_store::add_tags_to_files(tx, &paths, &tags)).await?;
+        }
+    }
+
+    let path_owned = path.to_string();
+    let tags = tag_store.read(move |conn| tag_store::tag_paths_for_path(conn, &path_owned)).await?;
+    let keywords = SidecarKeywords::from_tag_paths(&tags);
+    if keywords.is_empty() && xml.is_none() {
+        return Ok(());
+    }
+
+    let sidecar = existing.unwrap_or_else(|| sidecar_candidates(path)[0].clone());
+    let xml = write_keywords(xml.as_deref(), &keywords)?;
+    let sidecar_clone = sidecar.clone();
+    tokio::task::spawn_blocking(move || replace_sidecar(&sidecar_clone, xml.as_bytes())).await??;
+
+    // Remember our own write so the next scan does not import it back
+    if let Some(mtime) = sidecar_mtime(&sidecar).await {
+        let path = path.to_string();
+        tag_store.write(move |tx| tag_store::record_sidecar_stamp(tx, &path, mtime)).await?;
+    }
+    Ok(())
+}
+
+/// Writes `sidecar` through a temporary file, so a crash or a full disk never leaves it
+/// truncated. An existing sidecar keeps its permissions.
+fn replace_sidecar(sidecar: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let (Some(dir), Some(name)) = (sidecar.parent(), sidecar.file_name().and_then(|name| name.to_str())) else {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a file path"));
+    };
+    #[cfg(unix)]
+    let mode = std::fs::metadata(sidecar).ok().map(|metadata| {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    });
+    #[cfg(not(unix))]
+    let mode = None;
+    thumbnails::write_atomically(dir, name, bytes, mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A sidecar as darktable writes it, with a Lightroom keyword hierarchy added.
+    const SIDECAR: &str = r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/" x:xmptk="XMP Core 4.4.0-Exiv2">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:darktable="http://darktable.sf.net/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:lr="http://ns.adobe.com/lightroom/1.0/"
+    xmp:Rating="3"
+    darktable:xmp_version="5">
+   <darktable:history>
+    <rdf:Seq>
+     <rdf:li darktable:operation="exposure" darktable:enabled="1"/>
+    </rdf:Seq>
+   </darktable:history>
+   <dc:title>
+    <rdf:Alt>
+     <rdf:li xml:lang="x-default">Pier at dusk</rdf:li>
+    </rdf:Alt>
+   </dc:title>
+   <dc:subject>
+    <rdf:Bag>
+     <rdf:li>beach</rdf:li>
+     <rdf:li> Fish &amp; Chips </rdf:li>
+     <rdf:li>  </rdf:li>
+    </rdf:Bag>
+   </dc:subject>
+   <lr:hierarchicalSubject>
+    <rdf:Bag>
+     <rdf:li>places | coast|beach</rdf:li>
+    </rdf:Bag>
+   </lr:hierarchicalSubject>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>
+"#;
+
+    fn keywords(subjects: &[&str], hierarchical: &[&[&str]]) -> SidecarKeywords {
+        SidecarKeywords {
+            subjects: subjects.iter().map(|s| s.to_string()).collect(),
+            hierarchical: hierarchical.iter().map(|levels| levels.iter().map(|l| l.to_string()).collect()).collect(),
+        }
+    }
+
+    /// The parts of [`SIDECAR`] taggerrs does not own, which every rewrite must keep.
+    fn assert_foreign_content_kept(xml: &str) {
+        assert!(xml.starts_with(r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>"#));
+        assert!(xml.contains(r#"x:xmptk="XMP Core 4.4.0-Exiv2""#));
+        assert!(xml.contains(r#"xmp:Rating="3""#));
+        assert!(xml.contains(r#"darktable:xmp_version="5""#));
+        assert!(xml.contains(r#"<rdf:li darktable:operation="exposure" darktable:enabled="1"/>"#));
+        assert!(xml.contains(r#"<rdf:li xml:lang="x-default">Pier at dusk</rdf:li>"#));
+        assert!(xml.trim_end().ends_with(r#"<?xpacket end="w"?>"#));
+    }
+
+    #[test]
+    fn read_keywords_unescapes_trims_and_splits_levels() {
+        assert_eq!(
+            read_keywords(SIDECAR).unwrap(),
+            keywords(&["beach", "Fish & Chips"], &[&["places", "coast", "beach"]])
+        );
+    }
+
+    #[test]
+    fn write_keywords_replaces_only_the_keyword_lists() {
+        let new = keywords(&["cat", "R&D <draft>"], &[&["animal", "mammal", "cat"]]);
+        let xml = write_keywords(Some(SIDECAR), &new).unwrap();
+        assert_eq!(read_keywords(&xml).unwrap(), new);
+        assert_foreign_content_kept(&xml);
+        assert!(!xml.contains("beach"));
+        assert_eq!(xml.matches("<dc:subject>").count(), 1);
+        assert_eq!(xml.matches("<lr:hierarchicalSubject>").count(), 1);
+        // The namespaces were declared already and are not declared twice
+        assert_eq!(xml.matches("xmlns:dc=").count(), 1);
+    }
+
+    #[test]
+    fn write_keywords_without_keywords_drops_the_lists() {
+        let xml = write_keywords(Some(SIDECAR), &SidecarKeywords::default()).unwrap();
+        assert_eq!(read_keywords(&xml).unwrap(), SidecarKeywords::default());
+        assert_foreign_content_kept(&xml);
+        assert!(!xml.contains("dc:subject"));
+        assert!(!xml.contains("lr:hierarchicalSubject"));
+    }
+
+    #[test]
+    fn write_keywords_is_stable_when_repeated() {
+        let new = keywords(&["cat"], &[&["animal", "cat"]]);
+        let once = write_keywords(Some(SIDECAR), &new).unwrap();
+        assert_eq!(write_keywords(Some(&once), &new).unwrap(), once);
+    }
+
+    #[test]
+    fn write_keywords_opens_an_empty_description_and_declares_namespaces() {
+        let existing = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description rdf:about="" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:Rating="4"/></rdf:RDF></x:xmpmeta>"#;
+        let new = keywords(&["dog"], &[&["animal", "dog"]]);
+        let xml = write_keywords(Some(existing), &new).unwrap();
+        assert_eq!(read_keywords(&xml).unwrap(), new);
+        assert!(xml.contains(r#"xmp:Rating="4""#));
+        assert!(xml.contains(r#"xmlns:dc="http://purl.org/dc/elements/1.1/""#));
+        assert!(xml.contains(r#"xmlns:lr="http://ns.adobe.com/lightroom/1.0/""#));
+    }
+
+    #[test]
+    fn write_keywords_starts_a_new_packet_when_there_is_none() {
+        let new = keywords(&["dog"], &[]);
+        let xml = write_keywords(None, &new).unwrap();
+        assert_eq!(read_keywords(&xml).unwrap(), new);
+        assert_eq!(write_keywords(Some("<notes>no rdf here</notes>"), &new).unwrap(), xml);
+    }
+
+    #[test]
+    fn tag_paths_round_trip_through_keywords() {
+        let tags = vec![vec!["animal".to_string(), "big_cat".to_string()], vec!["holiday".to_string()]];
+        let keywords = SidecarKeywords::from_tag_paths(&tags);
+        assert_eq!(keywords.subjects, ["big cat", "holiday"]);
+        assert_eq!(keywords.hierarchical, [vec!["animal", "big cat"], vec!["holiday"]]);
+        assert_eq!(keywords.tag_paths(), [tags[0].clone(), tags[1].clone(), vec!["big_cat".to_string()]]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn replacing_a_sidecar_keeps_its_permissions_and_leaves_no_temporary_file() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("taggerrs-sidecar-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sidecar = dir.join("photo.jpg.xmp");
+        std::fs::write(&sidecar, "old").unwrap();
+        std::fs::set_permissions(&sidecar, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        replace_sidecar(&sidecar, b"new").unwrap();
+        let contents = std::fs::read_to_string(&sidecar).unwrap();
+        let mode = std::fs::metadata(&sidecar).unwrap().permissions().mode() & 0o777;
+        let entries = std::fs::read_dir(&dir).unwrap().count();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!((contents.as_str(), mode, entries), ("new", 0o640, 1));
+    }
+}